@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use key_value_db::{KvStore, StoreOptions};
+use tempfile::TempDir;
+
+const KEYS: usize = 10_000;
+
+/// 写入 KEYS 个键后重新打开，所有数据都位于不再写入的日志中
+fn prepare(mmap_reads: bool) -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || StoreOptions {
+        auto_compaction: false,
+        max_segment_bytes: 256 * 1024,
+        mmap_reads,
+        ..StoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options()).expect("unable to open store");
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "v".repeat(100)).expect("unable to set");
+    }
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options()).expect("unable to reopen store");
+    (temp_dir, store)
+}
+
+// 以固定的步长跳跃读取，访问顺序与写入顺序无关
+fn read_heavy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for (name, mmap_reads) in [("buffered", false), ("mmap", true)] {
+        let (_temp_dir, mut store) = prepare(mmap_reads);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for i in 0..KEYS {
+                    let key = format!("key{}", i * 7919 % KEYS);
+                    assert!(store.get(key).expect("unable to get").is_some());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read_heavy);
+criterion_main!(benches);
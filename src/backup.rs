@@ -0,0 +1,308 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blob::{self, BLOB_DIR},
+    error::{KvsError, Result},
+    format::{self, HEADER_LEN},
+    ratelimit::ThrottledReader,
+    kv::{complete_prefix, log_path, sorted_gen_list, sync_dir, write_atomic},
+    manifest::{self, Manifest},
+    KvStore,
+};
+
+/// 备份清单文件名
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// 日志及其长度
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogExtent {
+    pub gen: u64,
+    pub len: u64,
+    /// 日志文件头中的创建时间，与 gen 一起区分先后使用同一序号的不同日志
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+/// 一次备份中保存的日志片段 [start, end)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupPart {
+    pub gen: u64,
+    pub start: u64,
+    pub end: u64,
+    /// 所属日志文件头中的创建时间
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+/// 备份清单
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupManifest {
+    /// 备份编号，从 1 开始递增
+    pub id: u64,
+    /// 上一次备份，第一次备份为全量备份
+    pub parent: Option<u64>,
+    /// 备份时间（Unix 秒）
+    pub created_at: u64,
+    /// 备份时存储中的全部日志
+    pub gens: Vec<LogExtent>,
+    /// 本次备份新增的日志片段
+    pub parts: Vec<BackupPart>,
+    /// 备份时存储中的全部 blob，之前的备份中没有的 blob 保存在本次备份中
+    #[serde(default)]
+    pub blobs: Vec<u64>,
+    /// 压缩结果中的记录最初写入的日志，按位置恢复时以此判断记录写入的先后
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub origins: BTreeMap<u64, Vec<(u64, u64)>>,
+}
+
+/// 恢复的目标位置
+#[derive(Debug, Clone, Copy)]
+pub enum RestorePoint {
+    /// 最近一次备份
+    Latest,
+    /// 指定编号的备份
+    Backup(u64),
+    /// 日志 gen 中 offset 之前的全部写入
+    /// offset 不在命令边界时退回到之前最近的完整命令
+    Position { gen: u64, offset: u64 },
+}
+
+/// 增量备份存储到备份仓库
+///
+/// 只保存上一次备份之后新出现的日志以及已有日志新增的尾部，
+/// 第一次备份保存全部日志。日志以序号与文件头中的创建时间识别，
+/// 清空存储后重新使用的序号视为新的日志。blob 写入后不再修改，每个 blob 只保存一次。
+pub fn create_backup(store: &mut KvStore, repo: impl AsRef<Path>) -> Result<BackupManifest> {
+    let repo = repo.as_ref();
+    fs::create_dir_all(repo)?;
+    let backups = list_backups(repo)?;
+    let coverage = coverage(&backups);
+
+    let id = backups.last().map_or(1, |last| last.id + 1);
+    let dir = backup_dir(repo, id);
+    // 清理之前中断的同编号备份
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+
+    let mut extents = Vec::new();
+    for (gen, len) in store.log_extents()? {
+        let created_at = format::read_header(store.path(), gen)?.created_at;
+        extents.push(LogExtent { gen, len, created_at });
+    }
+    let mut parts = Vec::new();
+    for &LogExtent { gen, len, created_at } in &extents {
+        let start = coverage.get(&(gen, created_at)).cloned().unwrap_or(0);
+        if start > len {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("log {} is shorter than its backup", gen),
+            )));
+        }
+        if start == len {
+            continue;
+        }
+        let mut reader = File::open(log_path(store.path(), gen))?;
+        reader.seek(SeekFrom::Start(start))?;
+        let mut writer = File::create(part_path(&dir, gen, start))?;
+        let mut reader = ThrottledReader::new(reader.take(len - start), store.io_limiter().cloned());
+        io::copy(&mut reader, &mut writer)?;
+        writer.sync_all()?;
+        parts.push(BackupPart { gen, start, end: len, created_at });
+    }
+
+    let saved: HashSet<u64> = backups.iter().flat_map(|backup| backup.blobs.iter().copied()).collect();
+    let blobs = blob::blob_ids(store.blob_dir())?;
+    for &id in blobs.iter().filter(|id| !saved.contains(id)) {
+        fs::create_dir_all(dir.join(BLOB_DIR))?;
+        let reader = File::open(blob::blob_path(store.blob_dir(), id))?;
+        let mut writer = File::create(blob::blob_path(&dir.join(BLOB_DIR), id))?;
+        let mut reader = ThrottledReader::new(reader, store.io_limiter().cloned());
+        io::copy(&mut reader, &mut writer)?;
+        writer.sync_all()?;
+    }
+
+    let manifest = BackupManifest {
+        id,
+        parent: backups.last().map(|last| last.id),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        gens: extents,
+        parts,
+        blobs,
+        origins: store.log_origins(),
+    };
+    // 清单最后写入，没有清单的备份目录视为不完整
+    if manifest.blobs.iter().any(|id| !saved.contains(id)) {
+        sync_dir(&dir.join(BLOB_DIR))?;
+    }
+    write_atomic(&dir, MANIFEST_FILE, &serde_json::to_vec_pretty(&manifest)?)?;
+    sync_dir(repo)?;
+    Ok(manifest)
+}
+
+/// 按编号列出仓库中完整的备份
+pub fn list_backups(repo: impl AsRef<Path>) -> Result<Vec<BackupManifest>> {
+    let repo = repo.as_ref();
+    let mut backups = Vec::new();
+    if !repo.exists() {
+        return Ok(backups);
+    }
+    for entry in fs::read_dir(repo)? {
+        let manifest_path = entry?.path().join(MANIFEST_FILE);
+        if manifest_path.is_file() {
+            let manifest: BackupManifest = serde_json::from_slice(&fs::read(manifest_path)?)?;
+            backups.push(manifest);
+        }
+    }
+    backups.sort_unstable_by_key(|manifest| manifest.id);
+    Ok(backups)
+}
+
+/// 从备份仓库恢复到 dest 目录，返回所用的备份清单
+pub fn restore(repo: impl AsRef<Path>, dest: impl AsRef<Path>, point: RestorePoint) -> Result<BackupManifest> {
+    let (repo, dest) = (repo.as_ref(), dest.as_ref());
+    let backups = list_backups(repo)?;
+
+    let (manifest, position) = match point {
+        RestorePoint::Latest => (backups.last(), None),
+        RestorePoint::Backup(id) => (backups.iter().find(|manifest| manifest.id == id), None),
+        // 最早一次包含该位置的备份，记录了当时的其余日志
+        RestorePoint::Position { gen, offset } => (
+            backups
+                .iter()
+                .find(|manifest| manifest.gens.iter().any(|extent| extent.gen == gen && extent.len >= offset)),
+            Some((gen, offset)),
+        ),
+    };
+    let manifest = manifest
+        .cloned()
+        .ok_or_else(|| KvsError::Io(io::Error::new(io::ErrorKind::NotFound, format!("no backup for {:?}", point))))?;
+
+    fs::create_dir_all(dest)?;
+    if !sorted_gen_list(dest)?.is_empty() {
+        return Err(KvsError::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already contains log files", dest.display()),
+        )));
+    }
+
+    // 该备份及之前的备份中保存的所有片段
+    let mut parts: Vec<(u64, BackupPart)> = backups
+        .iter()
+        .filter(|backup| backup.id <= manifest.id)
+        .flat_map(|backup| backup.parts.iter().map(move |&part| (backup.id, part)))
+        .collect();
+    parts.sort_unstable_by_key(|&(_, part)| (part.gen, part.start));
+
+    // 部分压缩之后序号更大的日志中可能有更早写入的记录，因此按记录最初写入的日志截断：
+    // 最初写入更早的日志，或者最初写入同一日志而所在的日志序号更小的记录都在指定位置之前
+    let cut = position.map(|(gen, offset)| {
+        let last = offset.saturating_sub(1).max(HEADER_LEN);
+        let origin = manifest.origins.get(&gen).and_then(|segments| manifest::origin_at(segments, last)).unwrap_or(gen);
+        (origin, gen, offset)
+    });
+    let mut restored = Vec::new();
+    for extent in &manifest.gens {
+        let len = match cut {
+            // 至少保留文件头
+            Some((_, gen, offset)) if extent.gen == gen => offset.max(HEADER_LEN).min(extent.len),
+            Some((cut_origin, gen, _)) => {
+                // 各段按最初写入的日志排列，截断到第一段在指定位置之后写入的记录
+                let later = manifest::origin_segments(&manifest.origins, extent.gen)
+                    .into_iter()
+                    .find(|&(_, origin)| (origin, extent.gen) > (cut_origin, gen));
+                match later {
+                    Some((start, _)) if start <= HEADER_LEN => continue,
+                    Some((start, _)) => start.min(extent.len),
+                    None => extent.len,
+                }
+            }
+            None => extent.len,
+        };
+        restored.push(extent.gen);
+
+        let dst = log_path(dest, extent.gen);
+        let mut writer = File::create(&dst)?;
+        let mut written = 0;
+        let extent_parts = parts
+            .iter()
+            .filter(|(_, part)| part.gen == extent.gen && part.created_at == extent.created_at);
+        for &(id, part) in extent_parts {
+            if written >= len {
+                break;
+            }
+            if part.start != written {
+                return Err(KvsError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("backup of log {} is missing bytes {}..{}", extent.gen, written, part.start),
+                )));
+            }
+            let reader = File::open(part_path(&backup_dir(repo, id), part.gen, part.start))?;
+            written += io::copy(&mut reader.take(part.end.min(len) - part.start), &mut writer)?;
+        }
+        if written < len {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("backup of log {} ends at {} before {}", extent.gen, written, len),
+            )));
+        }
+        writer.sync_all()?;
+        drop(writer);
+
+        // 截断到指定位置之前最近的完整命令
+        if position.is_some_and(|(gen, _)| gen == extent.gen) {
+            let mut reader = File::open(&dst)?;
+            reader.seek(SeekFrom::Start(HEADER_LEN))?;
+            let complete = HEADER_LEN + complete_prefix(io::BufReader::new(reader));
+            OpenOptions::new().write(true).open(&dst)?.set_len(complete)?;
+        }
+    }
+
+    // 每个 blob 保存在第一次出现它的备份中
+    for &id in &manifest.blobs {
+        let backup = backups
+            .iter()
+            .find(|backup| backup.blobs.contains(&id))
+            .expect("the chosen backup lists the blob");
+        fs::create_dir_all(dest.join(BLOB_DIR))?;
+        fs::copy(
+            blob::blob_path(&backup_dir(repo, backup.id).join(BLOB_DIR), id),
+            blob::blob_path(&dest.join(BLOB_DIR), id),
+        )?;
+    }
+
+    // 恢复的存储同样记录压缩结果中记录的来源，之后的备份仍然可以按位置恢复
+    let origins = manifest.origins.iter()
+        .filter(|(gen, _)| restored.contains(gen))
+        .map(|(&gen, segments)| (gen, segments.clone()))
+        .collect();
+    Manifest { gens: restored, origins, ..Manifest::default() }.store(dest)?;
+    Ok(manifest)
+}
+
+/// 仓库中每个日志（序号与创建时间）已备份到的长度
+fn coverage(backups: &[BackupManifest]) -> HashMap<(u64, u64), u64> {
+    let mut coverage = HashMap::new();
+    for part in backups.iter().flat_map(|backup| &backup.parts) {
+        let end = coverage.entry((part.gen, part.created_at)).or_insert(0);
+        *end = part.end.max(*end);
+    }
+    coverage
+}
+
+fn backup_dir(repo: &Path, id: u64) -> PathBuf {
+    repo.join(format!("backup-{}", id))
+}
+
+fn part_path(dir: &Path, gen: u64, start: u64) -> PathBuf {
+    dir.join(format!("{}-{}.part", gen, start))
+}
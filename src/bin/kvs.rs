@@ -1,15 +1,36 @@
 use std::env::current_dir;
+use std::fs::{self, File};
+use std::io::{stdin, stdout};
+use std::path::PathBuf;
 use std::process::exit;
 use clap::Parser;
-use key_value_db::{Result, KvsError, KvStore};
+use key_value_db::{Result, KvsError, KvStore, DataFormat, StoreOptions};
+use key_value_db::backup::{self, RestorePoint};
+use key_value_db::crypto::{EncryptionKey, Keyring, KEY_LEN};
+use key_value_db::{format, inspect, repair, verify};
 
 fn main() -> Result<()> {
 
     let opts: Opts = Opts::parse();
-
-    let mut store = KvStore::open(current_dir()?)?;
+    let options = StoreOptions {
+        encryption: load_keyring(&opts.key_files)?,
+        ..StoreOptions::default()
+    };
 
     match opts.commond {
+        // 以下命令不打开当前目录的存储
+        Command::Restore(args) => restore(args),
+        Command::Verify(args) => verify(args),
+        Command::Repair(args) => repair(args, options),
+        Command::Dump(args) => dump(args),
+        Command::InspectKey(args) => inspect_key(args),
+        Command::Migrate => migrate(),
+        command => run(KvStore::open_with_options(current_dir()?, options)?, command),
+    }
+}
+
+fn run(mut store: KvStore, command: Command) -> Result<()> {
+    match command {
 
         Command::Get(get) => {
             let value = store.get(get.key)?;
@@ -35,10 +56,140 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             }
         }
+        Command::Backup(args) => {
+            let manifest = backup::create_backup(&mut store, &args.repo)?;
+            println!("Backup {} ({} parts)", manifest.id, manifest.parts.len());
+        }
+        Command::Export(args) => {
+            match args.output {
+                Some(path) => store.export(File::create(path)?, args.format)?,
+                None => store.export(stdout().lock(), args.format)?,
+            };
+        }
+        Command::Compact => store.compact()?,
+        Command::Import(args) => {
+            let stats = match args.input {
+                Some(path) => store.import(File::open(path)?, args.format, args.atomic)?,
+                None => store.import(stdin().lock(), args.format, args.atomic)?,
+            };
+            println!("Inserted {}, overwritten {}", stats.inserted, stats.overwritten);
+        }
+        Command::Restore(_)
+        | Command::Verify(_)
+        | Command::Repair(_)
+        | Command::Dump(_)
+        | Command::InspectKey(_)
+        | Command::Migrate => unreachable!("handled without opening the store"),
+    }
+    Ok(())
+}
+
+fn restore(args: Restore) -> Result<()> {
+    let point = match (args.backup, args.gen, args.offset) {
+        (Some(id), None, None) => RestorePoint::Backup(id),
+        (None, Some(gen), Some(offset)) => RestorePoint::Position { gen, offset },
+        (None, None, None) => RestorePoint::Latest,
+        _ => {
+            eprintln!("Use either --backup or both --gen and --offset");
+            exit(1);
+        }
+    };
+    let manifest = backup::restore(&args.repo, &args.dest, point)?;
+    println!("Restored from backup {}", manifest.id);
+    Ok(())
+}
+
+fn verify(args: Verify) -> Result<()> {
+    let path = match args.path {
+        Some(path) => path,
+        None => current_dir()?,
+    };
+    let report = verify::verify(path)?;
+    for gen in &report.gens {
+        println!(
+            "{}.log: {} bytes, {} sets, {} removes, {:.1}% dead",
+            gen.gen, gen.bytes, gen.sets, gen.removes, gen.dead_ratio() * 100.0
+        );
+    }
+    println!("{} keys", report.keys);
+    for issue in &report.issues {
+        println!("{}", issue);
+    }
+    if !report.is_ok() {
+        exit(1);
+    }
+    Ok(())
+}
+
+fn repair(args: Repair, options: StoreOptions) -> Result<()> {
+    let report = repair::repair_with_options(current_dir()?, &args.dest, options)?;
+    println!("Recovered {} records, {} keys", report.recovered_records, report.keys);
+    for lost in &report.lost {
+        println!(
+            "Lost {}.log bytes {}..{}, keys: {:?}",
+            lost.gen, lost.start, lost.end, lost.keys
+        );
+    }
+    Ok(())
+}
+
+/// 读取密钥文件，第一个文件为当前密钥，其余只用于读取旧日志
+/// 每个文件的内容为密钥 id 与 64 个十六进制字符的密钥，以空白分隔
+fn load_keyring(files: &[PathBuf]) -> Result<Option<Keyring>> {
+    let mut keyring: Option<Keyring> = None;
+    for path in files {
+        let invalid = || KvsError::Encryption(format!("invalid key file {}", path.display()));
+        let content = fs::read_to_string(path)?;
+        let mut fields = content.split_whitespace();
+        let (Some(id), Some(hex), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid());
+        };
+        let id: u32 = id.parse().map_err(|_| invalid())?;
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0u8; KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        let key = EncryptionKey::new(id, key)?;
+        keyring = Some(match keyring {
+            Some(keyring) => keyring.with_previous(key),
+            None => Keyring::new(key),
+        });
+    }
+    Ok(keyring)
+}
+
+fn dump(args: Dump) -> Result<()> {
+    inspect::dump(current_dir()?, args.gen, args.from_offset, |record| {
+        println!("{}", record);
+        Ok(())
+    })
+}
+
+fn inspect_key(args: InspectKey) -> Result<()> {
+    let inspection = inspect::inspect_key(current_dir()?, &args.key)?;
+    match &inspection.current {
+        Some(record) => println!("current: gen={} offset={} len={}", record.gen, record.offset, record.len),
+        None => println!("current: none"),
+    }
+    for record in &inspection.history {
+        println!("{}", record);
     }
     Ok(())
 }
 
+fn migrate() -> Result<()> {
+    let report = format::migrate(current_dir()?)?;
+    println!(
+        "Migrated {} logs, {} already up to date",
+        report.migrated.len(),
+        report.current.len()
+    );
+    Ok(())
+}
+
 #[derive(Parser,Debug)]
 #[clap(
 name = env!("CARGO_PKG_NAME"),
@@ -47,6 +198,10 @@ author = env!("CARGO_PKG_AUTHORS"),
 about = env!("CARGO_PKG_DESCRIPTION")
 )]
 struct Opts {
+    /// 加密存储的密钥文件，内容为密钥 id 与 64 个十六进制字符的密钥
+    /// 可以重复指定，第一个为写入使用的当前密钥，其余只用于读取旧日志
+    #[clap(long = "key-file", global = true)]
+    key_files: Vec<PathBuf>,
     #[clap(subcommand)]
     commond: Command
 }
@@ -58,7 +213,37 @@ pub enum Command {
     #[clap()]
     Get(Get),
     #[clap()]
-    Rm(Rm)
+    Rm(Rm),
+    /// 增量备份到备份仓库
+    #[clap()]
+    Backup(Backup),
+    /// 从备份仓库恢复到新的目录
+    #[clap()]
+    Restore(Restore),
+    /// 导出所有数据，默认写到标准输出
+    #[clap()]
+    Export(Export),
+    /// 立即压缩当前目录的存储
+    #[clap()]
+    Compact,
+    /// 导入数据，默认从标准输入读取
+    #[clap()]
+    Import(Import),
+    /// 离线检查存储目录，发现错误时以非零状态退出
+    #[clap()]
+    Verify(Verify),
+    /// 从当前目录恢复所有可读的数据，重建到新的目录
+    #[clap()]
+    Repair(Repair),
+    /// 打印日志中的每条记录
+    #[clap()]
+    Dump(Dump),
+    /// 查看一个键的当前位置与磁盘上的历史记录
+    #[clap()]
+    InspectKey(InspectKey),
+    /// 把当前目录中旧格式的存储升级到当前格式
+    #[clap()]
+    Migrate,
 }
 
 #[derive(Parser,Debug)]
@@ -80,4 +265,76 @@ pub struct Get {
 pub struct Rm {
     #[clap()]
     key: String
+}
+
+#[derive(Parser, Debug)]
+pub struct Backup {
+    #[clap()]
+    repo: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct Restore {
+    #[clap()]
+    repo: PathBuf,
+    #[clap()]
+    dest: PathBuf,
+    /// 恢复到指定编号的备份，默认为最近一次备份
+    #[clap(long)]
+    backup: Option<u64>,
+    /// 恢复到日志 gen 的 offset 位置
+    #[clap(long)]
+    gen: Option<u64>,
+    #[clap(long)]
+    offset: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+pub struct Export {
+    /// jsonl、csv 或 bin
+    #[clap()]
+    format: DataFormat,
+    #[clap(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct Import {
+    /// jsonl、csv 或 bin
+    #[clap()]
+    format: DataFormat,
+    #[clap()]
+    input: Option<PathBuf>,
+    /// 全部读取成功后才一次性生效
+    #[clap(long)]
+    atomic: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct Verify {
+    /// 存储目录，默认为当前目录
+    #[clap()]
+    path: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct Repair {
+    #[clap()]
+    dest: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct Dump {
+    /// 只打印指定的日志
+    #[clap(long)]
+    gen: Option<u64>,
+    /// 从指定日志的该位置开始，必须位于记录边界
+    #[clap(long, default_value = "0", requires = "gen")]
+    from_offset: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct InspectKey {
+    #[clap()]
+    key: String,
 }
\ No newline at end of file
@@ -0,0 +1,253 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{KvsError, Result},
+    format::{LogHeader, HEADER_LEN},
+    kv::{Command, CommandPos},
+};
+
+/// blob 文件所在的子目录
+pub(crate) const BLOB_DIR: &str = "blobs";
+
+/// blob 文件的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlobStats {
+    /// 仍被键引用的 blob 数
+    pub live_blobs: u64,
+    pub live_bytes: u64,
+    /// 已被覆盖或删除、等待回收的 blob 数
+    pub dead_blobs: u64,
+    pub dead_bytes: u64,
+}
+
+/// 一个 blob 文件
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlobRef {
+    pub(crate) id: u64,
+    /// 文件的字节数
+    pub(crate) bytes: u64,
+}
+
+/// 仍在使用的 blob 与等待回收的 blob 及其记录所在的日志
+pub(crate) type BlobSnapshot = (Vec<(String, BlobRef)>, Vec<(u64, BlobRef)>);
+
+/// 单独存放大值的 blob 文件
+///
+/// 每个 blob 文件与日志格式相同：文件头之后是一条写入命令，文件头中的序号为 blob 的编号。
+/// 日志中只写入引用 blob 的 `SetBlob` 记录，压缩只需要移动这条小记录。
+///
+/// 被覆盖或删除的 blob 在引用它的记录所在的日志被压缩之后才删除，
+/// 在此之前重新打开存储时旧的记录仍然可以正常重放。
+pub(crate) struct BlobStore {
+    dir: PathBuf,
+    next_id: u64,
+    // 值存放在 blob 中的键
+    live: HashMap<String, BlobRef>,
+    // 等待回收的 blob 及引用它的记录所在的日志
+    dead: Vec<(u64, BlobRef)>,
+    // 编号小于它的 blob 已经落盘，为 None 时还没有落盘过
+    synced_id: Option<u64>,
+}
+
+impl BlobStore {
+    /// session 为本次打开存储时新建的日志序号
+    /// blob 的编号以它为高 32 位，不会与之前打开时写入的 blob 重复
+    pub(crate) fn new(dir: PathBuf, session: u64) -> BlobStore {
+        BlobStore {
+            dir,
+            next_id: session << 32,
+            live: HashMap::new(),
+            dead: Vec::new(),
+            synced_id: None,
+        }
+    }
+
+    /// 把写入命令存为新的 blob 文件，文件头记录加密所用的密钥
+    pub(crate) fn write(&mut self, cmd: &Command, key_id: u32) -> Result<BlobRef> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let buf = serde_json::to_vec(cmd)?;
+        // 目录在第一次写入 blob 时创建
+        fs::create_dir_all(&self.dir)?;
+        let mut writer = BufWriter::new(File::create(blob_path(&self.dir, id))?);
+        LogHeader::new(id).with_key_id(key_id).write_to(&mut writer)?;
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        Ok(BlobRef { id, bytes: HEADER_LEN + buf.len() as u64 })
+    }
+
+    /// 键 key 的值改为 blob（为 None 时值不在 blob 中）
+    /// old 为键之前的记录，它引用的 blob 在该记录所在的日志被压缩后回收；
+    /// 压缩移动记录时 blob 不变，不会被回收
+    pub(crate) fn replace(&mut self, key: &str, blob: Option<BlobRef>, old: Option<CommandPos>) {
+        let previous = match blob {
+            Some(blob) => self.live.insert(key.to_owned(), blob),
+            None => self.live.remove(key),
+        };
+        if let Some(previous) = previous.filter(|&previous| Some(previous) != blob) {
+            // 没有旧记录时下一次回收即可删除
+            self.dead.push((old.map_or(0, |old| old.gen), previous));
+        }
+    }
+
+    /// 删除引用它的日志已经不存在的 blob，返回回收的字节数
+    pub(crate) fn sweep(&mut self, is_live_gen: impl Fn(u64) -> bool) -> Result<u64> {
+        let mut freed = 0;
+        let mut kept = Vec::new();
+        for (gen, blob) in self.dead.drain(..) {
+            if is_live_gen(gen) {
+                kept.push((gen, blob));
+                continue;
+            }
+            remove_file(&blob_path(&self.dir, blob.id))?;
+            freed += blob.bytes;
+        }
+        self.dead = kept;
+        Ok(freed)
+    }
+
+    /// 等待回收的 blob 的记录所在的日志
+    pub(crate) fn dead_gens(&self) -> Vec<u64> {
+        let mut gens: Vec<u64> = self.dead.iter().map(|&(gen, _)| gen).collect();
+        gens.sort_unstable();
+        gens.dedup();
+        gens
+    }
+
+    /// 删除没有任何记录引用的 blob，例如写入 blob 之后、写入记录之前中断留下的文件
+    pub(crate) fn remove_orphans(&mut self) -> Result<()> {
+        let referenced: HashSet<u64> = self
+            .live
+            .values()
+            .chain(self.dead.iter().map(|(_, blob)| blob))
+            .map(|blob| blob.id)
+            .collect();
+        for id in blob_ids(&self.dir)? {
+            if !referenced.contains(&id) {
+                remove_file(&blob_path(&self.dir, id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 上次调用之后写入的 blob 文件及其目录，由调用者落盘
+    /// 第一次调用时包括之前打开存储时写入的 blob
+    pub(crate) fn take_unsynced(&mut self) -> Result<Vec<File>> {
+        let ids = match self.synced_id {
+            Some(synced) => (synced..self.next_id).collect(),
+            None => blob_ids(&self.dir)?,
+        };
+        self.synced_id = Some(self.next_id);
+        let mut files = Vec::new();
+        for id in ids {
+            match File::open(blob_path(&self.dir, id)) {
+                Ok(file) => files.push(file),
+                // 已被回收
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // 新建的文件需要目录落盘之后才可靠
+        if !files.is_empty() {
+            files.push(File::open(&self.dir)?);
+        }
+        Ok(files)
+    }
+
+    /// 仍在使用与等待回收的 blob，用于保存索引快照
+    pub(crate) fn snapshot(&self) -> BlobSnapshot {
+        let live = self.live.iter().map(|(key, &blob)| (key.clone(), blob)).collect();
+        (live, self.dead.clone())
+    }
+
+    /// 从索引快照中恢复仍在使用与等待回收的 blob
+    pub(crate) fn restore(&mut self, (live, dead): BlobSnapshot) {
+        self.live = live.into_iter().collect();
+        self.dead = dead;
+    }
+
+    /// 删除所有 blob
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        for id in blob_ids(&self.dir)? {
+            remove_file(&blob_path(&self.dir, id))?;
+        }
+        self.live.clear();
+        self.dead.clear();
+        Ok(())
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub(crate) fn stats(&self) -> BlobStats {
+        BlobStats {
+            live_blobs: self.live.len() as u64,
+            live_bytes: self.live.values().map(|blob| blob.bytes).sum(),
+            dead_blobs: self.dead.len() as u64,
+            dead_bytes: self.dead.iter().map(|(_, blob)| blob.bytes).sum(),
+        }
+    }
+}
+
+pub(crate) fn blob_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.blob", id))
+}
+
+/// 读取 blob 文件，返回其中的写入命令与加密所用的密钥
+/// 文件缺失或无法解析时返回 `Corruption`
+pub(crate) fn read(dir: &Path, id: u64) -> Result<(Command, u32)> {
+    let data = match fs::read(blob_path(dir, id)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(KvsError::Corruption(format!("blob {} is missing", id)));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    parse(id, &data)
+}
+
+/// 解析 blob id 文件的内容，返回其中的写入命令与加密所用的密钥
+pub(crate) fn parse(id: u64, data: &[u8]) -> Result<(Command, u32)> {
+    let header = LogHeader::read_from(&mut &data[..], id)
+        .map_err(|e| KvsError::Corruption(format!("blob {} has an invalid header: {}", id, e)))?;
+    let cmd = serde_json::from_slice(&data[HEADER_LEN as usize..])
+        .map_err(|e| KvsError::Corruption(format!("blob {} cannot be parsed: {}", id, e)))?;
+    Ok((cmd, header.key_id))
+}
+
+/// 目录中所有 blob 的编号，目录不存在时为空
+pub(crate) fn blob_ids(dir: &Path) -> Result<Vec<u64>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut ids = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some("blob".as_ref()) {
+            continue;
+        }
+        if let Some(id) = path.file_stem().and_then(OsStr::to_str).and_then(|stem| stem.parse().ok()) {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+/// 值缓存的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// 命中缓存的读取次数
+    pub hits: u64,
+    /// 未命中缓存、需要读取日志的次数
+    pub misses: u64,
+    /// 缓存中的键数
+    pub entries: usize,
+    /// 缓存中键与值的字节数
+    pub bytes: usize,
+    /// 缓存的容量（字节）
+    pub capacity: usize,
+}
+
+/// 以 CLOCK 算法淘汰的值缓存，按键与值的字节数限制大小
+///
+/// 每个条目带有访问标记，淘汰时指针循环扫描条目：有标记的清除标记后跳过，
+/// 没有标记的被淘汰。
+pub(crate) struct ValueCache {
+    capacity: usize,
+    bytes: usize,
+    map: HashMap<String, usize>,
+    slots: Vec<Option<Slot>>,
+    // 空闲的槽
+    free: Vec<usize>,
+    hand: usize,
+    hits: u64,
+    misses: u64,
+}
+
+struct Slot {
+    key: String,
+    value: String,
+    referenced: bool,
+}
+
+impl ValueCache {
+    /// 容量为 0 时不缓存
+    pub(crate) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            bytes: 0,
+            map: HashMap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            hand: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Option<String> {
+        match self.map.get(key) {
+            Some(&i) => {
+                self.hits += 1;
+                let slot = self.slots[i].as_mut().expect("cached slot is occupied");
+                slot.referenced = true;
+                Some(slot.value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// 缓存键的值，超过容量的值不缓存
+    pub(crate) fn insert(&mut self, key: String, value: String) {
+        self.invalidate(&key);
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        while self.bytes + size > self.capacity {
+            self.evict();
+        }
+
+        let slot = Slot { key: key.clone(), value, referenced: false };
+        let i = match self.free.pop() {
+            Some(i) => {
+                self.slots[i] = Some(slot);
+                i
+            }
+            None => {
+                self.slots.push(Some(slot));
+                self.slots.len() - 1
+            }
+        };
+        self.map.insert(key, i);
+        self.bytes += size;
+    }
+
+    /// 删除键的缓存
+    pub(crate) fn invalidate(&mut self, key: &str) {
+        if let Some(i) = self.map.remove(key) {
+            self.release(i);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.map.clear();
+        self.slots.clear();
+        self.free.clear();
+        self.hand = 0;
+        self.bytes = 0;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.map.len(),
+            bytes: self.bytes,
+            capacity: self.capacity,
+        }
+    }
+
+    /// 淘汰一个最近没有被访问的条目
+    fn evict(&mut self) {
+        loop {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+            let i = self.hand;
+            self.hand += 1;
+            match &mut self.slots[i] {
+                Some(slot) if slot.referenced => slot.referenced = false,
+                Some(slot) => {
+                    self.map.remove(&slot.key);
+                    self.release(i);
+                    return;
+                }
+                None => {}
+            }
+        }
+    }
+
+    fn release(&mut self, i: usize) {
+        if let Some(slot) = self.slots[i].take() {
+            self.bytes -= slot.key.len() + slot.value.len();
+            self.free.push(i);
+        }
+    }
+}
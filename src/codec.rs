@@ -0,0 +1,120 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{KvsError, Result};
+
+/// 默认的单个值的最大字节数
+/// 写入时超过该值的值被拒绝，还原压缩的值时解压的结果也不能超过它
+pub const DEFAULT_MAX_VALUE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// zstd 的压缩级别
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// 值的压缩算法
+///
+/// 压缩过的值以 base64 写入 `SetCompressed` 记录，算法记录在它的 `codec` 字段；
+/// 普通的 `Set` 记录保存原始的值，因此同一个日志中可以混合两种记录。
+/// 每种算法需要开启同名的 feature。
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    /// 当前构建是否支持该算法
+    pub fn is_available(self) -> bool {
+        match self {
+            Codec::Lz4 => cfg!(feature = "lz4"),
+            Codec::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    pub(crate) fn ensure_available(self) -> Result<()> {
+        if self.is_available() {
+            Ok(())
+        } else {
+            Err(self.unavailable())
+        }
+    }
+
+    fn unavailable(self) -> KvsError {
+        let name = match self {
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        };
+        KvsError::UnsupportedFormat(format!(
+            "{} compression requires building with the `{}` feature",
+            name, name
+        ))
+    }
+
+    #[cfg_attr(not(all(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    pub(crate) fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+            #[allow(unreachable_patterns)]
+            _ => Err(self.unavailable()),
+        }
+    }
+
+    /// 解压 data，结果超过 max_len 字节时返回错误
+    /// 长度前缀与压缩数据都来自日志，解压之前不能信任它们声明的大小
+    #[cfg_attr(not(all(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    pub(crate) fn decompress(self, data: &[u8], max_len: u64) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => {
+                let invalid = |e| KvsError::Corruption(format!("invalid lz4 value: {}", e));
+                let (len, _) = lz4_flex::block::uncompressed_size(data).map_err(invalid)?;
+                if len as u64 > max_len {
+                    return Err(KvsError::ValueTooLarge(len as u64, max_len));
+                }
+                lz4_flex::decompress_size_prepended(data).map_err(invalid)
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                use std::io::Read;
+
+                let invalid = |e| KvsError::Corruption(format!("invalid zstd value: {}", e));
+                let decoder = zstd::stream::read::Decoder::with_buffer(data).map_err(invalid)?;
+                // 多读一个字节以区分恰好达到上限与超过上限
+                let mut value = Vec::new();
+                decoder.take(max_len.saturating_add(1)).read_to_end(&mut value).map_err(invalid)?;
+                if value.len() as u64 > max_len {
+                    return Err(KvsError::ValueTooLarge(value.len() as u64, max_len));
+                }
+                Ok(value)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(self.unavailable()),
+        }
+    }
+}
+
+/// 按 codec 压缩值，返回写入记录的值与算法
+/// 压缩后没有变小的值保持原样
+pub(crate) fn encode_value(value: String, codec: Option<Codec>) -> Result<(String, Option<Codec>)> {
+    let Some(codec) = codec else {
+        return Ok((value, None));
+    };
+    let encoded = STANDARD.encode(codec.compress(value.as_bytes())?);
+    if encoded.len() >= value.len() {
+        return Ok((value, None));
+    }
+    Ok((encoded, Some(codec)))
+}
+
+/// 还原压缩记录中的值，解压后超过 max_len 字节时返回错误
+pub(crate) fn decode_value(value: &str, codec: Codec, max_len: u64) -> Result<String> {
+    let compressed = STANDARD
+        .decode(value)
+        .map_err(|e| KvsError::Corruption(format!("invalid compressed value: {}", e)))?;
+    String::from_utf8(codec.decompress(&compressed, max_len)?)
+        .map_err(|_| KvsError::Corruption("decompressed value is not UTF-8".to_owned()))
+}
@@ -0,0 +1,258 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{KvsError, Result},
+    KvStore, StoreOptions,
+};
+
+/// 组提交的参数
+#[derive(Debug, Clone)]
+pub struct GroupCommitOptions {
+    /// leader 落盘之前等待更多写入加入批次的最长时间，为 0 时立即落盘
+    /// 落盘期间到达的写入总会组成下一个批次，不需要等待也可以合并
+    pub max_batch_delay: Duration,
+    /// 批次中的写入达到该数量时不再等待
+    pub max_batch_size: u64,
+}
+
+impl Default for GroupCommitOptions {
+    fn default() -> Self {
+        GroupCommitOptions {
+            max_batch_delay: Duration::ZERO,
+            max_batch_size: 1024,
+        }
+    }
+}
+
+/// 组提交的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitMetrics {
+    /// 已落盘的写入数
+    pub writes: u64,
+    /// 落盘的次数
+    pub syncs: u64,
+    /// 单次落盘覆盖的最多写入数
+    pub max_batch: u64,
+    /// 写入从开始到落盘的总时间
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+    /// 统计开始之后经过的时间
+    pub elapsed: Duration,
+}
+
+impl CommitMetrics {
+    /// 平均每次落盘覆盖的写入数
+    pub fn mean_batch(&self) -> f64 {
+        if self.syncs == 0 {
+            0.0
+        } else {
+            self.writes as f64 / self.syncs as f64
+        }
+    }
+
+    pub fn mean_latency(&self) -> Duration {
+        match u32::try_from(self.writes) {
+            Ok(0) => Duration::ZERO,
+            Ok(writes) => self.total_latency / writes,
+            Err(_) => Duration::from_secs_f64(self.total_latency.as_secs_f64() / self.writes as f64),
+        }
+    }
+
+    /// 每秒落盘的写入数
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            0.0
+        } else {
+            self.writes as f64 / self.elapsed.as_secs_f64()
+        }
+    }
+}
+
+/// 多个线程共享、写入返回时已经落盘的存储
+///
+/// 并发的写入以组提交合并落盘：每个写入先追加到日志，然后等待落盘。
+/// 没有进行中的落盘时，等待者成为 leader，最多等待 `max_batch_delay` 让更多写入加入批次，
+/// 再在存储的锁之外落盘一次，唤醒所有已被这次落盘覆盖的写入。
+/// 落盘期间新的写入继续追加，由下一个 leader 一起落盘。
+///
+/// 克隆出的句柄共享同一个存储。
+#[derive(Clone)]
+pub struct GroupCommitStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    store: Mutex<KvStore>,
+    state: Mutex<CommitState>,
+    // 落盘完成时唤醒等待的写入
+    synced: Condvar,
+    // 有新的写入时唤醒等待批次的 leader
+    appended: Condvar,
+    options: GroupCommitOptions,
+    started: Instant,
+}
+
+struct CommitState {
+    // 最近追加的写入序号
+    appended: u64,
+    // 序号不超过它的写入都已落盘
+    synced: u64,
+    // 是否已有 leader
+    leading: bool,
+    // 落盘失败之后无法确定哪些写入已经持久化，之后的写入全部失败
+    failed: Option<(io::ErrorKind, String)>,
+    metrics: CommitMetrics,
+}
+
+impl GroupCommitStore {
+    pub fn new(store: KvStore, options: GroupCommitOptions) -> GroupCommitStore {
+        GroupCommitStore {
+            inner: Arc::new(Inner {
+                store: Mutex::new(store),
+                state: Mutex::new(CommitState {
+                    appended: 0,
+                    synced: 0,
+                    leading: false,
+                    failed: None,
+                    metrics: CommitMetrics::default(),
+                }),
+                synced: Condvar::new(),
+                appended: Condvar::new(),
+                options,
+                started: Instant::now(),
+            }),
+        }
+    }
+
+    /// 以指定的参数开启存储
+    pub fn open(path: impl Into<PathBuf>, options: StoreOptions, commit: GroupCommitOptions) -> Result<GroupCommitStore> {
+        Ok(GroupCommitStore::new(KvStore::open_with_options(path, options)?, commit))
+    }
+
+    /// 存入数据，返回时已经落盘
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(|store| store.set(key, value))
+    }
+
+    /// 删除数据，返回时已经落盘
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.write(|store| store.remove(key))
+    }
+
+    /// 获取数据
+    /// 可以读到已经追加、还没有落盘的写入
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.lock_store().get(key)
+    }
+
+    /// 当前的组提交统计
+    pub fn metrics(&self) -> CommitMetrics {
+        let mut metrics = self.inner.lock_state().metrics;
+        metrics.elapsed = self.inner.started.elapsed();
+        metrics
+    }
+
+    fn write(&self, op: impl FnOnce(&mut KvStore) -> Result<()>) -> Result<()> {
+        let start = Instant::now();
+        let seq = {
+            let mut store = self.inner.lock_store();
+            self.inner.lock_state().check()?;
+            op(&mut store)?;
+            // 在存储的锁内分配序号，序号的顺序与日志中的顺序一致
+            let mut state = self.inner.lock_state();
+            state.appended += 1;
+            state.appended
+        };
+        self.inner.appended.notify_one();
+
+        let mut state = self.inner.lock_state();
+        loop {
+            state.check()?;
+            if state.synced >= seq {
+                let latency = start.elapsed();
+                state.metrics.writes += 1;
+                state.metrics.total_latency += latency;
+                state.metrics.max_latency = state.metrics.max_latency.max(latency);
+                return Ok(());
+            }
+            if state.leading {
+                state = self.inner.synced.wait(state).expect("commit lock poisoned");
+            } else {
+                state.leading = true;
+                state = self.inner.lead(state);
+            }
+        }
+    }
+}
+
+impl Inner {
+    /// 作为 leader 等待批次、落盘并唤醒等待的写入
+    fn lead<'a>(&'a self, mut state: MutexGuard<'a, CommitState>) -> MutexGuard<'a, CommitState> {
+        let deadline = Instant::now() + self.options.max_batch_delay;
+        while state.appended - state.synced < self.options.max_batch_size {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.appended.wait_timeout(state, deadline - now).expect("commit lock poisoned").0;
+        }
+        drop(state);
+
+        // 在存储的锁内取得批次的范围与需要落盘的文件，落盘时不持有锁，新的写入可以继续追加
+        let result = {
+            let mut store = self.lock_store();
+            let target = self.lock_state().appended;
+            store.unsynced_files().map(|files| (target, files))
+        }
+        .and_then(|(target, files)| {
+            for file in files {
+                file.sync_all()?;
+            }
+            Ok(target)
+        });
+
+        let mut state = self.lock_state();
+        state.leading = false;
+        match result {
+            Ok(target) => {
+                state.metrics.syncs += 1;
+                state.metrics.max_batch = state.metrics.max_batch.max(target - state.synced);
+                state.synced = target;
+            }
+            Err(e) => {
+                let kind = match &e {
+                    KvsError::Io(e) => e.kind(),
+                    _ => io::ErrorKind::Other,
+                };
+                state.failed = Some((kind, e.to_string()));
+            }
+        }
+        self.synced.notify_all();
+        state
+    }
+
+    fn lock_store(&self) -> MutexGuard<'_, KvStore> {
+        self.store.lock().expect("store lock poisoned")
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, CommitState> {
+        self.state.lock().expect("commit lock poisoned")
+    }
+}
+
+impl CommitState {
+    fn check(&self) -> Result<()> {
+        match &self.failed {
+            Some((kind, message)) => Err(KvsError::Io(io::Error::new(
+                *kind,
+                format!("an earlier sync failed: {}", message),
+            ))),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{error::Result, kv::GenStats, KvStore};
+
+/// 默认策略在无效数据超过该值时压缩
+pub const DEFAULT_MIN_DEAD_BYTES: u64 = 1024 * 1024;
+
+/// 默认策略单次最多读取的日志字节数
+pub const DEFAULT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+/// 压缩策略，决定何时压缩以及压缩哪些日志
+///
+/// 每次写入之后（开启自动压缩时）、调用 `KvStore::run_compaction` 时，
+/// 或由 `CompactionScheduler` 定时调用时，以当前各日志的统计询问策略，
+/// 返回的日志会被合并为一个新的日志。
+pub trait CompactionPolicy: Send {
+    /// 返回需要压缩的日志，为空时不压缩
+    fn select(&mut self, stats: &[GenStats]) -> Vec<u64>;
+}
+
+/// 存储总大小超过有效数据的 ratio 倍时压缩所有日志
+#[derive(Debug, Clone)]
+pub struct SizeRatioPolicy {
+    pub ratio: f64,
+    /// 无效数据不足该值时不压缩，避免小存储频繁压缩
+    pub min_dead_bytes: u64,
+}
+
+impl CompactionPolicy for SizeRatioPolicy {
+    fn select(&mut self, stats: &[GenStats]) -> Vec<u64> {
+        let total: u64 = stats.iter().map(|stats| stats.bytes).sum();
+        let dead: u64 = stats.iter().map(|stats| stats.dead_bytes).sum();
+        // 统计中的无效数据可能暂时超过记录字节数，不能下溢
+        let live = total.saturating_sub(dead);
+        if dead < self.min_dead_bytes || (total as f64) <= live as f64 * self.ratio {
+            return Vec::new();
+        }
+        stats.iter().map(|stats| stats.gen).collect()
+    }
+}
+
+/// 无效数据总量超过 min_dead_bytes 时，按无效比例从高到低选择日志
+///
+/// 只选择无效比例不低于 min_ratio 的日志，选中的日志总大小不超过 max_bytes，
+/// 但至少选择一个。
+#[derive(Debug, Clone)]
+pub struct GarbageRatioPolicy {
+    pub min_ratio: f64,
+    pub min_dead_bytes: u64,
+    pub max_bytes: u64,
+}
+
+impl Default for GarbageRatioPolicy {
+    fn default() -> Self {
+        GarbageRatioPolicy {
+            min_ratio: 0.0,
+            min_dead_bytes: DEFAULT_MIN_DEAD_BYTES,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl CompactionPolicy for GarbageRatioPolicy {
+    fn select(&mut self, stats: &[GenStats]) -> Vec<u64> {
+        let dead: u64 = stats.iter().map(|stats| stats.dead_bytes).sum();
+        if dead <= self.min_dead_bytes {
+            return Vec::new();
+        }
+
+        let mut candidates: Vec<&GenStats> = stats
+            .iter()
+            .filter(|stats| stats.dead_bytes > 0 && stats.garbage_ratio() >= self.min_ratio)
+            .collect();
+        // 无效比例高的优先，比例相同时先压缩较早的日志
+        candidates.sort_by(|a, b| b.garbage_ratio().total_cmp(&a.garbage_ratio()).then(a.gen.cmp(&b.gen)));
+
+        let mut selected = Vec::new();
+        let mut total = 0;
+        for stats in candidates {
+            if !selected.is_empty() && total + stats.bytes > self.max_bytes {
+                break;
+            }
+            total += stats.bytes;
+            selected.push(stats.gen);
+        }
+        selected
+    }
+}
+
+/// 只在每天的时间窗口内交给内部策略选择，窗口之外不压缩
+///
+/// 窗口为 UTC 一天中的秒数 [start, end)，start 大于 end 时窗口跨过午夜。
+pub struct TimeWindowPolicy {
+    pub start: u32,
+    pub end: u32,
+    pub inner: Box<dyn CompactionPolicy>,
+}
+
+impl TimeWindowPolicy {
+    /// 当前时间是否在窗口内
+    pub fn in_window(&self, now: SystemTime) -> bool {
+        let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let time_of_day = (secs % 86400) as u32;
+        if self.start <= self.end {
+            (self.start..self.end).contains(&time_of_day)
+        } else {
+            time_of_day >= self.start || time_of_day < self.end
+        }
+    }
+}
+
+impl CompactionPolicy for TimeWindowPolicy {
+    fn select(&mut self, stats: &[GenStats]) -> Vec<u64> {
+        if !self.in_window(SystemTime::now()) {
+            return Vec::new();
+        }
+        self.inner.select(stats)
+    }
+}
+
+/// 在后台线程中按固定间隔调用 `KvStore::run_compaction`
+///
+/// 存储通常关闭自动压缩，以 `Arc<Mutex<KvStore>>` 与调度器共享，压缩期间持有存储的锁。
+/// 压缩出错时调度器停止，错误由 `stop` 返回；drop 时停止调度并等待进行中的压缩完成。
+pub struct CompactionScheduler {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl CompactionScheduler {
+    /// 每隔 interval 按存储的压缩策略压缩一次
+    pub fn start(store: Arc<Mutex<KvStore>>, interval: Duration) -> CompactionScheduler {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    store.lock().expect("store lock poisoned").run_compaction()?;
+                }
+                _ => return Ok(()),
+            }
+        });
+        CompactionScheduler {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// 停止调度，返回后台压缩遇到的错误
+    pub fn stop(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        // 关闭通道即可唤醒等待中的线程
+        self.stop.take();
+        match self.handle.take() {
+            Some(handle) => handle.join().expect("compaction thread panicked"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CompactionScheduler {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::{
+    codec::Codec,
+    error::{KvsError, Result},
+};
+
+/// 密钥的字节数
+pub const KEY_LEN: usize = 32;
+
+/// 加密日志中值的密钥
+///
+/// id 写入日志文件头，读取时据此选择解密的密钥；id 为 0 的日志没有加密。
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: u32,
+    key: [u8; KEY_LEN],
+}
+
+impl EncryptionKey {
+    /// id 必须非 0
+    pub fn new(id: u32, key: [u8; KEY_LEN]) -> Result<EncryptionKey> {
+        if id == 0 {
+            return Err(KvsError::Encryption("key id 0 is reserved for plaintext logs".to_owned()));
+        }
+        Ok(EncryptionKey { id, key })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.key.into())
+    }
+}
+
+// 不在日志与错误信息中输出密钥
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+/// 存储使用的密钥
+///
+/// 新的记录以 current 加密。轮换密钥之后，旧日志仍以旧的密钥加密，
+/// 旧的密钥需要继续提供，直到压缩把这些日志重写为当前的密钥。
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    current: EncryptionKey,
+    previous: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    pub fn new(current: EncryptionKey) -> Keyring {
+        Keyring { current, previous: Vec::new() }
+    }
+
+    /// 加入只用于读取旧日志的密钥
+    pub fn with_previous(mut self, key: EncryptionKey) -> Keyring {
+        self.previous.push(key);
+        self
+    }
+
+    /// 新日志文件头中记录的密钥 id
+    pub fn current_id(&self) -> u32 {
+        self.current.id
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.key(id).is_some()
+    }
+
+    fn key(&self, id: u32) -> Option<&EncryptionKey> {
+        std::iter::once(&self.current).chain(&self.previous).find(|key| key.id == id)
+    }
+}
+
+/// 以当前的密钥加密键 key 的值，返回 nonce 与密文的 base64 以及使用的压缩算法
+///
+/// 值先按 codec 压缩（压缩后没有变小时保持原样）再以 XChaCha20-Poly1305 加密，
+/// 键作为附加数据参与认证，值被挪到别的键下时同样无法解密。
+pub(crate) fn encrypt_value(
+    keyring: &Keyring,
+    key: &str,
+    value: String,
+    codec: Option<Codec>,
+) -> Result<(String, String, Option<Codec>)> {
+    let (plaintext, codec) = match codec {
+        Some(codec) => {
+            let compressed = codec.compress(value.as_bytes())?;
+            if compressed.len() < value.len() {
+                (compressed, Some(codec))
+            } else {
+                (value.into_bytes(), None)
+            }
+        }
+        None => (value.into_bytes(), None),
+    };
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = keyring
+        .current
+        .cipher()
+        .encrypt(&nonce, Payload { msg: &plaintext, aad: key.as_bytes() })
+        .map_err(|_| KvsError::Encryption(format!("value of {:?} cannot be encrypted", key)))?;
+    Ok((STANDARD.encode(nonce), STANDARD.encode(ciphertext), codec))
+}
+
+/// 以日志文件头中 key_id 对应的密钥解密键 key 的值
+/// 认证失败说明记录被篡改或损坏，返回 `Corruption`；解压后超过 max_len 字节时返回错误
+pub(crate) fn decrypt_value(
+    keyring: Option<&Keyring>,
+    key_id: u32,
+    key: &str,
+    nonce: &str,
+    value: &str,
+    codec: Option<Codec>,
+    max_len: u64,
+) -> Result<String> {
+    let secret = keyring.and_then(|keyring| keyring.key(key_id)).ok_or_else(|| {
+        KvsError::Encryption(format!("value of {:?} needs key {} which was not supplied", key, key_id))
+    })?;
+    let corrupt = || KvsError::Corruption(format!("encrypted value of {:?} failed authentication", key));
+    let nonce = STANDARD.decode(nonce).map_err(|_| corrupt())?;
+    if nonce.len() != 24 {
+        return Err(corrupt());
+    }
+    let ciphertext = STANDARD.decode(value).map_err(|_| corrupt())?;
+    let plaintext = secret
+        .cipher()
+        .decrypt(XNonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: key.as_bytes() })
+        .map_err(|_| corrupt())?;
+    let plaintext = match codec {
+        Some(codec) => codec.decompress(&plaintext, max_len)?,
+        None => plaintext,
+    };
+    String::from_utf8(plaintext).map_err(|_| KvsError::Corruption("decrypted value is not UTF-8".to_owned()))
+}
@@ -0,0 +1,568 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, DefaultHasher, Hasher, RandomState},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blob::BlobRef,
+    error::{KvsError, Result},
+    index::packed_pos,
+    kv::{sync_dir, write_atomic, CommandPos, GenStats},
+};
+
+/// 磁盘索引所在的目录，位于存储目录中
+pub(crate) const INDEX_DIR: &str = "index";
+
+/// 每个槽的字节数：u64 哈希、u64 键偏移、u64 位置、u32 日志序号、u32 长度
+const SLOT_LEN: u64 = 32;
+
+const INITIAL_CAPACITY: u64 = 1024;
+
+/// 键偏移为该值的槽已被删除，查找时需要继续向后探测
+const DELETED: u64 = u64::MAX;
+
+/// 正常关闭时保存的索引快照
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// 校验哈希函数没有变化所用的键
+const PROBE_KEY: &[u8] = b"kvs-disk-index";
+
+/// 批量处理槽时每次读取的槽数
+const SLOT_BLOCK: u64 = 1024;
+
+/// 保存在磁盘上的开放寻址哈希表
+///
+/// 槽文件中每个槽记录键的哈希与命令位置，键以 u32 长度前缀追加写入键文件。
+/// 哈希相同的槽需要再读取一次键文件比较键。最近查找过的键保存在有界的缓存中，
+/// 缓存之外的内存占用与键数无关。
+///
+/// 存储正常关闭时索引文件落盘并写入快照，下次打开时日志没有变化就直接使用；
+/// 打开时快照立即删除，之后崩溃留下的索引文件会被丢弃并由日志重建。
+pub(crate) struct DiskIndex {
+    slots_path: PathBuf,
+    keys_path: PathBuf,
+    slots: File,
+    keys: File,
+    keys_len: u64,
+    capacity: u64,
+    len: u64,
+    deleted: u64,
+    // 哈希的种子，随快照保存
+    seed: u64,
+
+    // 最近查找过的键，None 表示该键不存在；按加入的顺序淘汰
+    cache: HashMap<String, Option<CommandPos>>,
+    cache_order: VecDeque<String>,
+    cache_entries: usize,
+    cache_key_bytes: usize,
+}
+
+/// 重新打开索引文件所需的状态
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DiskIndexState {
+    capacity: u64,
+    len: u64,
+    deleted: u64,
+    keys_len: u64,
+    seed: u64,
+    // 以 seed 计算的 PROBE_KEY 的哈希，哈希函数变化时快照失效
+    probe: u64,
+}
+
+/// 存储正常关闭时保存的快照，记录索引对应的日志以及重放日志得到的其余状态
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IndexSnapshot {
+    /// 索引对应的日志及其长度，按序号排列
+    pub(crate) logs: Vec<(u64, u64)>,
+    pub(crate) live: DiskIndexState,
+    pub(crate) tombstones: DiskIndexState,
+    pub(crate) gen_stats: Vec<GenStats>,
+    /// 值存放在 blob 中的键，以及等待回收的 blob 与引用它的记录所在的日志
+    pub(crate) live_blobs: Vec<(String, BlobRef)>,
+    pub(crate) dead_blobs: Vec<(u64, BlobRef)>,
+}
+
+impl IndexSnapshot {
+    /// 写入快照，调用者需要先将索引文件落盘
+    pub(crate) fn store(&self, dir: &Path) -> Result<()> {
+        write_atomic(dir, SNAPSHOT_FILE, &serde_json::to_vec(self)?)
+    }
+
+    /// 读取并删除快照，快照不存在或无法解析时返回 None
+    /// 删除落盘之后索引文件才可以修改
+    pub(crate) fn take(dir: &Path) -> Result<Option<IndexSnapshot>> {
+        let path = dir.join(SNAPSHOT_FILE);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        fs::remove_file(&path)?;
+        sync_dir(dir)?;
+        Ok(serde_json::from_slice(&data).ok())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    hash: u64,
+    key_off: u64,
+    cmd_pos: CommandPos,
+}
+
+impl DiskIndex {
+    /// 在 dir 中创建名为 name 的空索引，已有的同名索引会被覆盖
+    pub(crate) fn create(dir: &Path, name: &str, cache_entries: usize) -> Result<DiskIndex> {
+        fs::create_dir_all(dir)?;
+        let slots_path = dir.join(format!("{}.slots", name));
+        let keys_path = dir.join(format!("{}.keys", name));
+        let index = DiskIndex {
+            slots: create_file(&slots_path)?,
+            keys: create_file(&keys_path)?,
+            slots_path,
+            keys_path,
+            keys_len: 0,
+            capacity: INITIAL_CAPACITY,
+            len: 0,
+            deleted: 0,
+            seed: RandomState::new().build_hasher().finish(),
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+            cache_entries,
+            cache_key_bytes: 0,
+        };
+        index.slots.set_len(INITIAL_CAPACITY * SLOT_LEN)?;
+        Ok(index)
+    }
+
+    /// 以快照中的状态打开 dir 中名为 name 的已有索引
+    /// 文件大小或哈希函数与快照不符时返回 None，文件内容损坏时返回 `KvsError::Corruption`
+    pub(crate) fn reopen(dir: &Path, name: &str, cache_entries: usize, state: DiskIndexState) -> Result<Option<DiskIndex>> {
+        let slots_path = dir.join(format!("{}.slots", name));
+        let keys_path = dir.join(format!("{}.keys", name));
+        let open = |path: &Path| OpenOptions::new().read(true).write(true).open(path);
+        let (slots, keys) = match (open(&slots_path), open(&keys_path)) {
+            (Ok(slots), Ok(keys)) => (slots, keys),
+            _ => return Ok(None),
+        };
+        if state.probe != key_hash(state.seed, PROBE_KEY)
+            || !state.capacity.is_power_of_two()
+            || slots.metadata()?.len() != state.capacity * SLOT_LEN
+            || keys.metadata()?.len() != state.keys_len
+        {
+            return Ok(None);
+        }
+        check_files(&slots, &keys, &state)?;
+        Ok(Some(DiskIndex {
+            slots_path,
+            keys_path,
+            slots,
+            keys,
+            keys_len: state.keys_len,
+            capacity: state.capacity,
+            len: state.len,
+            deleted: state.deleted,
+            seed: state.seed,
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+            cache_entries,
+            cache_key_bytes: 0,
+        }))
+    }
+
+    /// 将索引文件落盘，返回重新打开所需的状态
+    pub(crate) fn persist(&self) -> Result<DiskIndexState> {
+        self.slots.sync_all()?;
+        self.keys.sync_all()?;
+        Ok(DiskIndexState {
+            capacity: self.capacity,
+            len: self.len,
+            deleted: self.deleted,
+            keys_len: self.keys_len,
+            seed: self.seed,
+            probe: key_hash(self.seed, PROBE_KEY),
+        })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        if let Some(&cmd_pos) = self.cache.get(key) {
+            return Ok(cmd_pos);
+        }
+        let hash = self.hash(key);
+        let cmd_pos = self.find(key, hash)?.1.map(|slot| slot.cmd_pos);
+        self.cache_put(key, cmd_pos);
+        Ok(cmd_pos)
+    }
+
+    /// 写入键的位置，返回原来的位置
+    pub(crate) fn insert(&mut self, key: &str, cmd_pos: CommandPos) -> Result<Option<CommandPos>> {
+        // 包括已删除的槽在内最多使用一半的槽，保证探测可以遇到空槽
+        if (self.len + self.deleted + 1) * 2 > self.capacity {
+            self.rebuild()?;
+        }
+        let hash = self.hash(key);
+        let (i, found) = self.find(key, hash)?;
+        let old = match found {
+            Some(slot) => {
+                write_slot(&mut self.slots, i, &Slot { cmd_pos, ..slot })?;
+                Some(slot.cmd_pos)
+            }
+            None => {
+                // 复用已删除的槽
+                if read_slot(&mut self.slots, i)?.hash != 0 {
+                    self.deleted -= 1;
+                }
+                let key_off = self.append_key(key.as_bytes())?;
+                write_slot(&mut self.slots, i, &Slot { hash, key_off, cmd_pos })?;
+                self.len += 1;
+                None
+            }
+        };
+        // 写入不加入缓存，只更新已经缓存的键
+        if let Some(cached) = self.cache.get_mut(key) {
+            *cached = Some(cmd_pos);
+        }
+        Ok(old)
+    }
+
+    /// 删除键，返回它的位置
+    pub(crate) fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        let hash = self.hash(key);
+        let (i, found) = self.find(key, hash)?;
+        let Some(slot) = found else {
+            return Ok(None);
+        };
+        write_slot(&mut self.slots, i, &Slot { key_off: DELETED, ..slot })?;
+        self.len -= 1;
+        self.deleted += 1;
+        if let Some(cached) = self.cache.get_mut(key) {
+            *cached = None;
+        }
+        Ok(Some(slot.cmd_pos))
+    }
+
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        self.slots.set_len(0)?;
+        self.slots.set_len(INITIAL_CAPACITY * SLOT_LEN)?;
+        self.keys.set_len(0)?;
+        self.keys_len = 0;
+        self.capacity = INITIAL_CAPACITY;
+        self.len = 0;
+        self.deleted = 0;
+        self.cache.clear();
+        self.cache_order.clear();
+        self.cache_key_bytes = 0;
+        Ok(())
+    }
+
+    /// 按槽的顺序遍历所有的键与位置
+    pub(crate) fn for_each(&mut self, mut f: impl FnMut(&str, CommandPos)) -> Result<()> {
+        self.slots.seek(SeekFrom::Start(0))?;
+        let mut slots = BufReader::new(&self.slots);
+        let mut buf = [0; SLOT_LEN as usize];
+        for _ in 0..self.capacity {
+            slots.read_exact(&mut buf)?;
+            let slot = decode_slot(&buf);
+            if slot.hash == 0 || slot.key_off == DELETED {
+                continue;
+            }
+            let key = key_string(read_key(&mut self.keys, self.keys_len, slot.key_off)?)?;
+            f(&key, slot.cmd_pos);
+        }
+        Ok(())
+    }
+
+    /// 按槽的顺序分批遍历，cursor 为槽的序号
+    pub(crate) fn batch(&mut self, cursor: &mut u64, max: usize) -> Result<Vec<(String, CommandPos)>> {
+        let mut batch = Vec::new();
+        self.slots.seek(SeekFrom::Start(*cursor * SLOT_LEN))?;
+        let mut slots = BufReader::new(&self.slots);
+        let mut buf = [0; SLOT_LEN as usize];
+        while batch.len() < max && *cursor < self.capacity {
+            slots.read_exact(&mut buf)?;
+            *cursor += 1;
+            let slot = decode_slot(&buf);
+            if slot.hash == 0 || slot.key_off == DELETED {
+                continue;
+            }
+            let key = read_key(&mut self.keys, self.keys_len, slot.key_off)?;
+            batch.push((key_string(key)?, slot.cmd_pos));
+        }
+        Ok(batch)
+    }
+
+    /// 删除位置不满足 keep 的键
+    /// 槽按块读取，块中有删除的槽时整块写回
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&CommandPos) -> bool) -> Result<()> {
+        let mut block = vec![0; (SLOT_BLOCK * SLOT_LEN) as usize];
+        let mut start = 0;
+        while start < self.capacity {
+            let count = SLOT_BLOCK.min(self.capacity - start);
+            let block = &mut block[..(count * SLOT_LEN) as usize];
+            self.slots.seek(SeekFrom::Start(start * SLOT_LEN))?;
+            self.slots.read_exact(block)?;
+            let mut changed = false;
+            for buf in block.chunks_exact_mut(SLOT_LEN as usize) {
+                let slot = decode_slot((&*buf).try_into().expect("chunk of SLOT_LEN bytes"));
+                if slot.hash == 0 || slot.key_off == DELETED || keep(&slot.cmd_pos) {
+                    continue;
+                }
+                buf[8..16].copy_from_slice(&DELETED.to_le_bytes());
+                self.len -= 1;
+                self.deleted += 1;
+                changed = true;
+            }
+            if changed {
+                self.slots.seek(SeekFrom::Start(start * SLOT_LEN))?;
+                self.slots.write_all(block)?;
+            }
+            start += count;
+        }
+        // 删除的键可能在缓存中
+        self.cache.clear();
+        self.cache_order.clear();
+        self.cache_key_bytes = 0;
+        Ok(())
+    }
+
+    /// 缓存占用的内存字节数（估算）
+    pub(crate) fn cache_bytes(&self) -> usize {
+        let entry = mem::size_of::<String>() + mem::size_of::<Option<CommandPos>>() + 1;
+        self.cache.capacity() * entry
+            + self.cache_order.capacity() * mem::size_of::<String>()
+            + self.cache_key_bytes * 2
+    }
+
+    /// 槽文件与键文件的字节数
+    pub(crate) fn disk_bytes(&self) -> u64 {
+        self.capacity * SLOT_LEN + self.keys_len
+    }
+
+    fn hash(&self, key: &str) -> u64 {
+        key_hash(self.seed, key.as_bytes())
+    }
+
+    /// 返回键所在的槽，键不存在时返回可以写入的槽
+    fn find(&mut self, key: &str, hash: u64) -> Result<(u64, Option<Slot>)> {
+        let mask = self.capacity - 1;
+        let mut i = hash & mask;
+        let mut free = None;
+        loop {
+            let slot = read_slot(&mut self.slots, i)?;
+            if slot.hash == 0 {
+                return Ok((free.unwrap_or(i), None));
+            }
+            if slot.key_off == DELETED {
+                free.get_or_insert(i);
+            } else if slot.hash == hash && read_key(&mut self.keys, self.keys_len, slot.key_off)? == key.as_bytes() {
+                return Ok((i, Some(slot)));
+            }
+            i = (i + 1) & mask;
+        }
+    }
+
+    fn append_key(&mut self, key: &[u8]) -> Result<u64> {
+        let key_off = self.keys_len;
+        self.keys.seek(SeekFrom::Start(key_off))?;
+        self.keys.write_all(&(key.len() as u32).to_le_bytes())?;
+        self.keys.write_all(key)?;
+        self.keys_len += 4 + key.len() as u64;
+        Ok(key_off)
+    }
+
+    fn cache_put(&mut self, key: &str, cmd_pos: Option<CommandPos>) {
+        if self.cache_entries == 0 {
+            return;
+        }
+        while self.cache_order.len() >= self.cache_entries {
+            if let Some(evicted) = self.cache_order.pop_front() {
+                self.cache_key_bytes -= evicted.len();
+                self.cache.remove(&evicted);
+            }
+        }
+        self.cache_key_bytes += key.len();
+        self.cache_order.push_back(key.to_owned());
+        self.cache.insert(key.to_owned(), cmd_pos);
+    }
+
+    /// 把存活的键写入新的槽文件与键文件，丢弃已删除的槽并按键数扩容
+    fn rebuild(&mut self) -> Result<()> {
+        let capacity = ((self.len + 1) * 4).next_power_of_two().max(INITIAL_CAPACITY);
+        let slots_tmp = tmp_path(&self.slots_path);
+        let keys_tmp = tmp_path(&self.keys_path);
+        let mut new_slots = create_file(&slots_tmp)?;
+        let mut new_keys = BufWriter::new(create_file(&keys_tmp)?);
+        new_slots.set_len(capacity * SLOT_LEN)?;
+        // 新表中已占用的槽，探测时不需要读取槽文件
+        let mut used = vec![0u64; capacity.div_ceil(64) as usize];
+
+        let mut keys_len = 0;
+        let mask = capacity - 1;
+        self.slots.seek(SeekFrom::Start(0))?;
+        let mut slots = BufReader::new(&self.slots);
+        let mut buf = [0; SLOT_LEN as usize];
+        for _ in 0..self.capacity {
+            slots.read_exact(&mut buf)?;
+            let slot = decode_slot(&buf);
+            if slot.hash == 0 || slot.key_off == DELETED {
+                continue;
+            }
+            let key = read_key(&mut self.keys, self.keys_len, slot.key_off)?;
+            new_keys.write_all(&(key.len() as u32).to_le_bytes())?;
+            new_keys.write_all(&key)?;
+
+            // 新表中没有重复的键，遇到空槽即可写入
+            let mut i = slot.hash & mask;
+            while used[(i / 64) as usize] & (1 << (i % 64)) != 0 {
+                i = (i + 1) & mask;
+            }
+            used[(i / 64) as usize] |= 1 << (i % 64);
+            write_slot(&mut new_slots, i, &Slot { key_off: keys_len, ..slot })?;
+            keys_len += 4 + key.len() as u64;
+        }
+
+        let new_keys = new_keys.into_inner().map_err(|e| e.into_error())?;
+        fs::rename(&slots_tmp, &self.slots_path)?;
+        fs::rename(&keys_tmp, &self.keys_path)?;
+        self.slots = new_slots;
+        self.keys = new_keys;
+        self.keys_len = keys_len;
+        self.capacity = capacity;
+        self.deleted = 0;
+        Ok(())
+    }
+}
+
+fn create_file(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn read_slot(file: &mut File, i: u64) -> Result<Slot> {
+    let mut buf = [0; SLOT_LEN as usize];
+    file.seek(SeekFrom::Start(i * SLOT_LEN))?;
+    file.read_exact(&mut buf)?;
+    Ok(decode_slot(&buf))
+}
+
+fn write_slot(file: &mut File, i: u64, slot: &Slot) -> Result<()> {
+    let mut buf = [0; SLOT_LEN as usize];
+    buf[..8].copy_from_slice(&slot.hash.to_le_bytes());
+    buf[8..16].copy_from_slice(&slot.key_off.to_le_bytes());
+    buf[16..24].copy_from_slice(&slot.cmd_pos.pos.to_le_bytes());
+    let (gen, len) = packed_pos(slot.cmd_pos)?;
+    buf[24..28].copy_from_slice(&gen.to_le_bytes());
+    buf[28..32].copy_from_slice(&len.to_le_bytes());
+    file.seek(SeekFrom::Start(i * SLOT_LEN))?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+fn decode_slot(buf: &[u8; SLOT_LEN as usize]) -> Slot {
+    let u64_at = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().expect("slice of 8 bytes"));
+    let u32_at = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().expect("slice of 4 bytes"));
+    Slot {
+        hash: u64_at(0),
+        key_off: u64_at(8),
+        cmd_pos: CommandPos {
+            gen: u64::from(u32_at(24)),
+            pos: u64_at(16),
+            len: u64::from(u32_at(28)),
+        },
+    }
+}
+
+/// 以 seed 计算键的哈希，哈希值 0 表示空槽
+fn key_hash(seed: u64, key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(seed);
+    hasher.write(key);
+    hasher.finish() | 1
+}
+
+/// 读取键文件中 key_off 处的键，键的长度不能超出键文件已写入的 keys_len 字节
+fn read_key(file: &mut File, keys_len: u64, key_off: u64) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    let end = key_off.checked_add(4).filter(|&end| end <= keys_len).ok_or_else(|| {
+        KvsError::Corruption(format!("index key at {} is beyond the end of the key file", key_off))
+    })?;
+    file.seek(SeekFrom::Start(key_off))?;
+    file.read_exact(&mut len)?;
+    let len = u64::from(u32::from_le_bytes(len));
+    if len > keys_len - end {
+        return Err(KvsError::Corruption(format!("index key at {} with length {} is beyond the end of the key file", key_off, len)));
+    }
+    let mut key = vec![0; len as usize];
+    file.read_exact(&mut key)?;
+    Ok(key)
+}
+
+fn key_string(key: Vec<u8>) -> Result<String> {
+    // 键文件中只写入过 &str 的字节，不是 UTF-8 时文件已损坏
+    String::from_utf8(key).map_err(|_| KvsError::Corruption("index key is not valid UTF-8".to_owned()))
+}
+
+/// 检查重新打开的索引文件：键文件由完整的 UTF-8 键组成，
+/// 槽中的键偏移位于键文件之内，键数与快照一致
+fn check_files(slots: &File, keys: &File, state: &DiskIndexState) -> Result<()> {
+    let mut reader = BufReader::new(keys);
+    let mut off = 0;
+    let mut len = [0; 4];
+    let mut key = Vec::new();
+    while off < state.keys_len {
+        if state.keys_len - off < 4 {
+            return Err(KvsError::Corruption(format!("index key at {} is beyond the end of the key file", off)));
+        }
+        reader.read_exact(&mut len)?;
+        let len = u64::from(u32::from_le_bytes(len));
+        if len > state.keys_len - off - 4 {
+            return Err(KvsError::Corruption(format!("index key at {} with length {} is beyond the end of the key file", off, len)));
+        }
+        key.resize(len as usize, 0);
+        reader.read_exact(&mut key)?;
+        if std::str::from_utf8(&key).is_err() {
+            return Err(KvsError::Corruption("index key is not valid UTF-8".to_owned()));
+        }
+        off += 4 + len;
+    }
+
+    let mut reader = BufReader::new(slots);
+    let mut buf = [0; SLOT_LEN as usize];
+    let (mut live, mut deleted) = (0, 0);
+    for _ in 0..state.capacity {
+        reader.read_exact(&mut buf)?;
+        let slot = decode_slot(&buf);
+        if slot.hash == 0 {
+            continue;
+        }
+        if slot.key_off == DELETED {
+            deleted += 1;
+        } else if slot.key_off >= state.keys_len {
+            return Err(KvsError::Corruption(format!("index key at {} is beyond the end of the key file", slot.key_off)));
+        } else {
+            live += 1;
+        }
+    }
+    if (live, deleted) != (state.len, state.deleted) {
+        return Err(KvsError::Corruption(format!(
+            "index holds {} keys and {} deleted slots, snapshot expects {} and {}",
+            live, deleted, state.len, state.deleted
+        )));
+    }
+    Ok(())
+}
@@ -1,3 +1,7 @@
+// failure 的派生宏会在与 KvsError 同级的常量块中生成 impl，
+// 写在枚举上的 allow 无法覆盖它，只能在本模块内允许
+#![allow(non_local_definitions)]
+
 use std::io;
 use failure::Fail;
 
@@ -21,6 +25,49 @@ pub enum KvsError {
     /// 有损坏的日志或程序错误
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+
+    /// 复制协议错误
+    /// leader 与 follower 之间收到了无法识别的消息
+    #[fail(display = "Replication protocol error: {}", _0)]
+    Replication(String),
+
+    /// 当前节点不是 leader，无法处理写入
+    /// 已知 leader 时附带其节点编号
+    #[fail(display = "Not the leader")]
+    NotLeader(Option<u64>),
+
+    /// Raft 集群错误
+    #[fail(display = "Raft error: {}", _0)]
+    Raft(String),
+
+    /// 分片布局错误
+    #[fail(display = "Shard error: {}", _0)]
+    Shard(String),
+
+    /// 导入的数据格式错误
+    #[fail(display = "Invalid import data: {}", _0)]
+    InvalidImport(String),
+
+    /// 日志格式无法识别
+    /// 旧格式的存储需要先执行 `kvs migrate`
+    #[fail(display = "Unsupported log format: {}", _0)]
+    UnsupportedFormat(String),
+
+    /// 单条记录超过索引可以记录的长度
+    #[fail(display = "Record of {} bytes exceeds the limit of {} bytes", _0, _1)]
+    RecordTooLarge(u64, u64),
+
+    /// 值超过存储允许的最大字节数
+    #[fail(display = "Value of {} bytes exceeds the limit of {} bytes", _0, _1)]
+    ValueTooLarge(u64, u64),
+
+    /// 记录的内容无法还原
+    #[fail(display = "Corrupted record: {}", _0)]
+    Corruption(String),
+
+    /// 缺少解密所需的密钥或无法加密
+    #[fail(display = "Encryption error: {}", _0)]
+    Encryption(String),
 }
 
 impl From<io::Error> for KvsError {
@@ -0,0 +1,244 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{KvsError, Result};
+
+/// 二进制导出格式的文件头
+const BINARY_MAGIC: &[u8; 8] = b"KVSDUMP\x01";
+
+/// CSV 导出格式的表头
+const CSV_HEADER: [&str; 2] = ["key", "value"];
+
+/// 导入导出的数据格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    /// 每行一个 `{"key": ..., "value": ...}` 对象
+    JsonLines,
+    /// 带 `key,value` 表头的 CSV
+    Csv,
+    /// 文件头之后为长度前缀的键值对
+    Binary,
+}
+
+impl FromStr for DataFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<DataFormat, String> {
+        match s {
+            "jsonl" | "json-lines" => Ok(DataFormat::JsonLines),
+            "csv" => Ok(DataFormat::Csv),
+            "bin" | "binary" => Ok(DataFormat::Binary),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+/// 导入结果统计
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportStats {
+    /// 新增的键
+    pub inserted: u64,
+    /// 覆盖已有值的键
+    pub overwritten: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    key: String,
+    value: String,
+}
+
+/// 按格式逐条写出键值对
+pub(crate) struct RecordWriter<W: Write> {
+    writer: W,
+    format: DataFormat,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub(crate) fn new(mut writer: W, format: DataFormat) -> Result<Self> {
+        match format {
+            DataFormat::Csv => write_csv_row(&mut writer, &CSV_HEADER)?,
+            DataFormat::Binary => writer.write_all(BINARY_MAGIC)?,
+            DataFormat::JsonLines => {}
+        }
+        Ok(RecordWriter { writer, format })
+    }
+
+    pub(crate) fn write(&mut self, key: String, value: String) -> Result<()> {
+        match self.format {
+            DataFormat::JsonLines => {
+                serde_json::to_writer(&mut self.writer, &JsonRecord { key, value })?;
+                self.writer.write_all(b"\n")?;
+            }
+            DataFormat::Csv => write_csv_row(&mut self.writer, &[&key, &value])?,
+            DataFormat::Binary => {
+                for field in [key.as_bytes(), value.as_bytes()] {
+                    let len = u32::try_from(field.len())
+                        .map_err(|_| KvsError::InvalidImport("field larger than 4 GiB".to_owned()))?;
+                    self.writer.write_all(&len.to_le_bytes())?;
+                    self.writer.write_all(field)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// 按格式逐条读取键值对
+pub(crate) struct RecordReader<R: Read> {
+    reader: BufReader<R>,
+    format: DataFormat,
+    started: bool,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub(crate) fn new(reader: R, format: DataFormat) -> Self {
+        RecordReader {
+            reader: BufReader::new(reader),
+            format,
+            started: false,
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<(String, String)>> {
+        if !self.started {
+            self.started = true;
+            match self.format {
+                DataFormat::Csv => {
+                    let header = read_csv_row(&mut self.reader)?;
+                    if header.as_deref() != Some(&CSV_HEADER.map(str::to_owned)[..]) {
+                        return Err(KvsError::InvalidImport("missing key,value header".to_owned()));
+                    }
+                }
+                DataFormat::Binary => {
+                    let mut magic = [0; 8];
+                    self.reader.read_exact(&mut magic)?;
+                    if &magic != BINARY_MAGIC {
+                        return Err(KvsError::InvalidImport("not a binary dump".to_owned()));
+                    }
+                }
+                DataFormat::JsonLines => {}
+            }
+        }
+
+        match self.format {
+            DataFormat::JsonLines => loop {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: JsonRecord = serde_json::from_str(&line)?;
+                return Ok(Some((record.key, record.value)));
+            },
+            DataFormat::Csv => match read_csv_row(&mut self.reader)? {
+                None => Ok(None),
+                Some(mut row) if row.len() == 2 => {
+                    let value = row.pop().expect("row has two fields");
+                    let key = row.pop().expect("row has two fields");
+                    Ok(Some((key, value)))
+                }
+                Some(row) => Err(KvsError::InvalidImport(format!("expected 2 fields, found {}", row.len()))),
+            },
+            DataFormat::Binary => {
+                if self.reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let key = read_binary_field(&mut self.reader)?;
+                let value = read_binary_field(&mut self.reader)?;
+                Ok(Some((key, value)))
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn read_binary_field<R: Read>(reader: &mut R) -> Result<String> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    // 长度来自导入的数据，按实际读到的内容分配
+    let len = u64::from(u32::from_le_bytes(len));
+    let mut buf = Vec::new();
+    if reader.take(len).read_to_end(&mut buf)? as u64 != len {
+        return Err(KvsError::InvalidImport("truncated field".to_owned()));
+    }
+    String::from_utf8(buf).map_err(|_| KvsError::InvalidImport("field is not valid UTF-8".to_owned()))
+}
+
+/// 包含逗号、引号或换行的字段加引号，引号写两次
+fn write_csv_row<W: Write>(writer: &mut W, fields: &[&str]) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            write!(writer, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            writer.write_all(field.as_bytes())?;
+        }
+    }
+    writer.write_all(b"\n")
+}
+
+/// 读取一行 CSV，引号内的字段可以跨行
+fn read_csv_row<R: BufRead>(reader: &mut R) -> Result<Option<Vec<String>>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    loop {
+        if i == chars.len() {
+            if !quoted {
+                break;
+            }
+            // 引号内的换行，继续读取下一行
+            let mut next = String::new();
+            if reader.read_line(&mut next)? == 0 {
+                return Err(KvsError::InvalidImport("unterminated quoted field".to_owned()));
+            }
+            chars.extend(next.chars());
+            continue;
+        }
+        let c = chars[i];
+        i += 1;
+        match (quoted, c) {
+            (true, '"') if chars.get(i) == Some(&'"') => {
+                field.push('"');
+                i += 1;
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (false, '\n') => break,
+            (false, '\r') if chars.get(i) == Some(&'\n') => {}
+            (false, c) => field.push(c),
+        }
+    }
+    fields.push(field);
+    Ok(Some(fields))
+}
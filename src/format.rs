@@ -0,0 +1,177 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{KvsError, Result},
+    kv::{log_path, sorted_gen_list, sync_dir},
+};
+
+/// 日志文件头的魔数
+const MAGIC: &[u8; 8] = b"KVSLOG\0\0";
+
+/// 当前的日志格式版本
+/// 没有文件头的旧格式视为版本 0
+pub const FORMAT_VERSION: u32 = 1;
+
+/// 日志文件头的长度，记录从该位置开始
+pub const HEADER_LEN: u64 = 32;
+
+/// 日志文件头
+///
+/// 布局为 8 字节魔数、u32 版本、u32 密钥 id、u64 创建时间（Unix 秒）、u64 日志序号，
+/// 整数均为小端序。密钥 id 为 0 的日志没有加密。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogHeader {
+    pub version: u32,
+    /// 加密日志中的值所用的密钥
+    pub key_id: u32,
+    pub created_at: u64,
+    pub gen: u64,
+}
+
+impl LogHeader {
+    /// 以当前版本与当前时间为日志 gen 创建未加密的文件头
+    pub fn new(gen: u64) -> LogHeader {
+        LogHeader {
+            version: FORMAT_VERSION,
+            key_id: 0,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            gen,
+        }
+    }
+
+    /// 日志中的值以 key_id 对应的密钥加密
+    pub fn with_key_id(self, key_id: u32) -> LogHeader {
+        LogHeader { key_id, ..self }
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut buf = [0; HEADER_LEN as usize];
+        buf[..8].copy_from_slice(MAGIC);
+        buf[8..12].copy_from_slice(&self.version.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.key_id.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.created_at.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.gen.to_le_bytes());
+        writer.write_all(&buf)
+    }
+
+    /// 读取并校验日志 gen 的文件头
+    /// 旧格式、未知版本或序号不符时返回 `UnsupportedFormat`
+    pub(crate) fn read_from<R: Read>(reader: &mut R, gen: u64) -> Result<LogHeader> {
+        let mut buf = [0; HEADER_LEN as usize];
+        let read = read_full(reader, &mut buf)?;
+        if read < MAGIC.len() || &buf[..8] != MAGIC {
+            // 旧格式的日志直接以 JSON 命令开始
+            let message = if read == 0 || buf[0] == b'{' {
+                format!("{}.log has no header, run `kvs migrate` to upgrade the store", gen)
+            } else {
+                format!("{}.log is not a kvs log", gen)
+            };
+            return Err(KvsError::UnsupportedFormat(message));
+        }
+        if read < buf.len() {
+            return Err(KvsError::UnsupportedFormat(format!("{}.log has a truncated header", gen)));
+        }
+
+        let header = LogHeader {
+            version: u32::from_le_bytes(buf[8..12].try_into().expect("slice of 4 bytes")),
+            key_id: u32::from_le_bytes(buf[12..16].try_into().expect("slice of 4 bytes")),
+            created_at: u64::from_le_bytes(buf[16..24].try_into().expect("slice of 8 bytes")),
+            gen: u64::from_le_bytes(buf[24..32].try_into().expect("slice of 8 bytes")),
+        };
+        if header.version != FORMAT_VERSION {
+            return Err(KvsError::UnsupportedFormat(format!(
+                "{}.log uses format version {}, this build supports version {}",
+                gen, header.version, FORMAT_VERSION
+            )));
+        }
+        if header.gen != gen {
+            return Err(KvsError::UnsupportedFormat(format!(
+                "{}.log has a header for generation {}",
+                gen, header.gen
+            )));
+        }
+        Ok(header)
+    }
+}
+
+/// 读满 buf 或读到文件末尾，返回读到的字节数
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// 读取并校验日志 gen 的文件头
+pub(crate) fn read_header(dir: &Path, gen: u64) -> Result<LogHeader> {
+    LogHeader::read_from(&mut File::open(log_path(dir, gen))?, gen)
+}
+
+/// 读取日志 gen 文件头之后的全部记录
+pub(crate) fn read_records(dir: &Path, gen: u64) -> Result<Vec<u8>> {
+    let mut file = File::open(log_path(dir, gen))?;
+    LogHeader::read_from(&mut file, gen)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// 迁移结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrateReport {
+    /// 补上文件头的日志
+    pub migrated: Vec<u64>,
+    /// 已经是当前格式的日志
+    pub current: Vec<u64>,
+}
+
+/// 把没有文件头的旧格式存储升级到当前格式
+///
+/// 每个旧日志（包括创建后还没有写入任何内容的空日志）流式复制到带文件头的临时文件，
+/// 落盘后重命名替换，中途中断可以重新执行。迁移时存储不能处于打开状态。
+pub fn migrate(path: impl AsRef<Path>) -> Result<MigrateReport> {
+    let path = path.as_ref();
+    let mut report = MigrateReport::default();
+    for gen in sorted_gen_list(path)? {
+        let mut reader = File::open(log_path(path, gen))?;
+        let mut head = [0; HEADER_LEN as usize];
+        let read = read_full(&mut reader, &mut head)?;
+        let head = &head[..read];
+        if head.starts_with(MAGIC) {
+            // 已有文件头，只确认版本可以识别
+            LogHeader::read_from(&mut &head[..], gen)?;
+            report.current.push(gen);
+            continue;
+        }
+        if head.first().is_some_and(|&b| b != b'{') {
+            return Err(KvsError::UnsupportedFormat(format!("{}.log is not a kvs log", gen)));
+        }
+
+        let tmp_path = path.join(format!("{}.log.tmp", gen));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        LogHeader::new(gen).write_to(&mut writer)?;
+        writer.write_all(head)?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        fs::rename(&tmp_path, log_path(path, gen))?;
+        report.migrated.push(gen);
+    }
+    // 目录落盘之后重命名才可靠
+    if !report.migrated.is_empty() {
+        sync_dir(path)?;
+    }
+    Ok(report)
+}
@@ -0,0 +1,351 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    path::Path,
+};
+
+use hashbrown::HashTable;
+
+use crate::{
+    diskindex::DiskIndex,
+    error::{KvsError, Result},
+    kv::CommandPos,
+};
+
+/// 单条记录的最大字节数，索引以 u32 记录长度
+pub const MAX_RECORD_LEN: u64 = u32::MAX as u64;
+
+/// 已删除的键占用的空间超过该值并且超过存储区的一半时整理存储区
+const MIN_GARBAGE_BYTES: usize = 64 * 1024;
+
+/// 索引保存的位置
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum IndexMode {
+    /// 所有键保存在内存中
+    #[default]
+    Memory,
+    /// 键保存在存储目录下的磁盘哈希表中，内存中只缓存最近查找过的 cache_entries 个键
+    /// 缓存之外的查找需要额外读取磁盘
+    Disk { cache_entries: usize },
+}
+
+/// 键到命令位置的索引
+pub(crate) enum Index {
+    Memory(MemoryIndex),
+    Disk(DiskIndex),
+}
+
+/// 键到命令位置的紧凑内存索引
+///
+/// 键以长度前缀连续存放在一块存储区中，不再为每个键单独分配内存；
+/// 哈希表中每一项只有键的偏移与压缩后的位置，共 24 字节。
+pub(crate) struct MemoryIndex {
+    table: HashTable<Entry>,
+    keys: Vec<u8>,
+    // 已删除的键在存储区中留下的字节数
+    garbage: usize,
+    hasher: RandomState,
+}
+
+struct Entry {
+    key_off: u64,
+    pos: u64,
+    gen: u32,
+    len: u32,
+}
+
+/// 索引占用的内存
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexMemory {
+    /// 索引中的键数
+    pub entries: usize,
+    /// 键的存储区占用的字节数，包括已删除的键还没有回收的空间
+    pub key_bytes: usize,
+    /// 哈希表占用的字节数，磁盘索引为缓存占用的字节数
+    pub table_bytes: usize,
+    /// 磁盘索引文件的字节数，不计入内存
+    pub disk_bytes: u64,
+}
+
+impl IndexMemory {
+    /// 索引占用的总字节数
+    pub fn total(&self) -> usize {
+        self.key_bytes + self.table_bytes
+    }
+}
+
+impl Index {
+    /// 创建名为 name 的空索引，磁盘索引的文件位于 dir 中
+    pub(crate) fn create(mode: &IndexMode, dir: &Path, name: &str) -> Result<Index> {
+        Ok(match *mode {
+            IndexMode::Memory => Index::Memory(MemoryIndex::new()),
+            IndexMode::Disk { cache_entries } => Index::Disk(DiskIndex::create(dir, name, cache_entries)?),
+        })
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(index) => Ok(index.get(key)),
+            Index::Disk(index) => index.get(key),
+        }
+    }
+
+    pub(crate) fn contains_key(&mut self, key: &str) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// 写入键的位置，返回原来的位置
+    pub(crate) fn insert(&mut self, key: &str, cmd_pos: CommandPos) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(index) => index.insert(key, cmd_pos),
+            Index::Disk(index) => index.insert(key, cmd_pos),
+        }
+    }
+
+    /// 删除键，返回它的位置
+    pub(crate) fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(index) => Ok(index.remove(key)),
+            Index::Disk(index) => index.remove(key),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        match self {
+            Index::Memory(index) => {
+                index.clear();
+                Ok(())
+            }
+            Index::Disk(index) => index.clear(),
+        }
+    }
+
+    /// 遍历所有的键与位置，顺序不定
+    pub(crate) fn for_each(&mut self, mut f: impl FnMut(&str, CommandPos)) -> Result<()> {
+        match self {
+            Index::Memory(index) => {
+                for (key, cmd_pos) in index.iter() {
+                    f(key, cmd_pos);
+                }
+                Ok(())
+            }
+            Index::Disk(index) => index.for_each(f),
+        }
+    }
+
+    /// 从 cursor 处继续遍历，至多返回 max 个键与位置，遍历结束时返回空
+    /// cursor 从 0 开始，分批遍历期间索引不能被修改
+    pub(crate) fn batch(&mut self, cursor: &mut u64, max: usize) -> Result<Vec<(String, CommandPos)>> {
+        match self {
+            Index::Memory(index) => Ok(index.batch(cursor, max)),
+            Index::Disk(index) => index.batch(cursor, max),
+        }
+    }
+
+    /// 索引中的键数
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Index::Memory(index) => index.table.len() as u64,
+            Index::Disk(index) => index.len(),
+        }
+    }
+
+    /// 删除位置不满足 keep 的键
+    pub(crate) fn retain(&mut self, keep: impl FnMut(&CommandPos) -> bool) -> Result<()> {
+        match self {
+            Index::Memory(index) => {
+                index.retain(keep);
+                Ok(())
+            }
+            Index::Disk(index) => index.retain(keep),
+        }
+    }
+
+    pub(crate) fn memory(&self) -> IndexMemory {
+        match self {
+            Index::Memory(index) => index.memory(),
+            Index::Disk(index) => IndexMemory {
+                entries: index.len() as usize,
+                key_bytes: 0,
+                table_bytes: index.cache_bytes(),
+                disk_bytes: index.disk_bytes(),
+            },
+        }
+    }
+}
+
+impl MemoryIndex {
+    pub(crate) fn new() -> MemoryIndex {
+        MemoryIndex {
+            table: HashTable::new(),
+            keys: Vec::new(),
+            garbage: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<CommandPos> {
+        let hash = self.hasher.hash_one(key.as_bytes());
+        self.table
+            .find(hash, |entry| key_at(&self.keys, entry.key_off) == key.as_bytes())
+            .map(|entry| entry.cmd_pos())
+    }
+
+    /// 写入键的位置，返回原来的位置
+    pub(crate) fn insert(&mut self, key: &str, cmd_pos: CommandPos) -> Result<Option<CommandPos>> {
+        let (gen, len) = packed_pos(cmd_pos)?;
+        let hash = self.hasher.hash_one(key.as_bytes());
+        let MemoryIndex { table, keys, hasher, .. } = self;
+        if let Some(entry) = table.find_mut(hash, |entry| key_at(keys, entry.key_off) == key.as_bytes()) {
+            let old = entry.cmd_pos();
+            *entry = Entry { key_off: entry.key_off, pos: cmd_pos.pos, gen, len };
+            return Ok(Some(old));
+        }
+
+        let key_off = keys.len() as u64;
+        push_key(keys, key.as_bytes());
+        let entry = Entry { key_off, pos: cmd_pos.pos, gen, len };
+        table.insert_unique(hash, entry, |entry| hasher.hash_one(key_at(keys, entry.key_off)));
+        Ok(None)
+    }
+
+    /// 删除键，返回它的位置
+    pub(crate) fn remove(&mut self, key: &str) -> Option<CommandPos> {
+        let hash = self.hasher.hash_one(key.as_bytes());
+        let keys = &self.keys;
+        let entry = self.table
+            .find_entry(hash, |entry| key_at(keys, entry.key_off) == key.as_bytes())
+            .ok()?;
+        let (entry, _) = entry.remove();
+        self.garbage += encoded_len(key.len());
+        if self.garbage > MIN_GARBAGE_BYTES && self.garbage > self.keys.len() / 2 {
+            self.shrink_keys();
+        }
+        Some(entry.cmd_pos())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.table.clear();
+        self.keys.clear();
+        self.garbage = 0;
+    }
+
+    /// 遍历所有的键与位置，顺序不定
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, CommandPos)> {
+        self.table.iter().map(|entry| (key_str(&self.keys, entry.key_off), entry.cmd_pos()))
+    }
+
+    /// 删除位置不满足 keep 的键
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&CommandPos) -> bool) {
+        let MemoryIndex { table, keys, garbage, .. } = self;
+        table.retain(|entry| {
+            let kept = keep(&entry.cmd_pos());
+            if !kept {
+                *garbage += encoded_len(key_at(keys, entry.key_off).len());
+            }
+            kept
+        });
+        if self.garbage > MIN_GARBAGE_BYTES && self.garbage > self.keys.len() / 2 {
+            self.shrink_keys();
+        }
+    }
+
+    /// 按键在存储区中的顺序分批遍历，cursor 为存储区中的偏移
+    /// 已删除的键仍然留在存储区中，只返回哈希表仍然指向的键
+    pub(crate) fn batch(&self, cursor: &mut u64, max: usize) -> Vec<(String, CommandPos)> {
+        let mut batch = Vec::new();
+        while batch.len() < max && (*cursor as usize) < self.keys.len() {
+            let key_off = *cursor;
+            let key = key_at(&self.keys, key_off);
+            *cursor += encoded_len(key.len()) as u64;
+            let hash = self.hasher.hash_one(key);
+            if let Some(entry) = self.table.find(hash, |entry| entry.key_off == key_off) {
+                batch.push((key_str(&self.keys, key_off).to_owned(), entry.cmd_pos()));
+            }
+        }
+        batch
+    }
+
+    pub(crate) fn memory(&self) -> IndexMemory {
+        IndexMemory {
+            entries: self.table.len(),
+            key_bytes: self.keys.capacity(),
+            table_bytes: self.table.allocation_size(),
+            disk_bytes: 0,
+        }
+    }
+
+    /// 把存活的键复制到新的存储区，回收已删除的键占用的空间
+    /// 键的哈希值不变，哈希表只需要更新偏移
+    fn shrink_keys(&mut self) {
+        let mut keys = Vec::with_capacity(self.keys.len() - self.garbage);
+        for entry in self.table.iter_mut() {
+            let key = key_at(&self.keys, entry.key_off);
+            entry.key_off = keys.len() as u64;
+            push_key(&mut keys, key);
+        }
+        self.keys = keys;
+        self.garbage = 0;
+    }
+}
+
+impl Entry {
+    fn cmd_pos(&self) -> CommandPos {
+        CommandPos {
+            gen: u64::from(self.gen),
+            pos: self.pos,
+            len: u64::from(self.len),
+        }
+    }
+}
+
+/// 索引以 u32 保存日志序号与记录长度，超出时返回错误而不是截断
+pub(crate) fn packed_pos(cmd_pos: CommandPos) -> Result<(u32, u32)> {
+    let gen = u32::try_from(cmd_pos.gen).map_err(|_| {
+        KvsError::UnsupportedFormat(format!("log generation {} exceeds the index limit of {}", cmd_pos.gen, u32::MAX))
+    })?;
+    let len = u32::try_from(cmd_pos.len).map_err(|_| KvsError::RecordTooLarge(cmd_pos.len, MAX_RECORD_LEN))?;
+    Ok((gen, len))
+}
+
+/// 以 LEB128 长度前缀追加键
+fn push_key(keys: &mut Vec<u8>, key: &[u8]) {
+    let mut len = key.len();
+    while len >= 0x80 {
+        keys.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    keys.push(len as u8);
+    keys.extend_from_slice(key);
+}
+
+fn key_at(keys: &[u8], key_off: u64) -> &[u8] {
+    let mut off = key_off as usize;
+    let mut len = 0;
+    let mut shift = 0;
+    loop {
+        let byte = keys[off];
+        off += 1;
+        len |= usize::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            break;
+        }
+        shift += 7;
+    }
+    &keys[off..off + len]
+}
+
+fn key_str(keys: &[u8], key_off: u64) -> &str {
+    // 存储区中只写入过 &str 的字节
+    std::str::from_utf8(key_at(keys, key_off)).expect("index keys are valid UTF-8")
+}
+
+/// 键在存储区中占用的字节数
+fn encoded_len(len: usize) -> usize {
+    let mut prefix = 1;
+    let mut rest = len >> 7;
+    while rest > 0 {
+        prefix += 1;
+        rest >>= 7;
+    }
+    prefix + len
+}
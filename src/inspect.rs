@@ -0,0 +1,134 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, Seek, SeekFrom},
+    path::Path,
+};
+
+use serde_json::Deserializer;
+
+use crate::{
+    codec,
+    error::Result,
+    format::{LogHeader, HEADER_LEN},
+    kv::{log_path, Command},
+    manifest::live_gens,
+};
+
+/// 记录的操作类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Set,
+    Remove,
+}
+
+/// 日志中一条记录的位置与摘要
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordInfo {
+    pub gen: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub op: Operation,
+    pub key: String,
+    /// Set 记录的值还原后的字节数，加密或存放在 blob 中的值无法还原时为 None
+    pub value_size: Option<u64>,
+    /// 值经过压缩、加密或存放在 blob 中时，记录中保存的字节数：
+    /// 压缩与加密的值为 base64 编码后的长度，blob 为 blob 文件的字节数
+    pub encoded_size: Option<u64>,
+}
+
+impl fmt::Display for RecordInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.op {
+            Operation::Set => "set",
+            Operation::Remove => "rm",
+        };
+        write!(
+            f,
+            "gen={} offset={} len={} op={} key={:?}",
+            self.gen, self.offset, self.len, op, self.key
+        )?;
+        if let Some(size) = self.value_size {
+            write!(f, " value_size={}", size)?;
+        }
+        if let Some(size) = self.encoded_size {
+            write!(f, " encoded_size={}", size)?;
+        }
+        Ok(())
+    }
+}
+
+/// 一个键的当前位置与历史记录
+#[derive(Debug, Clone, Default)]
+pub struct KeyInspection {
+    /// 索引中的当前位置，键不存在时为 None
+    pub current: Option<RecordInfo>,
+    /// 磁盘上仍保留的该键的所有记录，按写入顺序
+    pub history: Vec<RecordInfo>,
+}
+
+/// 按顺序访问日志中的每条记录
+/// only_gen 为 None 时访问所有日志；指定日志时从 from_offset 开始解析，该位置必须位于记录边界，
+/// 位于文件头之内时从第一条记录开始
+pub fn dump<F>(path: impl AsRef<Path>, only_gen: Option<u64>, from_offset: u64, mut visit: F) -> Result<()>
+where
+    F: FnMut(&RecordInfo) -> Result<()>,
+{
+    let path = path.as_ref();
+    let (gens, start) = match only_gen {
+        Some(gen) => (vec![gen], from_offset),
+        None => (live_gens(path)?, 0),
+    };
+    for gen in gens {
+        let mut file = File::open(log_path(path, gen))?;
+        LogHeader::read_from(&mut file, gen)?;
+        let start = start.max(HEADER_LEN);
+        file.seek(SeekFrom::Start(start))?;
+
+        let mut stream = Deserializer::from_reader(BufReader::new(file)).into_iter::<Command>();
+        let mut pos = start;
+        while let Some(cmd) = stream.next() {
+            let new_pos = start + stream.byte_offset() as u64;
+            let (op, key, value_size, encoded_size) = match cmd? {
+                Command::Set { key, value } => (Operation::Set, key, Some(value.len() as u64), None),
+                // 压缩的值解压后得到实际大小，没有编译对应的压缩算法时只有编码后的大小
+                Command::SetCompressed { key, value, codec } => {
+                    let value_size = codec::decode_value(&value, codec, codec::DEFAULT_MAX_VALUE_BYTES).ok().map(|decoded| decoded.len() as u64);
+                    (Operation::Set, key, value_size, Some(value.len() as u64))
+                }
+                // 没有密钥无法解密，只有编码后的大小
+                Command::SetEncrypted { key, value, .. } => (Operation::Set, key, None, Some(value.len() as u64)),
+                Command::SetBlob { key, len, .. } => (Operation::Set, key, None, Some(len)),
+                Command::Remove { key } => (Operation::Remove, key, None, None),
+            };
+            visit(&RecordInfo {
+                gen,
+                offset: pos,
+                len: new_pos - pos,
+                op,
+                key,
+                value_size,
+                encoded_size,
+            })?;
+            pos = new_pos;
+        }
+    }
+    Ok(())
+}
+
+/// 查看一个键的当前位置，以及磁盘上仍保留的所有历史记录
+/// 当前位置按 `KvStore::open` 重建索引的方式得出
+pub fn inspect_key(path: impl AsRef<Path>, key: &str) -> Result<KeyInspection> {
+    let mut inspection = KeyInspection::default();
+    dump(path, None, 0, |record| {
+        if record.key == key {
+            inspection.current = match record.op {
+                Operation::Set => Some(record.clone()),
+                Operation::Remove => None,
+            };
+            inspection.history.push(record.clone());
+        }
+        Ok(())
+    })?;
+    Ok(inspection)
+}
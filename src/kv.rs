@@ -1,77 +1,246 @@
-use std::{io::{Read, Seek, BufReader, Write, BufWriter, SeekFrom, self}, path::{PathBuf, Path}, collections::HashMap, fs::{File, self, OpenOptions}, ffi::OsStr};
+use std::{io::{Read, Seek, BufReader, Write, BufWriter, SeekFrom, self}, path::{PathBuf, Path}, collections::{BTreeMap, HashMap, HashSet}, fs::{File, self, OpenOptions}, ffi::OsStr, ops::RangeBounds};
 
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
-use crate::{error::{Result, KvsError}};
+use crate::{error::{Result, KvsError}, export::{DataFormat, ImportStats, RecordReader, RecordWriter}, format::{LogHeader, HEADER_LEN}, index::{Index, IndexMemory, IndexMode, MAX_RECORD_LEN}, diskindex::{DiskIndex, IndexSnapshot, INDEX_DIR}, blob::{self, BlobRef, BlobStats, BlobStore, BLOB_DIR}, cache::{CacheStats, ValueCache}, codec::{self, Codec}, crypto::{self, Keyring}, manifest::{Compaction, Manifest}, compaction::{CompactionPolicy, GarbageRatioPolicy}, ratelimit::{RateLimiter, ThrottledWriter}};
+
+/// KvStore 的可调参数
+pub struct StoreOptions {
+    /// 每次写入后按压缩策略自动压缩
+    /// 关闭后由调用者通过 `run_compaction`、`compact` 等方法在合适的时机压缩，
+    /// 或以 `CompactionScheduler` 定时压缩
+    pub auto_compaction: bool,
+    /// 决定何时压缩以及压缩哪些日志
+    pub compaction_policy: Box<dyn CompactionPolicy>,
+    /// 压缩、备份与导出共用的限速器，为 None 时不限速
+    /// 自动压缩同样限速，限速的压缩会阻塞同步触发它的写入；不希望写入等待时关闭自动压缩，
+    /// 由 `run_compaction` 或 `CompactionScheduler` 在写入路径之外压缩
+    pub io_limiter: Option<RateLimiter>,
+    /// 单个日志文件的最大字节数，写满后切换到新的日志
+    /// 单条记录超过该值时独占一个日志
+    pub max_segment_bytes: u64,
+    /// 不再写入的日志以内存映射读取，关闭后所有日志都通过缓冲读取
+    pub mmap_reads: bool,
+    /// 索引保存在内存中还是磁盘上
+    pub index_mode: IndexMode,
+    /// 值缓存的容量（键与值的字节数），为 0 时不缓存
+    pub value_cache_bytes: usize,
+    /// 写入时压缩值的算法，为 None 时不压缩
+    pub compression: Option<Codec>,
+    /// 加密写入的值的密钥，为 None 时不加密
+    /// 已加密的日志所用的密钥都需要提供，否则无法打开
+    /// 只加密值：键、删除记录与 blob 引用以明文写入，删除记录也不经过认证
+    pub encryption: Option<Keyring>,
+    /// 超过该字节数的值单独存入 blob 文件，日志中只记录引用，为 0 时不分离
+    pub blob_threshold: usize,
+    /// 开启自动压缩时，等待回收的 blob 超过该字节数后压缩引用它们的日志并删除这些 blob
+    pub blob_gc_bytes: u64,
+    /// 单个值的最大字节数，写入更大的值返回 `ValueTooLarge`
+    /// 读取压缩的值时解压的结果同样不能超过该值，避免损坏的记录耗尽内存
+    pub max_value_bytes: u64,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            auto_compaction: true,
+            compaction_policy: Box::new(GarbageRatioPolicy::default()),
+            io_limiter: None,
+            max_segment_bytes: 64 * 1024 * 1024,
+            mmap_reads: true,
+            index_mode: IndexMode::Memory,
+            value_cache_bytes: 0,
+            compression: None,
+            encryption: None,
+            blob_threshold: 0,
+            blob_gc_bytes: 64 * 1024 * 1024,
+            max_value_bytes: codec::DEFAULT_MAX_VALUE_BYTES,
+        }
+    }
+}
 
-const COMPACTION_THRESHOLD:u64 = 1024 * 1024;
+/// 分批遍历索引时每批的键数
+const INDEX_BATCH: usize = 1024;
 
 pub struct KvStore {
     path: PathBuf,
     readers: HashMap<u64, BufReaderWithPos<File>>,
+    // 不再写入的日志的内存映射，读取时直接从映射中解析命令
+    mmaps: HashMap<u64, Mmap>,
     writer: BufWriterWithPos<File>,
-    index: HashMap<String, CommandPos>,
+    index: Index,
+    // 已删除的键最近一次删除标记的位置
+    tombstones: Index,
     current_gen: u64,
-
-    // 压缩后可以保存的字节数。
-    uncompacted:u64,
+    manifest: Manifest,
+
+    // 每个日志的记录字节数与其中压缩后可以保存的字节数
+    gen_stats: HashMap<u64, GenStats>,
+    // 每个日志文件头中的密钥 id
+    key_ids: HashMap<u64, u32>,
+    // 单独存放的大值
+    blobs: BlobStore,
+    // 序号不小于它的日志中可能有还没有落盘的写入
+    synced_gen: u64,
+    // 最近读取的值
+    cache: ValueCache,
+    // 修改日志与索引的操作出过错，索引可能与日志不一致，关闭时不再保存磁盘索引
+    index_stale: bool,
+    options: StoreOptions,
 }
 
 impl KvStore {
     // 通过文件夹路径开启一个KvStore
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, StoreOptions::default())
+    }
+
+    /// 以指定的参数开启一个KvStore
+    pub fn open_with_options(path: impl Into<PathBuf>, options: StoreOptions) -> Result<KvStore> {
 
         let path = path.into();
 
+        if let Some(codec) = options.compression {
+            codec.ensure_available()?;
+        }
+
         fs::create_dir_all(&path)?;
 
         let mut readers = HashMap::<u64, BufReaderWithPos<File>>::new();
 
-        let mut index= HashMap::<String,CommandPos>::new();
+        // 上次正常关闭时保存的磁盘索引快照，无论是否使用，打开之后都不再有效
+        let index_dir = path.join(INDEX_DIR);
+        let snapshot = IndexSnapshot::take(&index_dir)?;
 
-        let gen_list = sorted_gen_list(&path)?;
-
-        let mut uncompacted = 0;
+        // 以清单为准加载日志，并清理中断的压缩留下的文件
+        let mut manifest = match Manifest::load(&path)? {
+            Some(mut manifest) => {
+                manifest.recover(&path)?;
+                manifest
+            }
+            // 没有清单的存储按目录中的日志文件建立清单
+            None => Manifest {
+                gens: sorted_gen_list(&path)?,
+                ..Manifest::default()
+            },
+        };
+        let gen_list = manifest.gens.clone();
+
+        // 获取当前最新的写入序名（之前的+1），跳过目录中不在清单里的日志文件
+        let current_gen = gen_list.last().copied().max(sorted_gen_list(&path)?.last().copied()).unwrap_or(0) + 1;
+
+        let mut blobs = BlobStore::new(path.join(BLOB_DIR), current_gen);
+        let mut gen_stats = HashMap::<u64, GenStats>::new();
+        let mut key_ids = HashMap::<u64, u32>::new();
+        let key_id = options.encryption.as_ref().map_or(0, Keyring::current_id);
+
+        // 日志与快照记录的一致时直接使用保存的磁盘索引，否则删除上次留下的索引文件并由日志重建
+        let reopened = match (&options.index_mode, snapshot) {
+            (&IndexMode::Disk { cache_entries }, Some(snapshot)) => {
+                reopen_index(&path, &index_dir, cache_entries, snapshot, &gen_list)?
+            }
+            _ => None,
+        };
+        let replay = reopened.is_none();
+        let (mut index, mut tombstones) = match reopened {
+            Some((index, tombstones, snapshot)) => {
+                gen_stats.extend(snapshot.gen_stats.into_iter().map(|stats| (stats.gen, stats)));
+                blobs.restore((snapshot.live_blobs, snapshot.dead_blobs));
+                (index, tombstones)
+            }
+            None => {
+                match fs::remove_dir_all(&index_dir) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                (
+                    Index::create(&options.index_mode, &index_dir, "live")?,
+                    Index::create(&options.index_mode, &index_dir, "tombstones")?,
+                )
+            }
+        };
 
-        // 对读入其Map进行初始化并计算对应的压缩阈值
+        // 对读入其Map进行初始化并统计各日志的无效数据
         for &gen in &gen_list {
             let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
+            let header = if replay {
+                load(gen, &mut reader, &mut index, &mut tombstones, &mut blobs, &mut gen_stats)?
+            } else {
+                LogHeader::read_from(&mut reader, gen)?
+            };
+            // 缺少密钥时无法读取该日志中的值
+            if header.key_id != 0 && !options.encryption.as_ref().is_some_and(|keyring| keyring.contains(header.key_id)) {
+                return Err(KvsError::Encryption(format!(
+                    "{}.log is encrypted with key {} which was not supplied",
+                    gen, header.key_id
+                )));
+            }
+            key_ids.insert(gen, header.key_id);
             readers.insert(gen, reader);
         }
-
-        // 获取当前最新的写入序名（之前的+1）
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-
-        // 以最新的写入序名创建新的日志文件
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
-        Ok(KvStore{
+        blobs.remove_orphans()?;
+
+        // 以最新的写入序名创建新的日志文件，并记录到清单中
+        let writer = new_log_file(&path, current_gen, key_id, &mut readers)?;
+        key_ids.insert(current_gen, key_id);
+        manifest.gens.push(current_gen);
+        manifest.store(&path)?;
+        let mut store = KvStore{
             path,
             readers,
+            mmaps: HashMap::new(),
             writer,
             index,
+            tombstones,
             current_gen,
-            uncompacted
-        })
+            manifest,
+            gen_stats,
+            key_ids,
+            blobs,
+            // 之前打开时的写入同样可能没有落盘
+            synced_gen: 0,
+            index_stale: false,
+            cache: ValueCache::new(options.value_cache_bytes),
+            options
+        };
+        // 已有的日志都不再写入
+        for gen in gen_list {
+            store.seal(gen)?;
+        }
+        Ok(store)
     }
 
     /// 存入数据
     pub fn set(&mut self,key:String,value:String) -> Result<()> {
+        self.set_with_codec(key, value, self.options.compression)
+    }
 
-        let cmd = Command::set(key, value);
+    /// 以指定的压缩算法存入数据，codec 为 None 时不压缩
+    pub fn set_with_codec(&mut self, key:String, value:String, codec:Option<Codec>) -> Result<()> {
+        self.tracked(|store| store.write_set(key, value, codec))
+    }
+
+    fn write_set(&mut self, key:String, value:String, codec:Option<Codec>) -> Result<()> {
+        let cmd = self.encode(key, value, codec)?;
+        let buf = serde_json::to_vec(&cmd)?;
+        check_record_len(buf.len())?;
+        // 当前日志写满时切换到新的日志
+        self.rotate_if_full(buf.len() as u64)?;
 
         // 获取写入器当前地址
         let pos = self.writer.pos;
 
         // 以json形式写入该命令
-        serde_json::to_writer(&mut self.writer, &cmd)?;
+        self.writer.write_all(&buf)?;
 
         // 刷入文件中
         self.writer.flush()?;
 
+        let blob = cmd.blob_ref();
         // 当模式匹配cmd为正确时
-        if let Command::Set {key,..} = cmd{
+        if let Command::Set {key,..} | Command::SetCompressed {key,..} | Command::SetEncrypted {key,..} | Command::SetBlob {key,..} = cmd{
             // 封装为CommandPos
             let cmd_pos = CommandPos{
                 gen:self.current_gen,
@@ -79,20 +248,23 @@ impl KvStore {
                 len:self.writer.pos - pos
             };
 
-            // 将封装ComandPos存入索引Map中
-            if let Some(old_cmd) = self.index.insert(key,cmd_pos) {
-                // 将阈值提升至该命令的大小
-                self.uncompacted += old_cmd.len;
+            self.stats_mut(cmd_pos.gen).bytes += cmd_pos.len;
+            self.cache.invalidate(&key);
+            // 重新写入的键不再需要删除标记
+            if let Some(tombstone) = self.tombstones.remove(&key)? {
+                self.stats_mut(tombstone.gen).dead_bytes += tombstone.len;
             }
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                // 当阈值达到时
+            // 将封装ComandPos存入索引Map中
+            let old_cmd = self.index.insert(&key,cmd_pos)?;
+            self.blobs.replace(&key, blob, old_cmd);
+            if let Some(old_cmd) = old_cmd {
+                // 旧值所在日志的无效数据增加该命令的大小
+                self.stats_mut(old_cmd.gen).dead_bytes += old_cmd.len;
             }
         }
 
-        // 阈值过高进行压缩
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?
-        }
+        // 按压缩策略进行压缩
+        self.maybe_compact()?;
 
         // 获取写入器当前地址
         Ok(())
@@ -101,25 +273,21 @@ impl KvStore {
     /// 获取数据
     pub fn get(&mut self, key:String) -> Result<Option<String>> {
 
-        // 若index中获取到了该数据命令
-        if let Some(cmd_pos) = self.index.get(&key) {
-            // 从读取器Map中通过该命令的序号获取对应的日志读取器
-            let reader = self.readers.get_mut(&cmd_pos.gen)
-                .expect(format!("Can't find reader: {}", &cmd_pos.gen).as_str());
-
-            // 将读取器的指针切换到命令的位置中
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            // 获取这段内容
-            let cmd_reader = reader.take(cmd_pos.len);
+        // 命中缓存时不需要查找索引与读取日志
+        if self.cache.is_enabled() {
+            if let Some(value) = self.cache.get(&key) {
+                return Ok(Some(value));
+            }
+        }
 
-            // 将命令进行转换
-            if let Command::Set {value,  ..} = serde_json::from_reader(cmd_reader)? {
-                //返回匹配成功的数据
-                Ok(Some(value))
-            } else {
-                //返回错误（错误的指令类型）
-                Err(KvsError::UnexpectedCommandType)
+        // 若index中获取到了该数据命令
+        if let Some(cmd_pos) = self.index.get(&key)? {
+            let value = self.read_value(cmd_pos)?;
+            if self.cache.is_enabled() {
+                self.cache.insert(key, value.clone());
             }
+            //返回匹配成功的数据
+            Ok(Some(value))
         } else {
             Ok(None)
         }
@@ -129,41 +297,306 @@ impl KvStore {
     /// 删除数据
     pub fn remove(&mut self, key:String) -> Result<()> {
         // 若index中存在这个key
-        if self.index.contains_key(&key) {
-            // 对这个key做命令封装
-            let cmd = Command::remove(key);
-            // 将这条命令以json形式写入至当前日志文件
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            // 刷入文件中
-            self.writer.flush()?;
-            // 若cmd模式匹配成功则删除该数据
-            if let Command::Remove {key} = cmd{
-                self.index.remove(&key).expect("key not found");
-            }
-            Ok(())
+        if self.index.contains_key(&key)? {
+            self.tracked(|store| store.write_remove(key))
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
-    pub fn compact(&mut self) -> Result<()> {
+    fn write_remove(&mut self, key:String) -> Result<()> {
+        // 对这个key做命令封装
+        let cmd = Command::remove(key);
+        let buf = serde_json::to_vec(&cmd)?;
+        // 当前日志写满时切换到新的日志
+        self.rotate_if_full(buf.len() as u64)?;
+        // 获取写入器当前地址
+        let pos = self.writer.pos;
+        // 将这条命令以json形式写入至当前日志文件
+        self.writer.write_all(&buf)?;
+        // 刷入文件中
+        self.writer.flush()?;
+        // 若cmd模式匹配成功则删除该数据，并记录删除标记
+        if let Command::Remove {key} = cmd{
+            let old_cmd = self.index.remove(&key)?.expect("key not found");
+            self.blobs.replace(&key, None, Some(old_cmd));
+            self.cache.invalidate(&key);
+            self.stats_mut(old_cmd.gen).dead_bytes += old_cmd.len;
+            let tombstone = CommandPos{ gen:self.current_gen, pos, len:self.writer.pos - pos };
+            self.stats_mut(tombstone.gen).bytes += tombstone.len;
+            self.tombstones.insert(&key, tombstone)?;
+        }
+        Ok(())
+    }
 
-        // 预压缩的数据位置为原文件位置的向上一位
-        let compaction_gen = self.current_gen + 1;
-        // 新的写入位置为原位置的向上两位
-        self.current_gen += 2;
-        // 写入器的位置重定向为新的写入位置
+    /// 按键的顺序返回范围内的所有数据
+    /// 只收集范围内的键的位置，排序之后逐条读取
+    pub fn scan<R: RangeBounds<String>>(&mut self, range: R) -> Result<Vec<(String, String)>> {
+        let mut found = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let batch = self.index.batch(&mut cursor, INDEX_BATCH)?;
+            if batch.is_empty() {
+                break;
+            }
+            found.extend(batch.into_iter().filter(|(key, _)| range.contains(key)));
+        }
+        found.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let mut pairs = Vec::with_capacity(found.len());
+        for (key, cmd_pos) in found {
+            let value = self.read_value(cmd_pos)?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+
+    /// 导出所有数据，返回导出的条数
+    /// 按索引的顺序分批读取，顺序不定；值直接从日志读取并逐条写出，不经过值缓存
+    pub fn export<W: Write>(&mut self, writer: W, format: DataFormat) -> Result<u64> {
+        let writer = ThrottledWriter::new(writer, self.options.io_limiter.clone());
+        let mut writer = RecordWriter::new(writer, format)?;
+        let mut count = 0;
+        let mut cursor = 0;
+        loop {
+            let batch = self.index.batch(&mut cursor, INDEX_BATCH)?;
+            if batch.is_empty() {
+                break;
+            }
+            for (key, cmd_pos) in batch {
+                let value = self.read_value(cmd_pos)?;
+                writer.write(key, value)?;
+                count += 1;
+            }
+        }
+        writer.finish()?;
+        Ok(count)
+    }
+
+    /// 导入数据，返回新增与覆盖的键数
+    /// atomic 为 true 时先把所有数据写入单独的日志，全部读取成功后才一次性生效，
+    /// 否则逐条写入，出错时之前的数据已经生效
+    pub fn import<R: Read>(&mut self, reader: R, format: DataFormat, atomic: bool) -> Result<ImportStats> {
+        self.tracked(|store| store.import_records(reader, format, atomic))
+    }
+
+    fn import_records<R: Read>(&mut self, reader: R, format: DataFormat, atomic: bool) -> Result<ImportStats> {
+        let records = RecordReader::new(reader, format);
+        let mut stats = ImportStats::default();
+
+        if !atomic {
+            for record in records {
+                let (key, value) = record?;
+                if self.index.contains_key(&key)? {
+                    stats.overwritten += 1;
+                } else {
+                    stats.inserted += 1;
+                }
+                self.set(key, value)?;
+            }
+            return Ok(stats);
+        }
+
+        // 导入的日志位于当前日志之后，每个日志不超过分段大小，新的写入位置在它们之后
+        let key_id = self.key_id();
+        let mut import_gens = vec![self.current_gen + 1];
+        let mut count = 0;
+        let written = (|| -> Result<()> {
+            let mut writer = compaction_file(&self.path, self.current_gen + 1, key_id)?;
+            let mut pos = HEADER_LEN;
+            for record in records {
+                let (key, value) = record?;
+                let cmd = self.encode(key, value, self.options.compression)?;
+                let buf = serde_json::to_vec(&cmd)?;
+                check_record_len(buf.len())?;
+                if pos > HEADER_LEN && pos + buf.len() as u64 > self.options.max_segment_bytes {
+                    finish_import_file(writer)?;
+                    let gen = import_gens[import_gens.len() - 1] + 1;
+                    import_gens.push(gen);
+                    writer = compaction_file(&self.path, gen, key_id)?;
+                    pos = HEADER_LEN;
+                }
+                writer.write_all(&buf)?;
+                pos += buf.len() as u64;
+                count += 1;
+            }
+            finish_import_file(writer)
+        })();
+        if let Err(e) = written {
+            for &gen in &import_gens {
+                let _ = fs::remove_file(self.path.join(format!("{}.log.tmp", gen)));
+            }
+            return Err(e);
+        }
+
+        // 重命名并写入清单之后导入才生效
+        for &gen in &import_gens {
+            fs::rename(self.path.join(format!("{}.log.tmp", gen)), log_path(&self.path, gen))?;
+            self.key_ids.insert(gen, key_id);
+        }
+        let sealed_gen = self.current_gen;
+        self.current_gen = import_gens[import_gens.len() - 1] + 1;
         self.writer = self.new_log_file(self.current_gen)?;
+        self.manifest.gens.extend(&import_gens);
+        self.manifest.gens.push(self.current_gen);
+        self.manifest.store(&self.path)?;
+        self.seal(sealed_gen)?;
+
+        // 导入之前没有的键为新增，其余记录都覆盖了之前的值
+        let before = self.index.len();
+        for gen in import_gens {
+            let mut reader = BufReaderWithPos::new(File::open(log_path(&self.path, gen))?)?;
+            load(gen, &mut reader, &mut self.index, &mut self.tombstones, &mut self.blobs, &mut self.gen_stats)?;
+            self.readers.insert(gen, reader);
+            self.seal(gen)?;
+        }
+        stats.inserted = self.index.len() - before;
+        stats.overwritten = count - stats.inserted;
+        self.cache.clear();
+
+        self.maybe_compact()?;
+        Ok(stats)
+    }
+
+    /// 遍历当前所有的键，顺序不定
+    pub(crate) fn for_each_key(&mut self, mut f: impl FnMut(&str)) -> Result<()> {
+        self.index.for_each(|key, _| f(key))
+    }
+
+    /// 当前的键数
+    pub(crate) fn key_count(&self) -> u64 {
+        self.index.len()
+    }
+
+    /// 每个日志的记录字节数与无效字节数，按序号排列
+    pub fn gen_stats(&self) -> Vec<GenStats> {
+        let mut stats: Vec<GenStats> = self.readers.keys()
+            .map(|&gen| self.gen_stats.get(&gen).cloned().unwrap_or(GenStats{ gen, bytes:0, dead_bytes:0 }))
+            .collect();
+        stats.sort_unstable_by_key(|stats| stats.gen);
+        stats
+    }
+
+    /// 选出无效数据比例最高的日志进行压缩，返回参与压缩的日志
+    /// 参与压缩的日志总大小不超过 max_bytes，但至少压缩一个有无效数据的日志
+    pub fn compact_incremental(&mut self, max_bytes: u64) -> Result<Vec<u64>> {
+        let mut policy = GarbageRatioPolicy {
+            min_ratio: 0.0,
+            min_dead_bytes: 0,
+            max_bytes,
+        };
+        let selected = policy.select(&self.gen_stats());
+        self.compact_selected(selected)
+    }
+
+    /// 按存储的压缩策略压缩一次，返回参与压缩的日志
+    /// 关闭自动压缩时可以由调用者手动调用，或交给 `CompactionScheduler` 定时调用
+    pub fn run_compaction(&mut self) -> Result<Vec<u64>> {
+        let stats = self.gen_stats();
+        let selected = self.options.compaction_policy.select(&stats);
+        self.compact_selected(selected)
+    }
+
+    fn compact_selected(&mut self, selected: Vec<u64>) -> Result<Vec<u64>> {
+        if !selected.is_empty() {
+            self.compact_gens(&selected)?;
+        }
+        Ok(selected)
+    }
+
+    /// 压缩所有日志
+    pub fn compact(&mut self) -> Result<()> {
+        // 当前写入的日志在压缩开始时转为只读，同样参与压缩
+        let gens: Vec<u64> = self.readers.keys().cloned().collect();
+        self.compact_gens(&gens)
+    }
+
+    /// 只压缩指定的日志，其余日志保持不变
+    ///
+    /// 指定日志中仍然有效的数据与删除标记写入新的日志，每个日志不超过分段大小。
+    /// 删除标记只有在不存在更早的、未参与压缩的日志时才会丢弃，否则更早日志中的旧值
+    /// 会在重新打开时复活。压缩之后写入切换到新的日志。
+    pub fn compact_gens(&mut self, gens: &[u64]) -> Result<()> {
+        self.tracked(|store| store.rewrite_gens(gens))
+    }
+
+    fn rewrite_gens(&mut self, gens: &[u64]) -> Result<()> {
+
+        // 压缩结果从当前写入位置的向上一位开始
+        let mut compaction_gen = self.current_gen + 1;
+        // 在清单中记录进行中的压缩
+        self.manifest.compaction = Some(Compaction { outputs: vec![compaction_gen] });
+        self.manifest.store(&self.path)?;
+        crash_point("compaction-started");
+
+        // 参与压缩的日志
+        let compacting: HashSet<u64> = gens.iter()
+            .filter(|&&gen| self.readers.contains_key(&gen))
+            .cloned().collect();
+        // 未参与压缩的最早日志，比它更早的删除标记可以丢弃
+        let oldest_kept = self.manifest.gens.iter()
+            .filter(|&&gen| !compacting.contains(&gen))
+            .min().cloned();
+
+        // 初始化新的写入地址，位于文件头之后
+        let mut new_pos:u64 = HEADER_LEN;
+        // 压缩结果以当前的密钥加密
+        let key_id = self.key_id();
+        // 压缩结果先写入临时文件
+        let mut compaction_writer = compaction_file(&self.path, compaction_gen, key_id)?;
+        // 压缩提交之前索引保持不变，提交之后按压缩结果中的记录更新
+        let mut output_stats = Vec::new();
+
+        // 逐批遍历索引与删除标记，只收集参与压缩的记录的位置
+        // 按最初写入的日志排序之后写入，压缩结果中的记录保持写入的先后，按位置恢复备份时可以按来源截断
+        let mut records = Vec::new();
+        for is_tombstone in [false, true] {
+            let mut cursor = 0;
+            loop {
+                let source = if is_tombstone { &mut self.tombstones } else { &mut self.index };
+                let batch = source.batch(&mut cursor, INDEX_BATCH)?;
+                if batch.is_empty() {
+                    break;
+                }
+                for (_, cmd_pos) in batch {
+                    if !compacting.contains(&cmd_pos.gen) {
+                        continue;
+                    }
+                    // 丢弃的删除标记在提交之后从索引中删除
+                    if is_tombstone && oldest_kept.is_none_or(|oldest| oldest > cmd_pos.gen) {
+                        continue;
+                    }
+                    records.push((self.manifest.origin_at(cmd_pos.gen, cmd_pos.pos), cmd_pos, is_tombstone));
+                }
+            }
+        }
+        records.sort_unstable_by_key(|&(origin, cmd_pos, _)| (origin, cmd_pos.gen, cmd_pos.pos));
+        // 压缩结果中每段记录最初写入的日志
+        let mut origins: BTreeMap<u64, Vec<(u64, u64)>> = BTreeMap::new();
+
+        for (origin, cmd_pos, is_tombstone) in records {
+            // 当前压缩文件写满时切换到下一个
+            if new_pos > HEADER_LEN && new_pos + cmd_pos.len > self.options.max_segment_bytes {
+                let reader = finish_compaction_file(&self.path, compaction_gen, compaction_writer)?;
+                self.readers.insert(compaction_gen, reader);
+                output_stats.push(GenStats{ gen:compaction_gen, bytes:new_pos - HEADER_LEN, dead_bytes:0 });
+
+                compaction_gen += 1;
+                if let Some(compaction) = &mut self.manifest.compaction {
+                    compaction.outputs.push(compaction_gen);
+                }
+                self.manifest.store(&self.path)?;
+                compaction_writer = compaction_file(&self.path, compaction_gen, key_id)?;
+                new_pos = HEADER_LEN;
+            }
+            let segments = origins.entry(compaction_gen).or_default();
+            if segments.last().is_none_or(|&(_, last)| last != origin) {
+                segments.push((new_pos, origin));
+            }
 
-        // 初始化新的写入地址
-        let mut new_pos:u64 = 0;
-        // 开启压缩文件的写入器
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
-        // 遍历内存中保存的所有数据
-        for cmd_pos in &mut self.index.values_mut() {
             // 通过该单条命令获取对应的文件读取器
             let reader = self.readers.get_mut(&cmd_pos.gen)
-                .expect(format!("Can't find reader: {}",&cmd_pos.gen).as_str());
+                .unwrap_or_else(|| panic!("Can't find reader: {}", &cmd_pos.gen));
 
             // 如果当前读取器的地址与指令地址不一致
             if reader.pos != cmd_pos.pos {
@@ -171,50 +604,501 @@ impl KvStore {
                 reader.seek(SeekFrom::Start(cmd_pos.pos))?;
             }
 
+            // 后台压缩按限速取得令牌
+            if let Some(limiter) = &self.options.io_limiter {
+                limiter.acquire(cmd_pos.len);
+            }
             // 获取该命令
             let mut cmd_reader = reader.take(cmd_pos.len);
-            // 将该命令拷贝到压缩文件中
-            let len = io::copy(&mut cmd_reader, &mut compaction_writer)?;
-            // 该命令解引用并更新为压缩文件中写入的命令
-            *cmd_pos = CommandPos{
-                gen:compaction_gen,
-                pos:new_pos,
-                len
+            let len = if is_tombstone || self.key_ids.get(&cmd_pos.gen).copied().unwrap_or(0) == key_id {
+                // 将该命令拷贝到压缩文件中
+                io::copy(&mut cmd_reader, &mut compaction_writer)?
+            } else {
+                // 以其他密钥加密或没有加密的值，解密后以当前的密钥重新写入
+                let cmd = serde_json::from_reader(cmd_reader)?;
+                let (key, value) = self.decode(cmd, cmd_pos.gen)?.ok_or(KvsError::UnexpectedCommandType)?;
+                // 重新写入的大值存入新的 blob，旧的 blob 随旧日志一起回收
+                let cmd = self.encode(key, value, self.options.compression)?;
+                let buf = serde_json::to_vec(&cmd)?;
+                // 重新加密与编码之后的记录可能变长
+                check_record_len(buf.len())?;
+                compaction_writer.write_all(&buf)?;
+                buf.len() as u64
             };
             // 写入地址累加
             new_pos += len;
         }
-        // 将所有写入刷入压缩文件中
-        compaction_writer.flush()?;
+        let reader = finish_compaction_file(&self.path, compaction_gen, compaction_writer)?;
+        self.readers.insert(compaction_gen, reader);
+        output_stats.push(GenStats{ gen:compaction_gen, bytes:new_pos - HEADER_LEN, dead_bytes:0 });
+        // 压缩结果写完之后不再修改
+        for stats in &output_stats {
+            self.key_ids.insert(stats.gen, key_id);
+            self.seal(stats.gen)?;
+        }
 
-        // 遍历过滤出小于压缩文件序号的文件号名收集为过期Vec
-        let stale_gens: Vec<_> = self.readers.keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned().collect();
+        // 写入切换到压缩结果之后的新日志，切换之前先记录为存活的日志：
+        // 之后的步骤失败时压缩结果在重新打开时丢弃，切换之后确认的写入仍然保留
+        let writer_gen = compaction_gen + 1;
+        let outputs = self.manifest.compaction.as_ref().map_or_else(Vec::new, |compaction| compaction.outputs.clone());
+        self.manifest.gens.push(writer_gen);
+        self.manifest.store(&self.path)?;
+        self.writer = self.new_log_file(writer_gen)?;
+        // 压缩之前写入的日志不再写入，没有参与压缩时同样以内存映射读取
+        let sealed_gen = self.current_gen;
+        self.current_gen = writer_gen;
+        fail_point("compaction-writer-switched")?;
+        if !compacting.contains(&sealed_gen) {
+            self.seal(sealed_gen)?;
+        }
+
+        // 参与压缩的日志收集为过期Vec
+        let mut stale_gens: Vec<_> = compacting.iter().cloned().collect();
+        stale_gens.sort_unstable();
+
+        // 写入清单提交压缩，旧日志之后才删除
+        self.manifest.gens.retain(|gen| !compacting.contains(gen));
+        self.manifest.gens.extend(outputs);
+        self.manifest.gens.sort_unstable();
+        self.manifest.compaction = None;
+        self.manifest.obsolete = stale_gens.clone();
+        self.manifest.origins.retain(|gen, _| !compacting.contains(gen));
+        self.manifest.origins.extend(origins);
+        self.manifest.store(&self.path)?;
+        crash_point("compaction-committed");
+
+        // 重新读取压缩结果，索引与删除标记切换到其中的位置
+        // 压缩只移动记录，缓存的值仍然有效
+        for stats in &output_stats {
+            self.apply_compacted(stats.gen)?;
+        }
+        // 剩下的仍指向旧日志的删除标记都已丢弃
+        self.tombstones.retain(|cmd_pos| !compacting.contains(&cmd_pos.gen))?;
 
         // 遍历过期Vec对数据进行旧文件删除
         for stale_gen in stale_gens {
             self.readers.remove(&stale_gen);
+            self.mmaps.remove(&stale_gen);
+            self.key_ids.remove(&stale_gen);
             fs::remove_file(log_path(&self.path, stale_gen))?;
+            crash_point("compaction-stale-removed");
+        }
+        self.manifest.obsolete.clear();
+        self.manifest.store(&self.path)?;
+        // 压缩结果中只有有效数据与保留的删除标记
+        for gen in &compacting {
+            self.gen_stats.remove(gen);
         }
-        // 将压缩阈值调整为0
-        self.uncompacted = 0;
+        for stats in output_stats {
+            self.gen_stats.insert(stats.gen, stats);
+        }
+        // 引用它们的记录已随旧日志删除的 blob 可以回收
+        let readers = &self.readers;
+        self.blobs.sweep(|gen| readers.contains_key(&gen))?;
 
         Ok(())
     }
 
+    /// 在 dest 目录生成一份一致的、可以直接打开的副本
+    /// 不再写入的日志以硬链接共享（跨文件系统时退化为复制），
+    /// 当前写入的日志只复制到已写入的位置，返回时副本已经落盘
+    ///
+    /// 以硬链接共享的日志在两个存储中都不会再被写入：打开存储时总是新建日志写入，
+    /// 压缩只会删除旧的日志，因此两边的内存映射看到的内容都保持不变。
+    pub fn checkpoint(&mut self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)?;
+        if !sorted_gen_list(dest)?.is_empty() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already contains log files", dest.display()),
+            )));
+        }
+
+        let extents = self.log_extents()?;
+        for &(gen, len) in &extents {
+            let src = log_path(&self.path, gen);
+            let dst = log_path(dest, gen);
+            if gen == self.current_gen {
+                let mut reader = File::open(&src)?.take(len);
+                let mut writer = File::create(&dst)?;
+                io::copy(&mut reader, &mut writer)?;
+            } else {
+                link_or_copy(&src, &dst)?;
+            }
+            // 硬链接共享的日志之前只写入了操作系统，同样需要落盘
+            File::open(&dst)?.sync_all()?;
+        }
+        // blob 写入之后不再修改，同样以硬链接共享
+        let blob_dir = dest.join(BLOB_DIR);
+        let blob_ids = blob::blob_ids(self.blobs.dir())?;
+        if !blob_ids.is_empty() {
+            fs::create_dir_all(&blob_dir)?;
+            for id in blob_ids {
+                let dst = blob::blob_path(&blob_dir, id);
+                link_or_copy(&blob::blob_path(self.blobs.dir(), id), &dst)?;
+                File::open(&dst)?.sync_all()?;
+            }
+            sync_dir(&blob_dir)?;
+        }
+        // 清单写入时目录随之落盘，之后副本所在的目录项也需要落盘
+        let manifest = Manifest {
+            gens: extents.iter().map(|&(gen, _)| gen).collect(),
+            origins: self.log_origins(),
+            ..Manifest::default()
+        };
+        manifest.store(dest)?;
+        if let Some(parent) = dest.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            sync_dir(parent)?;
+        }
+        Ok(())
+    }
+
+    /// 索引与删除标记占用的内存
+    /// 磁盘索引只统计缓存占用的内存，索引文件的大小记录在 disk_bytes 中
+    pub fn index_memory(&self) -> IndexMemory {
+        let index = self.index.memory();
+        let tombstones = self.tombstones.memory();
+        IndexMemory {
+            entries: index.entries + tombstones.entries,
+            key_bytes: index.key_bytes + tombstones.key_bytes,
+            table_bytes: index.table_bytes + tombstones.table_bytes,
+            disk_bytes: index.disk_bytes + tombstones.disk_bytes,
+        }
+    }
+
+    /// 值缓存的命中统计
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// 仍在使用与等待回收的 blob
+    pub fn blob_stats(&self) -> BlobStats {
+        self.blobs.stats()
+    }
+
+    /// 回收被覆盖或删除的 blob，返回释放的字节数
+    ///
+    /// blob 在引用它的记录所在的日志被压缩之后才能删除，
+    /// 因此先压缩这些日志，压缩只需要移动其中的小记录。
+    pub fn collect_blobs(&mut self) -> Result<u64> {
+        let dead_bytes = self.blobs.stats().dead_bytes;
+        let gens: Vec<u64> = self.blobs.dead_gens().into_iter()
+            .filter(|gen| self.readers.contains_key(gen))
+            .collect();
+        if !gens.is_empty() {
+            self.compact_gens(&gens)?;
+        }
+        let readers = &self.readers;
+        self.blobs.sweep(|gen| readers.contains_key(&gen))?;
+        Ok(dead_bytes.saturating_sub(self.blobs.stats().dead_bytes))
+    }
+
+    /// 单个值的最大字节数
+    pub(crate) fn max_value_bytes(&self) -> u64 {
+        self.options.max_value_bytes
+    }
+
+    /// 压缩、备份与导出共用的限速器
+    pub fn io_limiter(&self) -> Option<&RateLimiter> {
+        self.options.io_limiter.as_ref()
+    }
+
+    /// 存储目录
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// blob 文件所在的目录
+    pub(crate) fn blob_dir(&self) -> &Path {
+        self.blobs.dir()
+    }
+
+    /// 按序号返回所有日志及其已写入的长度
+    /// 当前日志的长度为写入器的位置，只包含完整的命令
+    pub(crate) fn log_extents(&mut self) -> Result<Vec<(u64, u64)>> {
+        self.writer.flush()?;
+        let mut gens: Vec<u64> = self.readers.keys().cloned().collect();
+        gens.sort_unstable();
+
+        let mut extents = Vec::with_capacity(gens.len());
+        for gen in gens {
+            let len = if gen == self.current_gen {
+                self.writer.pos
+            } else {
+                fs::metadata(log_path(&self.path, gen))?.len()
+            };
+            extents.push((gen, len));
+        }
+        Ok(extents)
+    }
+
+    /// 压缩结果中的记录最初写入的日志，见 [`Manifest::origins`]
+    pub(crate) fn log_origins(&self) -> BTreeMap<u64, Vec<(u64, u64)>> {
+        self.manifest.origins.iter()
+            .filter(|(gen, _)| self.readers.contains_key(gen))
+            .map(|(&gen, segments)| (gen, segments.clone()))
+            .collect()
+    }
+
+    /// 上次调用之后写入过的 blob 与日志文件，由调用者在存储的锁之外落盘
+    /// 返回时之前的写入都已经交给操作系统，blob 排在引用它们的日志之前
+    pub(crate) fn unsynced_files(&mut self) -> Result<Vec<File>> {
+        self.writer.flush()?;
+        let mut files = self.blobs.take_unsynced()?;
+        let mut gens: Vec<u64> = self.readers.keys().copied().filter(|&gen| gen >= self.synced_gen).collect();
+        gens.sort_unstable();
+        for gen in gens {
+            files.push(File::open(log_path(&self.path, gen))?);
+        }
+        self.synced_gen = self.current_gen;
+        Ok(files)
+    }
+
+    /// 将之前的写入落盘
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        for file in self.unsynced_files()? {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// 清空所有数据
+    /// 删除全部日志文件，并从新的日志文件重新开始写入
+    /// 新日志的序号继续递增，不会与删除的日志重复
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        self.tracked(KvStore::clear_logs)
+    }
+
+    fn clear_logs(&mut self) -> Result<()> {
+        self.readers.clear();
+        self.mmaps.clear();
+        for gen in sorted_gen_list(&self.path)? {
+            fs::remove_file(log_path(&self.path, gen))?;
+        }
+        self.index.clear()?;
+        self.tombstones.clear()?;
+        self.cache.clear();
+        self.gen_stats.clear();
+        self.key_ids.clear();
+        self.blobs.clear()?;
+        self.synced_gen = 0;
+        self.current_gen += 1;
+        self.writer = self.new_log_file(self.current_gen)?;
+        self.manifest = Manifest {
+            gens: vec![self.current_gen],
+            ..Manifest::default()
+        };
+        self.manifest.store(&self.path)?;
+        Ok(())
+    }
+
+    /// 写入 len 字节会超过分段大小时切换到新的日志
+    /// 空的日志总是可以写入，避免超大的记录无法写入
+    fn rotate_if_full(&mut self, len: u64) -> Result<()> {
+        if self.writer.pos > HEADER_LEN && self.writer.pos + len > self.options.max_segment_bytes {
+            self.current_gen += 1;
+            self.writer = self.new_log_file(self.current_gen)?;
+            self.manifest.gens.push(self.current_gen);
+            self.manifest.store(&self.path)?;
+            self.seal(self.current_gen - 1)?;
+        }
+        Ok(())
+    }
+
+    /// 日志 gen 不再写入，开启内存映射读取时映射该日志
+    fn seal(&mut self, gen: u64) -> Result<()> {
+        if self.options.mmap_reads {
+            let file = File::open(log_path(&self.path, gen))?;
+            // SAFETY: 不再写入的日志只会被整体删除或替换，不会被原地修改或截断，
+            // 映射的内容在映射存在期间保持不变。检查点以硬链接共享的日志在副本中
+            // 同样是不再写入的日志，副本打开时总是新建日志写入
+            let mmap = unsafe { Mmap::map(&file)? };
+            self.mmaps.insert(gen, mmap);
+        }
+        Ok(())
+    }
+
+    /// 开启自动压缩时按策略压缩
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.options.auto_compaction {
+            self.run_compaction()?;
+            if self.blobs.stats().dead_bytes >= self.options.blob_gc_bytes {
+                self.collect_blobs()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 按压缩结果日志 gen 中的记录更新索引与删除标记
+    /// 重新写入的大值引用新的 blob，旧的 blob 等待旧日志删除后回收
+    fn apply_compacted(&mut self, gen: u64) -> Result<()> {
+        let mut reader = BufReader::new(File::open(log_path(&self.path, gen))?);
+        reader.seek(SeekFrom::Start(HEADER_LEN))?;
+        let mut pos = HEADER_LEN;
+        let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+        while let Some(cmd) = stream.next() {
+            let new_pos = HEADER_LEN + stream.byte_offset() as u64;
+            let cmd_pos = CommandPos{ gen, pos, len:new_pos - pos };
+            let cmd = cmd?;
+            let blob = cmd.blob_ref();
+            match cmd {
+                Command::Set {key, ..} | Command::SetCompressed {key, ..} | Command::SetEncrypted {key, ..} | Command::SetBlob {key, ..} => {
+                    let old_cmd = self.index.insert(&key, cmd_pos)?;
+                    self.blobs.replace(&key, blob, old_cmd);
+                }
+                Command::Remove {key} => {
+                    self.tombstones.insert(&key, cmd_pos)?;
+                }
+            }
+            pos = new_pos;
+        }
+        Ok(())
+    }
+
+    /// 关闭时保存磁盘索引，日志落盘之后写入记录各日志长度的快照
+    fn save_index(&mut self) -> Result<()> {
+        if self.index_stale || self.manifest.compaction.is_some() {
+            return Ok(());
+        }
+        if let (Index::Disk(_), Index::Disk(_)) = (&self.index, &self.tombstones) {
+            self.sync()?;
+            let logs = self.log_extents()?;
+            let (Index::Disk(live), Index::Disk(tombstones)) = (&self.index, &self.tombstones) else {
+                return Ok(());
+            };
+            let mut gen_stats: Vec<GenStats> = self.gen_stats.values().cloned().collect();
+            gen_stats.sort_unstable_by_key(|stats| stats.gen);
+            let (live_blobs, dead_blobs) = self.blobs.snapshot();
+            let snapshot = IndexSnapshot {
+                logs,
+                live: live.persist()?,
+                tombstones: tombstones.persist()?,
+                gen_stats,
+                live_blobs,
+                dead_blobs,
+            };
+            snapshot.store(&self.path.join(INDEX_DIR))?;
+        }
+        Ok(())
+    }
+
+    /// 执行同时修改日志与索引的操作，出错之后关闭时不再保存磁盘索引
+    fn tracked<T>(&mut self, op: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let result = op(self);
+        if result.is_err() {
+            self.index_stale = true;
+        }
+        result
+    }
+
+    /// 读取并还原 cmd_pos 处写入命令的值，不经过值缓存
+    fn read_value(&mut self, cmd_pos: CommandPos) -> Result<String> {
+        let cmd = if let Some(mmap) = self.mmaps.get(&cmd_pos.gen) {
+            // 不再写入的日志直接从映射的内存中解析，不需要额外的读取与复制
+            let start = cmd_pos.pos as usize;
+            let record = mmap.get(start..start.saturating_add(cmd_pos.len as usize)).ok_or_else(|| KvsError::Corruption(format!(
+                "record at {}:{} with length {} is beyond the end of the log", cmd_pos.gen, cmd_pos.pos, cmd_pos.len,
+            )))?;
+            serde_json::from_slice(record)?
+        } else {
+            // 从读取器Map中通过该命令的序号获取对应的日志读取器
+            let reader = self.readers.get_mut(&cmd_pos.gen)
+                .unwrap_or_else(|| panic!("Can't find reader: {}", &cmd_pos.gen));
+
+            // 将读取器的指针切换到命令的位置中
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            // 获取这段内容
+            serde_json::from_reader(reader.take(cmd_pos.len))?
+        };
+
+        // 将命令进行转换，删除命令返回错误（错误的指令类型）
+        let (_, value) = self.decode(cmd, cmd_pos.gen)?.ok_or(KvsError::UnexpectedCommandType)?;
+        Ok(value)
+    }
+
+    fn stats_mut(&mut self, gen: u64) -> &mut GenStats {
+        stats_entry(&mut self.gen_stats, gen)
+    }
+
     // 新建日志文件方法参数封装
     fn new_log_file(&mut self, gen:u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+        let key_id = self.key_id();
+        self.key_ids.insert(gen, key_id);
+        new_log_file(&self.path, gen, key_id, &mut self.readers)
+    }
+
+    /// 新日志文件头中的密钥 id，不加密时为 0
+    fn key_id(&self) -> u32 {
+        self.options.encryption.as_ref().map_or(0, Keyring::current_id)
+    }
+
+    /// 编码写入命令，超过 blob 阈值的值存入新的 blob 文件，命令中只记录引用
+    fn encode(&mut self, key: String, value: String, codec: Option<Codec>) -> Result<Command> {
+        if value.len() as u64 > self.options.max_value_bytes {
+            return Err(KvsError::ValueTooLarge(value.len() as u64, self.options.max_value_bytes));
+        }
+        let threshold = self.options.blob_threshold;
+        if threshold > 0 && value.len() > threshold {
+            let cmd = Command::set_encoded(key.clone(), value, codec, self.options.encryption.as_ref())?;
+            let blob = self.blobs.write(&cmd, self.key_id())?;
+            return Ok(Command::SetBlob {key, blob: blob.id, len: blob.bytes});
+        }
+        Command::set_encoded(key, value, codec, self.options.encryption.as_ref())
+    }
+
+    /// 还原日志 gen 中写入命令的键与值，删除命令返回 None
+    fn decode(&self, cmd: Command, gen: u64) -> Result<Option<(String, String)>> {
+        let keyring = self.options.encryption.as_ref();
+        match cmd {
+            Command::SetBlob {key, blob, ..} => {
+                let (cmd, key_id) = blob::read(self.blobs.dir(), blob)?;
+                let value = decode_blob(&key, blob, cmd, keyring, key_id, self.options.max_value_bytes)?;
+                Ok(Some((key, value)))
+            }
+            cmd => decode_command(cmd, keyring, self.key_ids.get(&gen).copied().unwrap_or(0), self.options.max_value_bytes),
+        }
     }
 
 }
 
-#[derive(Debug)]
-struct CommandPos {
-    gen:u64,
-    pos:u64,
-    len:u64,
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        // 保存失败时下次打开由日志重建索引
+        let _ = self.save_index();
+    }
+}
+
+/// 单个日志的数据统计
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GenStats {
+    pub gen: u64,
+    /// 文件头之后的记录字节数
+    pub bytes: u64,
+    /// 已被覆盖或删除、压缩后可以回收的字节数
+    pub dead_bytes: u64,
+}
+
+impl GenStats {
+    /// 无效数据占记录字节数的比例
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.bytes == 0 {
+            0.0
+        } else {
+            self.dead_bytes as f64 / self.bytes as f64
+        }
+    }
+}
+
+fn stats_entry(stats: &mut HashMap<u64, GenStats>, gen: u64) -> &mut GenStats {
+    stats.entry(gen).or_insert_with(|| GenStats{ gen, bytes:0, dead_bytes:0 })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CommandPos {
+    pub(crate) gen:u64,
+    pub(crate) pos:u64,
+    pub(crate) len:u64,
 }
 
 
@@ -226,7 +1110,7 @@ struct BufReaderWithPos<R:Read + Seek> {
 
 impl <R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos
@@ -285,12 +1169,33 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
     }
 }
 
-#[derive(Serialize,Deserialize,Debug)]
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq,Eq)]
 pub enum Command{
     Set{
         key:String,
         value:String
     },
+    // 值经过压缩的写入，value 为压缩数据的 base64
+    SetCompressed{
+        key:String,
+        value:String,
+        codec:Codec
+    },
+    // 值经过加密的写入，value 为密文的 base64，密钥 id 记录在日志文件头中
+    // codec 不为空时值在加密之前经过压缩
+    SetEncrypted{
+        key:String,
+        value:String,
+        nonce:String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        codec:Option<Codec>
+    },
+    // 值存放在编号为 blob 的 blob 文件中，len 为该文件的字节数
+    SetBlob{
+        key:String,
+        blob:u64,
+        len:u64
+    },
     Remove{
         key:String
     }
@@ -298,58 +1203,269 @@ pub enum Command{
 
 
 impl Command {
-    fn set(key:String, value:String) -> Command {
-        Command::Set {key,value}
+    /// 按 codec 压缩值，压缩后没有变小时写入普通的 Set
+    /// 提供密钥时值在压缩之后加密
+    fn set_encoded(key:String, value:String, codec:Option<Codec>, keyring:Option<&Keyring>) -> Result<Command> {
+        if let Some(keyring) = keyring {
+            let (nonce, value, codec) = crypto::encrypt_value(keyring, &key, value, codec)?;
+            return Ok(Command::SetEncrypted {key,value,nonce,codec});
+        }
+        Ok(match codec::encode_value(value, codec)? {
+            (value, Some(codec)) => Command::SetCompressed {key,value,codec},
+            (value, None) => Command::Set {key,value},
+        })
     }
 
     fn remove(key:String) -> Command {
         Command::Remove {key}
     }
+
+    /// 命令引用的 blob
+    fn blob_ref(&self) -> Option<BlobRef> {
+        match *self {
+            Command::SetBlob {blob, len, ..} => Some(BlobRef {id: blob, bytes: len}),
+            _ => None,
+        }
+    }
+}
+
+/// 还原写入命令的键与值，删除命令返回 None
+/// 加密的值以 key_id 对应的密钥解密，blob 引用需要先读取 blob 文件
+pub(crate) fn decode_command(cmd: Command, keyring: Option<&Keyring>, key_id: u32, max_len: u64) -> Result<Option<(String, String)>> {
+    check_encrypted(&cmd, key_id)?;
+    Ok(match cmd {
+        Command::Set {key, value} => Some((key, value)),
+        Command::SetCompressed {key, value, codec} => {
+            let value = codec::decode_value(&value, codec, max_len)?;
+            Some((key, value))
+        }
+        Command::SetEncrypted {key, value, nonce, codec} => {
+            let value = crypto::decrypt_value(keyring, key_id, &key, &nonce, &value, codec, max_len)?;
+            Some((key, value))
+        }
+        Command::SetBlob {key, blob, ..} => {
+            return Err(KvsError::Corruption(format!("blob reference of {:?} to blob {} is not resolved", key, blob)));
+        }
+        Command::Remove {..} => None,
+    })
+}
+
+/// 以 key_id 加密的日志中只会写入加密的值，明文的值说明记录被篡改
+/// 删除记录本来就以明文写入，无法以同样的方式区分
+pub(crate) fn check_encrypted(cmd: &Command, key_id: u32) -> Result<()> {
+    match cmd {
+        Command::Set {key, ..} | Command::SetCompressed {key, ..} if key_id != 0 => Err(KvsError::Corruption(format!(
+            "unencrypted value of {:?} in a log encrypted with key {}", key, key_id
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// 还原 blob 文件中键 key 的值，key_id 为 blob 文件头中的密钥
+pub(crate) fn decode_blob(key: &str, blob: u64, cmd: Command, keyring: Option<&Keyring>, key_id: u32, max_len: u64) -> Result<String> {
+    match decode_command(cmd, keyring, key_id, max_len)? {
+        Some((found, value)) if found == key => Ok(value),
+        _ => Err(KvsError::Corruption(format!("blob {} does not hold the value of {:?}", blob, key))),
+    }
 }
 
 /// 对文件夹路径填充日志文件名
-fn log_path(dir: &Path, gen :u64) -> PathBuf {
+pub(crate) fn log_path(dir: &Path, gen :u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
+/// 创建压缩或导入结果的临时文件并写入文件头
+fn compaction_file(dir: &Path, gen: u64, key_id: u32) -> Result<BufWriter<File>> {
+    let mut writer = BufWriter::new(File::create(dir.join(format!("{}.log.tmp", gen)))?);
+    LogHeader::new(gen).with_key_id(key_id).write_to(&mut writer)?;
+    Ok(writer)
+}
+
+/// 快照记录的日志与清单中的日志及其长度一致、索引文件没有损坏时，打开保存的磁盘索引
+fn reopen_index(path: &Path, index_dir: &Path, cache_entries: usize, snapshot: IndexSnapshot, gen_list: &[u64]) -> Result<Option<(Index, Index, IndexSnapshot)>> {
+    let mut gens = gen_list.to_vec();
+    gens.sort_unstable();
+    if snapshot.logs.len() != gens.len() {
+        return Ok(None);
+    }
+    for (&(gen, len), &expected) in snapshot.logs.iter().zip(&gens) {
+        if gen != expected || fs::metadata(log_path(path, gen)).ok().map(|metadata| metadata.len()) != Some(len) {
+            return Ok(None);
+        }
+    }
+    // 损坏的索引文件同样由日志重建
+    let reopen = |name, state| match DiskIndex::reopen(index_dir, name, cache_entries, state) {
+        Err(KvsError::Corruption(_)) => Ok(None),
+        result => result,
+    };
+    let live = reopen("live", snapshot.live)?;
+    let tombstones = reopen("tombstones", snapshot.tombstones)?;
+    Ok(match (live, tombstones) {
+        (Some(live), Some(tombstones)) => Some((Index::Disk(live), Index::Disk(tombstones), snapshot)),
+        _ => None,
+    })
+}
+
+/// 导入的日志写完之后落盘，由调用者重命名
+fn finish_import_file(mut writer: BufWriter<File>) -> Result<()> {
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// 压缩结果落盘后重命名为正式的日志文件，返回它的读取器
+fn finish_compaction_file(dir: &Path, gen: u64, mut writer: BufWriter<File>) -> Result<BufReaderWithPos<File>> {
+    // 将所有写入刷入压缩文件中并落盘
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    crash_point("compaction-written");
+
+    // 重命名为正式的日志文件，目录落盘后文件名才可靠
+    fs::rename(dir.join(format!("{}.log.tmp", gen)), log_path(dir, gen))?;
+    sync_dir(dir)?;
+    crash_point("compaction-renamed");
+    BufReaderWithPos::new(File::open(log_path(dir, gen))?)
+}
+
+/// 以硬链接共享文件，跨文件系统等无法链接时复制
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+/// 将目录落盘，使其中文件的创建、重命名与删除持久化
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// 原子地写入 dir 中的 name 文件，返回时文件已经落盘
+/// 先写入临时文件落盘再重命名，崩溃后只会看到旧的或新的内容，不会留下写了一半的文件
+pub(crate) fn write_atomic(dir: &Path, name: &str, bytes: &[u8]) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", name));
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(tmp, dir.join(name))?;
+    sync_dir(dir)
+}
+
+/// 测试用的崩溃点，只在开启 crash-test feature 时编译
+/// 环境变量 KVS_CRASH_AT 等于 name 时立即终止进程，模拟在该步骤崩溃
+#[cfg(feature = "crash-test")]
+fn crash_point(name: &str) {
+    if std::env::var_os("KVS_CRASH_AT").is_some_and(|at| at == name) {
+        std::process::abort();
+    }
+}
+
+#[cfg(not(feature = "crash-test"))]
+fn crash_point(_name: &str) {}
+
+#[cfg(feature = "crash-test")]
+thread_local! {
+    static FAIL_AT: std::cell::Cell<Option<&'static str>> = const { std::cell::Cell::new(None) };
+}
+
+/// 测试用的失败点，只在开启 crash-test feature 时编译
+/// 当前线程之后第一次经过 name 时返回错误，模拟该步骤失败而进程继续运行
+#[cfg(feature = "crash-test")]
+#[doc(hidden)]
+pub fn fail_at(name: &'static str) {
+    FAIL_AT.with(|at| at.set(Some(name)));
+}
+
+#[cfg(feature = "crash-test")]
+fn fail_point(name: &str) -> Result<()> {
+    if FAIL_AT.with(|at| at.get() == Some(name)) {
+        FAIL_AT.with(|at| at.set(None));
+        return Err(io::Error::other(format!("injected failure at {}", name)).into());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "crash-test"))]
+fn fail_point(_name: &str) -> Result<()> {
+    Ok(())
+}
+
+/// 记录的长度需要能够写入索引
+fn check_record_len(len: usize) -> Result<()> {
+    if len as u64 > MAX_RECORD_LEN {
+        return Err(KvsError::RecordTooLarge(len as u64, MAX_RECORD_LEN));
+    }
+    Ok(())
+}
+
 /// 通过目录地址加载数据
-fn load(gen:u64, reader:&mut BufReaderWithPos<File>, index: &mut HashMap<String,CommandPos>) -> Result<u64> {
-    // 将读入器地址初始化0
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
+/// 同时统计每个日志的记录字节数与无效字节数，返回日志的文件头
+fn load(gen:u64, reader:&mut BufReaderWithPos<File>, index: &mut Index, tombstones: &mut Index, blobs: &mut BlobStore, stats: &mut HashMap<u64, GenStats>) -> Result<LogHeader> {
+    // 将读入器地址初始化0，校验文件头
+    reader.seek(SeekFrom::Start(0))?;
+    let header = LogHeader::read_from(reader, gen)?;
+    let mut pos = HEADER_LEN;
     // 流式读取将数据序列化为Command
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    // 初始化空间占用为0
-    let mut  uncompacted = 0;
 
     while let Some(cmd) = stream.next() {
         // 计算这段byte所在位置
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-
-            Command::Set {key, ..} => {
-                //数据插入索引之中，成功则对空间占用值进行累加
-                if let Some(old_name) = index.insert(key,CommandPos{gen,pos,len:new_pos-pos}) {
-                    uncompacted += old_name.len;
+        let new_pos = HEADER_LEN + stream.byte_offset() as u64;
+        let cmd_pos = CommandPos{gen,pos,len:new_pos-pos};
+        stats_entry(stats, gen).bytes += cmd_pos.len;
+        let cmd = cmd?;
+        check_encrypted(&cmd, header.key_id)?;
+        let blob = cmd.blob_ref();
+        match cmd {
+
+            Command::Set {key, ..} | Command::SetCompressed {key, ..} | Command::SetEncrypted {key, ..} | Command::SetBlob {key, ..} => {
+                // 被重新写入的键的删除标记失效
+                if let Some(tombstone) = tombstones.remove(&key)? {
+                    stats_entry(stats, tombstone.gen).dead_bytes += tombstone.len;
+                }
+                //数据插入索引之中，旧值所在日志的无效数据累加
+                let old_cmd = index.insert(&key,cmd_pos)?;
+                blobs.replace(&key, blob, old_cmd);
+                if let Some(old_cmd) = old_cmd {
+                    stats_entry(stats, old_cmd.gen).dead_bytes += old_cmd.len;
                 }
             }
 
             Command::Remove {key} => {
-                //索引删除该数据之中，成功则对空间占用值进行累加
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
+                //索引删除该数据，旧值与更早的删除标记都变为无效数据
+                let old_cmd = index.remove(&key)?;
+                blobs.replace(&key, None, old_cmd);
+                if let Some(old_cmd) = old_cmd {
+                    stats_entry(stats, old_cmd.gen).dead_bytes += old_cmd.len;
+                }
+                if let Some(tombstone) = tombstones.insert(&key, cmd_pos)? {
+                    stats_entry(stats, tombstone.gen).dead_bytes += tombstone.len;
                 }
-                uncompacted += new_pos - pos;
             }
         }
         // 写入地址等于new_pos
         pos = new_pos;
     }
 
-    Ok(uncompacted)
+    Ok(header)
+}
+
+/// 日志内容中完整命令所占的前缀长度
+/// 正在写入或被截断的日志末尾可能只有半条命令
+pub(crate) fn complete_prefix<R: Read>(reader: R) -> u64 {
+    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let mut end = 0;
+    while let Some(Ok(_)) = stream.next() {
+        end = stream.byte_offset() as u64;
+    }
+    end
 }
 
 /// 现有日志文件序号排序
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+pub(crate) fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     // 读取文件夹路径
     // 获取该文件夹内各个文件的地址
     // 判断是否为文件并判断拓展名是否为log
@@ -375,16 +1491,22 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
 
 
 /// 新建日志文件
-/// 传入文件夹路径、日志名序号、读取器Map
+/// 传入文件夹路径、日志名序号、文件头中的密钥 id、读取器Map
 /// 返回对应的写入器
-fn new_log_file(path:&Path, gen:u64, readers:&mut HashMap<u64, BufReaderWithPos<File>>) -> Result<BufWriterWithPos<File>> {
+fn new_log_file(path:&Path, gen:u64, key_id:u32, readers:&mut HashMap<u64, BufReaderWithPos<File>>) -> Result<BufWriterWithPos<File>> {
     // 得到对应日志的路径
     let path = log_path(path, gen);
 
+    // 新文件先在临时文件中写入文件头再重命名，日志文件不会出现没有文件头的状态
+    if !path.exists() {
+        let tmp_path = path.with_extension("log.tmp");
+        let mut file = File::create(&tmp_path)?;
+        LogHeader::new(gen).with_key_id(key_id).write_to(&mut file)?;
+        fs::rename(&tmp_path, &path)?;
+    }
+
     // 通过路径构造写入器
     let writer = BufWriterWithPos::new(OpenOptions::new()
-        .create(true)
-        .write(true)
         .append(true)
         .open(&path)?)?;
 
@@ -1,5 +1,29 @@
 pub mod kv;
 pub mod error;
+pub mod replication;
+pub mod raft;
+pub mod shard;
+pub mod backup;
+pub mod export;
+pub mod verify;
+pub mod repair;
+pub mod inspect;
+pub mod format;
+pub mod manifest;
+pub mod compaction;
+pub mod ratelimit;
+pub mod index;
+pub mod diskindex;
+pub mod cache;
+pub mod codec;
+pub mod crypto;
+pub mod blob;
+pub mod commit;
 
-pub use kv::KvStore;
-pub use error::{KvsError, Result};
\ No newline at end of file
+pub use kv::{KvStore, StoreOptions};
+pub use error::{KvsError, Result};
+pub use replication::{ReplicaFollower, ReplicationLeader};
+pub use raft::{RaftNode, RaftOptions};
+pub use commit::{GroupCommitOptions, GroupCommitStore};
+pub use shard::ShardedStore;
+pub use export::{DataFormat, ImportStats};
\ No newline at end of file
@@ -0,0 +1,102 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Result,
+    format::HEADER_LEN,
+    kv::{log_path, sorted_gen_list, write_atomic},
+};
+
+/// 清单文件名，写在存储目录中
+pub(crate) const MANIFEST_FILE: &str = "manifest.json";
+
+/// 存储的清单，记录存活的日志与压缩的进度
+///
+/// 清单整体写入临时文件后重命名替换，`KvStore::open` 以它为准加载日志，
+/// 目录中不在清单里的日志文件不会被加载。
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// 存活的日志，按序号排列
+    pub gens: Vec<u64>,
+    /// 进行中、还没有提交的压缩
+    pub compaction: Option<Compaction>,
+    /// 已被压缩结果替代、等待删除的日志
+    pub obsolete: Vec<u64>,
+    /// 压缩结果中的记录最初写入的日志，每段为起始位置与最初写入的日志
+    /// 压缩结果的序号大于它跳过的日志，其中的记录却写入得更早，不在这里的日志就是它自己
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub origins: BTreeMap<u64, Vec<(u64, u64)>>,
+}
+
+/// 压缩的进度
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Compaction {
+    /// 压缩过程中创建的日志，包括压缩结果与之后写入的日志
+    pub outputs: Vec<u64>,
+}
+
+impl Manifest {
+    /// 读取目录中的清单，没有清单时返回 None
+    pub fn load(dir: impl AsRef<Path>) -> Result<Option<Manifest>> {
+        match fs::read(dir.as_ref().join(MANIFEST_FILE)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// 原子地写入清单，返回时清单已经落盘
+    pub(crate) fn store(&self, dir: &Path) -> Result<()> {
+        write_atomic(dir, MANIFEST_FILE, &serde_json::to_vec(self)?)
+    }
+
+    /// 日志 gen 中 pos 处的记录最初写入的日志
+    pub(crate) fn origin_at(&self, gen: u64, pos: u64) -> u64 {
+        self.origins.get(&gen).and_then(|segments| origin_at(segments, pos)).unwrap_or(gen)
+    }
+
+    /// 清理中断的压缩留下的文件
+    /// 没有提交的压缩结果（包括临时文件）与已提交压缩的旧日志都会被删除
+    pub(crate) fn recover(&mut self, dir: &Path) -> Result<()> {
+        let mut leftovers: Vec<_> = self.obsolete.drain(..).map(|gen| log_path(dir, gen)).collect();
+        if let Some(compaction) = self.compaction.take() {
+            for gen in compaction.outputs {
+                leftovers.push(log_path(dir, gen));
+                leftovers.push(dir.join(format!("{}.log.tmp", gen)));
+            }
+        }
+        for path in leftovers {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 存储中存活的日志
+/// 有清单时以清单为准，否则按目录中的日志文件推断
+pub(crate) fn live_gens(dir: &Path) -> Result<Vec<u64>> {
+    match Manifest::load(dir)? {
+        Some(manifest) => Ok(manifest.gens),
+        None => sorted_gen_list(dir),
+    }
+}
+
+/// origins 中日志 gen 的各段，没有记录的日志只有最初写入它的一段
+pub(crate) fn origin_segments(origins: &BTreeMap<u64, Vec<(u64, u64)>>, gen: u64) -> Vec<(u64, u64)> {
+    origins.get(&gen).cloned().unwrap_or_else(|| vec![(HEADER_LEN, gen)])
+}
+
+/// 各段中 pos 所在的一段最初写入的日志
+pub(crate) fn origin_at(segments: &[(u64, u64)], pos: u64) -> Option<u64> {
+    let i = segments.partition_point(|&(start, _)| start <= pos);
+    segments.get(i.saturating_sub(1)).map(|&(_, origin)| origin)
+}
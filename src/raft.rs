@@ -0,0 +1,1048 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::{
+    error::{KvsError, Result},
+    format,
+    kv::{sync_dir, write_atomic},
+    manifest::live_gens,
+    KvStore,
+};
+
+/// 节点编号
+pub type NodeId = u64;
+
+/// 状态机（KvStore）所在的子目录
+const STORE_DIR: &str = "store";
+
+/// Raft 日志文件名
+const RAFT_LOG_FILE: &str = "raft.log";
+
+/// 快照文件名
+const SNAPSHOT_FILE: &str = "raft.snapshot";
+
+/// Raft 节点的可调参数，以 tick 为时间单位
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    /// 选举超时的下限，实际超时在 [election_ticks, 2 * election_ticks) 中随机
+    pub election_ticks: u64,
+    /// leader 发送心跳的间隔
+    pub heartbeat_ticks: u64,
+    /// 快照之后应用的条目数超过该值时重新生成快照
+    pub snapshot_threshold: u64,
+    /// 单条 AppendEntries 消息携带的最大条目数
+    pub max_append_entries: usize,
+}
+
+impl Default for RaftOptions {
+    fn default() -> Self {
+        RaftOptions {
+            election_ticks: 10,
+            heartbeat_ticks: 3,
+            snapshot_threshold: 1000,
+            max_append_entries: 64,
+        }
+    }
+}
+
+/// 节点角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// 应用到状态机的写入，只包含键与原始的值
+///
+/// 序列化格式与 KvStore 日志中未压缩、未加密的 `Set`/`Remove` 记录相同，
+/// 状态机以默认参数打开，它的日志可以直接作为快照按该类型重放。
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// 日志条目的内容
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum EntryData {
+    /// leader 当选后追加的空条目，用于提交之前任期的条目
+    Noop,
+    /// 应用到 KvStore 的写入
+    Command(WriteOp),
+    /// 新的集群成员列表，追加后立即生效
+    Config(Vec<NodeId>),
+}
+
+/// 日志条目
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub data: EntryData,
+}
+
+/// 成员变更，每次只增加或删除一个节点
+#[derive(Debug, Clone, Copy)]
+pub enum MembershipChange {
+    AddNode(NodeId),
+    RemoveNode(NodeId),
+}
+
+/// 快照的元数据
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SnapshotMeta {
+    /// 快照包含的最后一个条目
+    pub last_index: u64,
+    pub last_term: u64,
+    /// 快照时的集群成员
+    pub config: Vec<NodeId>,
+}
+
+/// 快照，数据为状态机压缩后的日志内容
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub meta: SnapshotMeta,
+    pub data: Vec<u8>,
+}
+
+/// 节点之间的消息
+#[derive(Debug, Clone)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    RequestVoteResponse {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    AppendEntriesResponse {
+        term: u64,
+        success: bool,
+        /// 成功时为已匹配的最后一个条目，失败时为 follower 的最后一个条目
+        match_index: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        snapshot: Snapshot,
+    },
+    InstallSnapshotResponse {
+        term: u64,
+        match_index: u64,
+    },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match *self {
+            Message::RequestVote { term, .. }
+            | Message::RequestVoteResponse { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendEntriesResponse { term, .. }
+            | Message::InstallSnapshot { term, .. }
+            | Message::InstallSnapshotResponse { term, .. } => term,
+        }
+    }
+}
+
+/// 带有收发地址的消息
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub message: Message,
+}
+
+/// Raft 日志文件中的记录
+#[derive(Serialize, Deserialize, Debug)]
+enum RaftRecord {
+    HardState { term: u64, voted_for: Option<NodeId> },
+    Entry(Entry),
+    /// 删除 from 及之后的条目
+    Truncate { from: u64 },
+}
+
+/// Raft 节点，以 KvStore 作为复制状态机
+///
+/// 节点由外部驱动：`tick` 推进逻辑时钟，`step` 处理收到的消息，
+/// 需要发送的消息通过 `drain_messages` 取出。
+pub struct RaftNode {
+    id: NodeId,
+    dir: PathBuf,
+    options: RaftOptions,
+    store: KvStore,
+
+    // 持久化状态
+    term: u64,
+    voted_for: Option<NodeId>,
+    log: Vec<Entry>,
+    snapshot: SnapshotMeta,
+    log_writer: BufWriter<File>,
+
+    // 易失状态
+    role: Role,
+    leader: Option<NodeId>,
+    commit_index: u64,
+    last_applied: u64,
+    votes: HashSet<NodeId>,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+
+    election_elapsed: u64,
+    election_timeout: u64,
+    heartbeat_elapsed: u64,
+    rng: u64,
+
+    outbox: Vec<Envelope>,
+}
+
+impl RaftNode {
+    /// 通过文件夹路径开启一个 Raft 节点
+    /// 全新的节点以 peers 作为初始成员，加入已有集群的节点传入空列表
+    pub fn open(id: NodeId, dir: impl Into<PathBuf>, peers: &[NodeId], options: RaftOptions) -> Result<RaftNode> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let snapshot = match read_snapshot(&dir)? {
+            Some(snapshot) => snapshot,
+            None => {
+                let snapshot = Snapshot {
+                    meta: SnapshotMeta {
+                        config: peers.to_vec(),
+                        ..SnapshotMeta::default()
+                    },
+                    data: Vec::new(),
+                };
+                write_snapshot(&dir, &snapshot)?;
+                snapshot
+            }
+        };
+
+        // 状态机从快照恢复，快照之后的条目提交后会重新应用
+        // 以默认参数打开，不压缩、不加密也不分离大值，日志中只有 Set 与 Remove 记录
+        let mut store = KvStore::open(dir.join(STORE_DIR))?;
+        restore_store(&mut store, &snapshot.data)?;
+
+        let (term, voted_for, log) = load_raft_log(&dir, snapshot.meta.last_index)?;
+        let log_writer = BufWriter::new(OpenOptions::new().create(true).append(true).open(dir.join(RAFT_LOG_FILE))?);
+        // 新建的日志文件需要目录落盘之后才可靠
+        sync_dir(&dir)?;
+
+        let mut node = RaftNode {
+            id,
+            dir,
+            options,
+            store,
+            term,
+            voted_for,
+            log,
+            commit_index: snapshot.meta.last_index,
+            last_applied: snapshot.meta.last_index,
+            snapshot: snapshot.meta,
+            log_writer,
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_elapsed: 0,
+            election_timeout: 0,
+            heartbeat_elapsed: 0,
+            rng: id.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            outbox: Vec::new(),
+        };
+        node.reset_election_timeout();
+        Ok(node)
+    }
+
+    /// 节点编号
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// 当前角色
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// 当前任期
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// 已知的 leader
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    /// 已提交的最后一个条目
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// 已应用到状态机的最后一个条目
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    /// 最近一次快照的元数据
+    pub fn snapshot_meta(&self) -> &SnapshotMeta {
+        &self.snapshot
+    }
+
+    /// 当前生效的集群成员
+    pub fn members(&self) -> Vec<NodeId> {
+        self.config_at(self.last_index())
+    }
+
+    /// 从本地状态机读取数据
+    /// 只反映本节点已应用的条目，follower 上可能读到旧值
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    /// 提交一条写入，返回其日志序号
+    /// 只有 leader 可以接受写入
+    pub fn propose(&mut self, op: WriteOp) -> Result<u64> {
+        self.check_leader()?;
+        self.append_as_leader(EntryData::Command(op))
+    }
+
+    /// 提交一次成员变更，返回其日志序号
+    /// 上一次成员变更提交之前不能发起新的变更
+    pub fn propose_membership(&mut self, change: MembershipChange) -> Result<u64> {
+        self.check_leader()?;
+        let pending = self
+            .log
+            .iter()
+            .any(|entry| entry.index > self.commit_index && matches!(entry.data, EntryData::Config(_)));
+        if pending {
+            return Err(KvsError::Raft("membership change in progress".to_owned()));
+        }
+
+        let mut config = self.members();
+        match change {
+            MembershipChange::AddNode(id) => {
+                if config.contains(&id) {
+                    return Err(KvsError::Raft(format!("node {} is already a member", id)));
+                }
+                config.push(id);
+            }
+            MembershipChange::RemoveNode(id) => {
+                if !config.contains(&id) {
+                    return Err(KvsError::Raft(format!("node {} is not a member", id)));
+                }
+                config.retain(|&member| member != id);
+            }
+        }
+        self.append_as_leader(EntryData::Config(config))
+    }
+
+    /// 取出待发送的消息
+    pub fn drain_messages(&mut self) -> Vec<Envelope> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// 推进一个逻辑时钟
+    pub fn tick(&mut self) -> Result<()> {
+        if self.role == Role::Leader {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= self.options.heartbeat_ticks {
+                self.heartbeat_elapsed = 0;
+                self.broadcast_append()?;
+            }
+            return Ok(());
+        }
+
+        // 不在集群成员中的节点不发起选举
+        if !self.members().contains(&self.id) {
+            return Ok(());
+        }
+        self.election_elapsed += 1;
+        if self.election_elapsed >= self.election_timeout {
+            self.start_election()?;
+        }
+        Ok(())
+    }
+
+    /// 处理一条收到的消息
+    pub fn step(&mut self, from: NodeId, message: Message) -> Result<()> {
+        if message.term() > self.term {
+            let leader = match message {
+                Message::AppendEntries { .. } | Message::InstallSnapshot { .. } => Some(from),
+                _ => None,
+            };
+            self.become_follower(message.term(), leader)?;
+        }
+
+        match message {
+            Message::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => self.handle_request_vote(from, term, last_log_index, last_log_term),
+            Message::RequestVoteResponse { term, granted } => self.handle_vote_response(from, term, granted),
+            Message::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.handle_append_entries(from, term, prev_log_index, prev_log_term, entries, leader_commit),
+            Message::AppendEntriesResponse {
+                term,
+                success,
+                match_index,
+            } => self.handle_append_response(from, term, success, match_index),
+            Message::InstallSnapshot { term, snapshot } => self.handle_install_snapshot(from, term, snapshot),
+            Message::InstallSnapshotResponse { term, match_index } => {
+                if term == self.term && self.role == Role::Leader {
+                    self.update_match(from, match_index)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_request_vote(&mut self, from: NodeId, term: u64, last_log_index: u64, last_log_term: u64) -> Result<()> {
+        let up_to_date = last_log_term > self.last_term()
+            || (last_log_term == self.last_term() && last_log_index >= self.last_index());
+        let granted = term == self.term
+            && self.voted_for.is_none_or(|voted| voted == from)
+            && up_to_date;
+        if granted {
+            self.voted_for = Some(from);
+            self.persist_hard_state()?;
+            self.election_elapsed = 0;
+        }
+        self.send(from, Message::RequestVoteResponse { term: self.term, granted });
+        Ok(())
+    }
+
+    fn handle_vote_response(&mut self, from: NodeId, term: u64, granted: bool) -> Result<()> {
+        if self.role != Role::Candidate || term != self.term || !granted {
+            return Ok(());
+        }
+        self.votes.insert(from);
+        let members = self.members();
+        let votes = members.iter().filter(|id| self.votes.contains(id)).count();
+        if votes > members.len() / 2 {
+            self.become_leader()?;
+        }
+        Ok(())
+    }
+
+    fn handle_append_entries(
+        &mut self,
+        from: NodeId,
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<()> {
+        if term < self.term {
+            self.send(
+                from,
+                Message::AppendEntriesResponse {
+                    term: self.term,
+                    success: false,
+                    match_index: self.last_index(),
+                },
+            );
+            return Ok(());
+        }
+        // 同一任期的候选者收到 leader 的消息后退回 follower
+        if self.role != Role::Follower || self.leader != Some(from) {
+            self.become_follower(term, Some(from))?;
+        }
+        self.election_elapsed = 0;
+
+        // 快照之前的条目都已提交，一定与 leader 一致
+        let matched = prev_log_index <= self.snapshot.last_index
+            || self.term_at(prev_log_index) == Some(prev_log_term);
+        if !matched {
+            let hint = self.last_index().min(prev_log_index.saturating_sub(1));
+            self.send(
+                from,
+                Message::AppendEntriesResponse {
+                    term: self.term,
+                    success: false,
+                    match_index: hint,
+                },
+            );
+            return Ok(());
+        }
+
+        let last_new = prev_log_index + entries.len() as u64;
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            if entry.index <= self.snapshot.last_index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.truncate_from(entry.index)?,
+                None => {}
+            }
+            new_entries.push(entry);
+        }
+        self.append_entries(new_entries)?;
+
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(last_new).max(self.commit_index);
+            self.apply()?;
+        }
+        self.send(
+            from,
+            Message::AppendEntriesResponse {
+                term: self.term,
+                success: true,
+                match_index: last_new.max(self.snapshot.last_index),
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_append_response(&mut self, from: NodeId, term: u64, success: bool, match_index: u64) -> Result<()> {
+        if self.role != Role::Leader || term != self.term {
+            return Ok(());
+        }
+        if success {
+            self.update_match(from, match_index)
+        } else {
+            // 回退到 follower 给出的位置后重试
+            let next = self.next_index.entry(from).or_insert(1);
+            *next = (*next - 1).min(match_index + 1).max(1);
+            self.send_append(from)
+        }
+    }
+
+    fn handle_install_snapshot(&mut self, from: NodeId, term: u64, snapshot: Snapshot) -> Result<()> {
+        if term < self.term {
+            self.send(
+                from,
+                Message::InstallSnapshotResponse {
+                    term: self.term,
+                    match_index: self.last_index(),
+                },
+            );
+            return Ok(());
+        }
+        if self.role != Role::Follower || self.leader != Some(from) {
+            self.become_follower(term, Some(from))?;
+        }
+        self.election_elapsed = 0;
+
+        let last_index = snapshot.meta.last_index;
+        if last_index > self.commit_index {
+            // 快照之后仍一致的条目保留，其余全部丢弃
+            if self.term_at(last_index) == Some(snapshot.meta.last_term) {
+                let keep = (last_index - self.snapshot.last_index) as usize;
+                self.log.drain(..keep);
+            } else {
+                self.log.clear();
+            }
+            write_snapshot(&self.dir, &snapshot)?;
+            restore_store(&mut self.store, &snapshot.data)?;
+            self.snapshot = snapshot.meta;
+            self.commit_index = last_index;
+            self.last_applied = last_index;
+            self.rewrite_raft_log()?;
+        }
+        self.send(
+            from,
+            Message::InstallSnapshotResponse {
+                term: self.term,
+                match_index: last_index.max(self.commit_index),
+            },
+        );
+        Ok(())
+    }
+
+    fn update_match(&mut self, from: NodeId, match_index: u64) -> Result<()> {
+        let matched = self.match_index.entry(from).or_insert(0);
+        *matched = (*matched).max(match_index);
+        let matched = *matched;
+        self.next_index.insert(from, matched + 1);
+        self.maybe_commit()?;
+        // 还有未发送的条目时继续发送
+        if matched < self.last_index() {
+            self.send_append(from)?;
+        }
+        Ok(())
+    }
+
+    fn start_election(&mut self) -> Result<()> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id);
+        self.votes.clear();
+        self.votes.insert(self.id);
+        self.persist_hard_state()?;
+        self.reset_election_timeout();
+
+        let members = self.members();
+        if members.iter().filter(|id| self.votes.contains(id)).count() > members.len() / 2 {
+            return self.become_leader();
+        }
+        let (last_log_index, last_log_term) = (self.last_index(), self.last_term());
+        let id = self.id;
+        for peer in members.into_iter().filter(|&peer| peer != id) {
+            self.send(
+                peer,
+                Message::RequestVote {
+                    term: self.term,
+                    last_log_index,
+                    last_log_term,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.persist_hard_state()?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.reset_election_timeout();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.heartbeat_elapsed = 0;
+        self.next_index.clear();
+        self.match_index.clear();
+        // 提交一个本任期的空条目，使之前任期的条目得以提交
+        self.append_as_leader(EntryData::Noop)?;
+        Ok(())
+    }
+
+    fn check_leader(&self) -> Result<()> {
+        if self.role == Role::Leader {
+            Ok(())
+        } else {
+            Err(KvsError::NotLeader(self.leader))
+        }
+    }
+
+    fn append_as_leader(&mut self, data: EntryData) -> Result<u64> {
+        let index = self.last_index() + 1;
+        self.append_entries(vec![Entry {
+            term: self.term,
+            index,
+            data,
+        }])?;
+        self.maybe_commit()?;
+        self.broadcast_append()?;
+        Ok(index)
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        let id = self.id;
+        for peer in self.members().into_iter().filter(|&peer| peer != id) {
+            self.send_append(peer)?;
+        }
+        Ok(())
+    }
+
+    fn send_append(&mut self, peer: NodeId) -> Result<()> {
+        let last_index = self.last_index();
+        let next = *self.next_index.entry(peer).or_insert(last_index + 1);
+
+        // follower 需要的条目已经被快照，改为发送快照
+        if next <= self.snapshot.last_index {
+            let snapshot = read_snapshot(&self.dir)?
+                .ok_or_else(|| KvsError::Raft("snapshot file missing".to_owned()))?;
+            self.send(
+                peer,
+                Message::InstallSnapshot {
+                    term: self.term,
+                    snapshot,
+                },
+            );
+            return Ok(());
+        }
+
+        let prev_log_index = next - 1;
+        let prev_log_term = self.term_at(prev_log_index).unwrap_or(0);
+        let start = (next - self.snapshot.last_index - 1) as usize;
+        let end = (start + self.options.max_append_entries).min(self.log.len());
+        let entries = self.log[start..end].to_vec();
+        self.send(
+            peer,
+            Message::AppendEntries {
+                term: self.term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit: self.commit_index,
+            },
+        );
+        Ok(())
+    }
+
+    /// 过半成员已复制且属于当前任期的条目可以提交
+    fn maybe_commit(&mut self) -> Result<()> {
+        let members = self.members();
+        if members.is_empty() {
+            return Ok(());
+        }
+        let mut matched: Vec<u64> = members
+            .iter()
+            .map(|&id| {
+                if id == self.id {
+                    self.last_index()
+                } else {
+                    self.match_index.get(&id).cloned().unwrap_or(0)
+                }
+            })
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let quorum = matched[members.len() / 2];
+        if quorum > self.commit_index && self.term_at(quorum) == Some(self.term) {
+            self.commit_index = quorum;
+            self.apply()?;
+        }
+        Ok(())
+    }
+
+    /// 将已提交的条目应用到状态机
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = self.entry(index).expect("committed entry missing").clone();
+            match entry.data {
+                EntryData::Command(op) => apply_write(&mut self.store, op)?,
+                EntryData::Config(config) => {
+                    // 已提交的新成员列表不包含自己时，leader 退位
+                    if self.role == Role::Leader && !config.contains(&self.id) {
+                        self.role = Role::Follower;
+                        self.leader = None;
+                    }
+                }
+                EntryData::Noop => {}
+            }
+            self.last_applied = index;
+        }
+
+        if self.last_applied - self.snapshot.last_index >= self.options.snapshot_threshold {
+            self.take_snapshot()?;
+        }
+        Ok(())
+    }
+
+    /// 压缩状态机并将压缩后的日志作为快照，丢弃快照之前的条目
+    fn take_snapshot(&mut self) -> Result<()> {
+        self.store.compact()?;
+        let data = read_store_logs(&self.dir.join(STORE_DIR))?;
+        let last_index = self.last_applied;
+        let snapshot = Snapshot {
+            meta: SnapshotMeta {
+                last_index,
+                last_term: self.term_at(last_index).expect("applied entry missing"),
+                config: self.config_at(last_index),
+            },
+            data,
+        };
+        write_snapshot(&self.dir, &snapshot)?;
+
+        let compacted = (last_index - self.snapshot.last_index) as usize;
+        self.log.drain(..compacted);
+        self.snapshot = snapshot.meta;
+        self.rewrite_raft_log()
+    }
+
+    fn send(&mut self, to: NodeId, message: Message) {
+        self.outbox.push(Envelope {
+            from: self.id,
+            to,
+            message,
+        });
+    }
+
+    fn reset_election_timeout(&mut self) {
+        // xorshift，按节点编号取不同的种子
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let base = self.options.election_ticks.max(1);
+        self.election_timeout = base + self.rng % base;
+        self.election_elapsed = 0;
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot.last_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.last_term, |entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot.last_index {
+            return None;
+        }
+        self.log.get((index - self.snapshot.last_index - 1) as usize)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.last_index {
+            return Some(self.snapshot.last_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    /// index 处生效的集群成员
+    fn config_at(&self, index: u64) -> Vec<NodeId> {
+        self.log
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.data {
+                EntryData::Config(config) => Some(config.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.config.clone())
+    }
+
+    fn append_entries(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        for entry in &entries {
+            serde_json::to_writer(&mut self.log_writer, &RaftRecord::Entry(entry.clone()))?;
+        }
+        self.sync_raft_log()?;
+        self.log.extend(entries);
+        Ok(())
+    }
+
+    fn truncate_from(&mut self, index: u64) -> Result<()> {
+        serde_json::to_writer(&mut self.log_writer, &RaftRecord::Truncate { from: index })?;
+        self.sync_raft_log()?;
+        self.log.retain(|entry| entry.index < index);
+        Ok(())
+    }
+
+    fn persist_hard_state(&mut self) -> Result<()> {
+        let record = RaftRecord::HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+        };
+        serde_json::to_writer(&mut self.log_writer, &record)?;
+        self.sync_raft_log()
+    }
+
+    /// 回复投票或追加请求之前，任期、投票与条目需要已经落盘
+    fn sync_raft_log(&mut self) -> Result<()> {
+        self.log_writer.flush()?;
+        self.log_writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// 以当前状态重写 Raft 日志文件
+    fn rewrite_raft_log(&mut self) -> Result<()> {
+        let hard_state = RaftRecord::HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+        };
+        let mut records = serde_json::to_vec(&hard_state)?;
+        for entry in &self.log {
+            serde_json::to_writer(&mut records, &RaftRecord::Entry(entry.clone()))?;
+        }
+        write_atomic(&self.dir, RAFT_LOG_FILE, &records)?;
+        self.log_writer = BufWriter::new(OpenOptions::new().append(true).open(self.dir.join(RAFT_LOG_FILE))?);
+        Ok(())
+    }
+}
+
+/// 重放 Raft 日志文件，返回任期、投票与快照之后的条目
+fn load_raft_log(dir: &Path, snapshot_index: u64) -> Result<(u64, Option<NodeId>, Vec<Entry>)> {
+    let (mut term, mut voted_for, mut log) = (0, None, Vec::<Entry>::new());
+    let file = match File::open(dir.join(RAFT_LOG_FILE)) {
+        Ok(file) => file,
+        Err(_) => return Ok((term, voted_for, log)),
+    };
+    let stream = Deserializer::from_reader(BufReader::new(file)).into_iter::<RaftRecord>();
+    for record in stream {
+        match record? {
+            RaftRecord::HardState { term: t, voted_for: v } => {
+                term = t;
+                voted_for = v;
+            }
+            RaftRecord::Entry(entry) => {
+                if entry.index > snapshot_index {
+                    log.push(entry);
+                }
+            }
+            RaftRecord::Truncate { from } => log.retain(|entry| entry.index < from),
+        }
+    }
+    Ok((term, voted_for, log))
+}
+
+/// 快照文件的第一行为元数据，之后为状态机数据
+fn read_snapshot(dir: &Path) -> Result<Option<Snapshot>> {
+    let file = match File::open(dir.join(SNAPSHOT_FILE)) {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let meta = serde_json::from_str(&line)?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    Ok(Some(Snapshot { meta, data }))
+}
+
+fn write_snapshot(dir: &Path, snapshot: &Snapshot) -> Result<()> {
+    let mut bytes = serde_json::to_vec(&snapshot.meta)?;
+    bytes.push(b'\n');
+    bytes.extend_from_slice(&snapshot.data);
+    write_atomic(dir, SNAPSHOT_FILE, &bytes)
+}
+
+/// 按序号拼接状态机全部日志文件中的记录
+fn read_store_logs(path: &Path) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for gen in live_gens(path)? {
+        data.extend(format::read_records(path, gen)?);
+    }
+    Ok(data)
+}
+
+/// 清空状态机后重放快照数据
+fn restore_store(store: &mut KvStore, data: &[u8]) -> Result<()> {
+    store.clear()?;
+    for op in Deserializer::from_slice(data).into_iter::<WriteOp>() {
+        apply_write(store, op?)?;
+    }
+    Ok(())
+}
+
+/// 把一条写入应用到状态机，删除不存在的键不是错误
+fn apply_write(store: &mut KvStore, op: WriteOp) -> Result<()> {
+    match op {
+        WriteOp::Set { key, value } => store.set(key, value),
+        WriteOp::Remove { key } => match store.remove(key) {
+            Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+/// 进程内的模拟网络，用于测试 Raft 集群
+///
+/// 消息按发送顺序投递，可以隔离节点来模拟网络分区与宕机。
+#[derive(Default)]
+pub struct SimNetwork {
+    nodes: BTreeMap<NodeId, RaftNode>,
+    queue: VecDeque<Envelope>,
+    isolated: HashSet<NodeId>,
+}
+
+impl SimNetwork {
+    pub fn new() -> SimNetwork {
+        SimNetwork::default()
+    }
+
+    /// 加入一个节点
+    pub fn add_node(&mut self, node: RaftNode) {
+        self.isolated.remove(&node.id());
+        self.nodes.insert(node.id(), node);
+    }
+
+    /// 移出一个节点，模拟宕机，节点的目录保持不变
+    pub fn remove_node(&mut self, id: NodeId) -> Option<RaftNode> {
+        self.queue.retain(|envelope| envelope.to != id);
+        self.nodes.remove(&id)
+    }
+
+    pub fn node(&self, id: NodeId) -> &RaftNode {
+        &self.nodes[&id]
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut RaftNode {
+        self.nodes.get_mut(&id).expect("node not found")
+    }
+
+    /// 隔离节点，发往或来自该节点的消息都会被丢弃
+    pub fn isolate(&mut self, id: NodeId) {
+        self.isolated.insert(id);
+    }
+
+    /// 恢复所有被隔离的节点
+    pub fn heal(&mut self) {
+        self.isolated.clear();
+    }
+
+    /// 当前任期最高的 leader
+    pub fn leader(&self) -> Option<NodeId> {
+        self.nodes
+            .values()
+            .filter(|node| node.role() == Role::Leader && !self.isolated.contains(&node.id()))
+            .max_by_key(|node| node.term())
+            .map(RaftNode::id)
+    }
+
+    /// 所有节点推进一个时钟，并投递消息直到网络中没有消息
+    pub fn tick(&mut self) -> Result<()> {
+        for node in self.nodes.values_mut() {
+            node.tick()?;
+        }
+        self.deliver()
+    }
+
+    /// 推进若干个时钟
+    pub fn run(&mut self, ticks: u64) -> Result<()> {
+        for _ in 0..ticks {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    /// 投递所有待发送的消息
+    pub fn deliver(&mut self) -> Result<()> {
+        loop {
+            for node in self.nodes.values_mut() {
+                self.queue.extend(node.drain_messages());
+            }
+            let envelope = match self.queue.pop_front() {
+                Some(envelope) => envelope,
+                None => return Ok(()),
+            };
+            if self.isolated.contains(&envelope.from) || self.isolated.contains(&envelope.to) {
+                continue;
+            }
+            if let Some(node) = self.nodes.get_mut(&envelope.to) {
+                node.step(envelope.from, envelope.message)?;
+            }
+        }
+    }
+}
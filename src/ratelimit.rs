@@ -0,0 +1,136 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// 令牌桶限速器，限制后台任务（压缩、备份、导出）的 I/O 速度
+///
+/// 克隆出的限速器共享同一个令牌桶，多个任务同时运行时总速度不超过设定值。
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    bytes_per_sec: u64,
+    burst: u64,
+    /// 可用的令牌，为负时表示已经透支
+    tokens: f64,
+    refilled_at: Instant,
+    metrics: ThrottleMetrics,
+}
+
+/// 限速统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThrottleMetrics {
+    /// 经过限速器的字节数
+    pub bytes: u64,
+    /// 因令牌不足而等待的次数
+    pub throttled: u64,
+    /// 等待的总时间
+    pub throttled_time: Duration,
+}
+
+impl RateLimiter {
+    /// 每秒 bytes_per_sec 字节，最多积攒一秒的令牌
+    /// bytes_per_sec 为 0 时不限速，只统计经过的字节数
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter::with_burst(bytes_per_sec, bytes_per_sec)
+    }
+
+    /// 每秒 bytes_per_sec 字节，最多积攒 burst 字节的令牌，bytes_per_sec 为 0 时不限速
+    pub fn with_burst(bytes_per_sec: u64, burst: u64) -> RateLimiter {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                bytes_per_sec,
+                burst,
+                tokens: burst as f64,
+                refilled_at: Instant::now(),
+                metrics: ThrottleMetrics::default(),
+            })),
+        }
+    }
+
+    /// 取走 bytes 个令牌，令牌不足时阻塞到补足为止
+    /// 单次请求可以超过 burst，超出的部分通过等待偿还
+    pub fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+            if bucket.bytes_per_sec == 0 {
+                bucket.metrics.bytes += bytes;
+                return;
+            }
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * bucket.bytes_per_sec as f64).min(bucket.burst as f64);
+            bucket.refilled_at = now;
+            bucket.tokens -= bytes as f64;
+            bucket.metrics.bytes += bytes;
+
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            let wait = Duration::from_secs_f64(-bucket.tokens / bucket.bytes_per_sec as f64);
+            bucket.metrics.throttled += 1;
+            bucket.metrics.throttled_time += wait;
+            wait
+        };
+        // 等待时不持有锁，其他任务看到透支的令牌同样会等待
+        thread::sleep(wait);
+    }
+
+    /// 当前的限速统计
+    pub fn metrics(&self) -> ThrottleMetrics {
+        self.bucket.lock().expect("rate limiter lock poisoned").metrics
+    }
+}
+
+/// 读取时按读到的字节数取令牌
+pub(crate) struct ThrottledReader<R> {
+    inner: R,
+    limiter: Option<RateLimiter>,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub(crate) fn new(inner: R, limiter: Option<RateLimiter>) -> Self {
+        ThrottledReader { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(len as u64);
+        }
+        Ok(len)
+    }
+}
+
+/// 写入后按写入的字节数取令牌
+pub(crate) struct ThrottledWriter<W> {
+    inner: W,
+    limiter: Option<RateLimiter>,
+}
+
+impl<W: Write> ThrottledWriter<W> {
+    pub(crate) fn new(inner: W, limiter: Option<RateLimiter>) -> Self {
+        ThrottledWriter { inner, limiter }
+    }
+}
+
+impl<W: Write> Write for ThrottledWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(len as u64);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
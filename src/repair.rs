@@ -0,0 +1,190 @@
+use std::{fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::{
+    blob::{self, BLOB_DIR},
+    codec,
+    crypto::{self, Keyring},
+    error::{KvsError, Result},
+    format::{LogHeader, HEADER_LEN},
+    kv::{self, log_path, sorted_gen_list, Command},
+    manifest::live_gens,
+    KvStore, StoreOptions,
+};
+
+/// 修复报告文件名，写在修复后的存储目录中
+pub(crate) const REPAIR_REPORT_FILE: &str = "repair-report.json";
+
+/// 一段无法解析而被跳过的日志内容 [start, end)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LostRange {
+    pub gen: u64,
+    pub start: u64,
+    pub end: u64,
+    /// 从损坏内容中仍能辨认出的键
+    pub keys: Vec<String>,
+}
+
+/// 修复结果
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RepairReport {
+    /// 扫描过的日志
+    pub gens: Vec<u64>,
+    /// 成功恢复的记录数
+    pub recovered_records: u64,
+    /// 丢失的日志内容
+    pub lost: Vec<LostRange>,
+    /// 修复后存储中的键数
+    pub keys: u64,
+}
+
+/// 从可能损坏的存储目录 src 中恢复所有可读的记录，重建一个干净的存储到 dest
+///
+/// 遇到无法解析的内容时，向后寻找下一条可以解析的记录继续，跳过的区间及其中
+/// 能辨认出的键记录在报告里，报告同时写入 dest 目录。src 中的文件不会被修改。
+/// 清单损坏时按目录中的日志文件修复，清单中缺失或无法读取的日志记录为整个丢失。
+pub fn repair(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<RepairReport> {
+    repair_with_options(src, dest, StoreOptions::default())
+}
+
+/// 以指定的参数打开 dest 进行修复
+/// 加密的存储需要在 options 中提供 src 所用的全部密钥，无法通过认证的记录按丢失处理；
+/// 修复后的存储以 options 中当前的密钥加密。
+pub fn repair_with_options(src: impl AsRef<Path>, dest: impl AsRef<Path>, options: StoreOptions) -> Result<RepairReport> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    if dest.exists() && !sorted_gen_list(dest)?.is_empty() {
+        return Err(KvsError::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already contains log files", dest.display()),
+        )));
+    }
+
+    let keyring = options.encryption.clone();
+    let mut store = KvStore::open_with_options(dest, options)?;
+    let mut report = RepairReport::default();
+
+    // 清单损坏时按目录中的日志文件修复
+    let gens = match live_gens(src) {
+        Ok(gens) => gens,
+        Err(_) => sorted_gen_list(src)?,
+    };
+    for gen in gens {
+        report.gens.push(gen);
+        // 缺失或无法读取的日志整个丢失，其余日志照常修复
+        let data = match fs::read(log_path(src, gen)) {
+            Ok(data) => data,
+            Err(_) => {
+                let len = fs::metadata(log_path(src, gen)).map_or(0, |metadata| metadata.len());
+                report.lost.push(LostRange { gen, start: 0, end: len, keys: Vec::new() });
+                continue;
+            }
+        };
+        // 文件头损坏或是旧格式时从头寻找记录
+        let (mut pos, key_id) = match LogHeader::read_from(&mut &data[..], gen) {
+            Ok(header) => (HEADER_LEN as usize, header.key_id),
+            Err(_) => (0, 0),
+        };
+        while pos < data.len() {
+            let mut stream = Deserializer::from_slice(&data[pos..]).into_iter::<Command>();
+            match stream.next() {
+                // 只剩空白
+                None => break,
+                Some(Ok(cmd)) => {
+                    let end = pos + stream.byte_offset();
+                    match apply(&mut store, src, cmd, keyring.as_ref(), key_id) {
+                        Ok(()) => report.recovered_records += 1,
+                        // 值无法解密、解压或 blob 缺失的记录同样丢失
+                        Err(KvsError::Corruption(_)) => report.lost.push(LostRange {
+                            gen,
+                            start: pos as u64,
+                            end: end as u64,
+                            keys: keys_in(&data[pos..end]),
+                        }),
+                        Err(e) => return Err(e),
+                    }
+                    pos = end;
+                }
+                Some(Err(_)) => {
+                    let next = resync(&data, pos + 1);
+                    report.lost.push(LostRange {
+                        gen,
+                        start: pos as u64,
+                        end: next as u64,
+                        keys: keys_in(&data[pos..next]),
+                    });
+                    pos = next;
+                }
+            }
+        }
+    }
+
+    // 压缩掉重放中产生的旧值
+    store.compact()?;
+    report.keys = store.key_count();
+    fs::write(dest.join(REPAIR_REPORT_FILE), serde_json::to_vec_pretty(&report)?)?;
+    Ok(report)
+}
+
+/// 把一条记录写入修复后的存储，加密的值以 key_id 对应的密钥解密
+/// blob 引用从 src 的 blob 文件中读取值
+fn apply(store: &mut KvStore, src: &Path, cmd: Command, keyring: Option<&Keyring>, key_id: u32) -> Result<()> {
+    kv::check_encrypted(&cmd, key_id)?;
+    match cmd {
+        Command::Set { key, value } => store.set(key, value),
+        Command::SetCompressed { key, value, codec } => {
+            let value = codec::decode_value(&value, codec, store.max_value_bytes())?;
+            store.set_with_codec(key, value, Some(codec))
+        }
+        Command::SetEncrypted { key, value, nonce, codec } => {
+            let value = crypto::decrypt_value(keyring, key_id, &key, &nonce, &value, codec, store.max_value_bytes())?;
+            store.set(key, value)
+        }
+        Command::SetBlob { key, blob, .. } => {
+            let (cmd, key_id) = blob::read(&src.join(BLOB_DIR), blob)?;
+            let value = kv::decode_blob(&key, blob, cmd, keyring, key_id, store.max_value_bytes())?;
+            store.set(key, value)
+        }
+        Command::Remove { key } => match store.remove(key) {
+            Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+/// 从 from 开始寻找下一条可以完整解析的记录，找不到时返回数据末尾
+fn resync(data: &[u8], from: usize) -> usize {
+    const MARKERS: [&[u8]; 5] = [
+        b"{\"Set\"",
+        b"{\"SetCompressed\"",
+        b"{\"SetEncrypted\"",
+        b"{\"SetBlob\"",
+        b"{\"Remove\"",
+    ];
+    (from..data.len())
+        .filter(|&pos| MARKERS.iter().any(|marker| data[pos..].starts_with(marker)))
+        .find(|&pos| {
+            let mut stream = Deserializer::from_slice(&data[pos..]).into_iter::<Command>();
+            matches!(stream.next(), Some(Ok(_)))
+        })
+        .unwrap_or(data.len())
+}
+
+/// 损坏内容中 `"key":` 之后能解析出的字符串
+fn keys_in(damaged: &[u8]) -> Vec<String> {
+    const KEY_FIELD: &[u8] = b"\"key\":";
+    let mut keys = Vec::new();
+    for pos in 0..damaged.len() {
+        if !damaged[pos..].starts_with(KEY_FIELD) {
+            continue;
+        }
+        let rest = &damaged[pos + KEY_FIELD.len()..];
+        if let Some(Ok(key)) = Deserializer::from_slice(rest).into_iter::<String>().next() {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    keys
+}
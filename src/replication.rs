@@ -0,0 +1,556 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::{
+    blob::{self, blob_path, BLOB_DIR},
+    codec,
+    crypto::{self, Keyring},
+    error::{KvsError, Result},
+    format::{LogHeader, HEADER_LEN},
+    kv::{self, complete_prefix, log_path, write_atomic, Command},
+    manifest::live_gens,
+    KvStore, StoreOptions,
+};
+
+/// 单次拉取日志的默认字节数上限
+const FETCH_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// follower 保存复制进度的文件名
+pub(crate) const REPLICATION_STATE_FILE: &str = "replication.json";
+
+/// follower 发给 leader 的请求
+#[derive(Serialize, Deserialize, Debug)]
+enum Request {
+    /// 从 leader 的 (gen, pos) 处开始拉取至多 max_bytes 字节的日志
+    Fetch { gen: u64, pos: u64, max_bytes: u64 },
+}
+
+/// leader 返回给 follower 的响应
+#[derive(Serialize, Deserialize, Debug)]
+enum Response {
+    /// follower 所在的日志已被压缩删除（或与 leader 不一致）
+    /// follower 需要清空数据并从 gen 的开头重新追赶，lag 为 leader 上全部日志的字节数
+    Reset { gen: u64, lag: u64 },
+    /// 从 (gen, pos) 开始的 len 字节日志，内容紧跟在响应行之后
+    /// lag 为这段日志之后 leader 上还剩余的字节数
+    /// key_id 为日志加密所用的密钥；blobs 为日志中引用的 blob 的编号与字节数，
+    /// 这些 blob 文件的内容按顺序紧跟在日志之后
+    Chunk {
+        gen: u64,
+        pos: u64,
+        len: u64,
+        lag: u64,
+        #[serde(default)]
+        key_id: u32,
+        #[serde(default)]
+        blobs: Vec<(u64, u64)>,
+    },
+}
+
+/// follower 在 leader 日志中的复制位置
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicationPosition {
+    /// leader 的日志序号
+    pub gen: u64,
+    /// 日志内的字节偏移
+    pub pos: u64,
+}
+
+/// follower 报告的复制延迟
+#[derive(Debug, Clone, Copy)]
+pub struct ReplicationLag {
+    /// 最近一次拉取时 leader 上尚未复制的字节数，从未拉取过时为 None
+    pub bytes: Option<u64>,
+    /// 距离上一次完全追上 leader 经过的时间，从未追上时为 None
+    pub since_caught_up: Option<Duration>,
+}
+
+/// 复制的 leader 端
+/// 在后台线程中监听 follower 的连接，直接从存储目录读取日志文件返回给 follower
+/// 丢弃时关闭全部连接，并等待服务这些连接的线程退出
+pub struct ReplicationLeader {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReplicationLeader {
+    /// 以存储目录与监听地址启动 leader
+    pub fn start(path: impl Into<PathBuf>, addr: impl ToSocketAddrs) -> Result<ReplicationLeader> {
+        let path = path.into();
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let flag = Arc::clone(&shutdown);
+        let handle = thread::spawn(move || {
+            let mut conns: Vec<(TcpStream, JoinHandle<()>)> = Vec::new();
+            for stream in listener.incoming() {
+                if flag.load(Ordering::SeqCst) {
+                    break;
+                }
+                conns.retain(|(_, handle)| !handle.is_finished());
+                // 单个连接出错不影响其他 follower
+                let Ok(stream) = stream else { continue };
+                let Ok(conn) = stream.try_clone() else { continue };
+                let path = path.clone();
+                let handle = thread::spawn(move || {
+                    let _ = serve(&path, conn);
+                });
+                conns.push((stream, handle));
+            }
+            // 关闭仍在服务的连接，唤醒阻塞在读取请求上的线程并等待它们退出
+            for (stream, handle) in conns {
+                let _ = stream.shutdown(Shutdown::Both);
+                let _ = handle.join();
+            }
+        });
+
+        Ok(ReplicationLeader {
+            addr,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    /// leader 实际监听的地址
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for ReplicationLeader {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // 连接一次自身以唤醒阻塞中的 accept
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// 处理单个 follower 连接
+fn serve(path: &Path, stream: TcpStream) -> Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    for line in reader.lines() {
+        let request: Request = serde_json::from_str(&line?)?;
+        match request {
+            Request::Fetch { gen, pos, max_bytes } => {
+                let (response, payload) = fetch(path, gen, pos, max_bytes)?;
+                serde_json::to_writer(&mut writer, &response)?;
+                writer.write_all(b"\n")?;
+                writer.write_all(&payload)?;
+                writer.flush()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 读取 leader 日志中 (gen, pos) 之后的一段完整命令
+fn fetch(path: &Path, gen: u64, pos: u64, max_bytes: u64) -> Result<(Response, Vec<u8>)> {
+    let gen_list = live_gens(path)?;
+    let first = match gen_list.first() {
+        Some(&first) => first,
+        None => {
+            let response = Response::Chunk { gen, pos, len: 0, lag: 0, key_id: 0, blobs: Vec::new() };
+            return Ok((response, Vec::new()));
+        }
+    };
+
+    // follower 的日志已不存在，需要从最早的日志开始全量追赶
+    let reset = Response::Reset {
+        gen: first,
+        lag: remaining_after(path, &gen_list, 0)?,
+    };
+    if !gen_list.contains(&gen) {
+        return Ok((reset, Vec::new()));
+    }
+
+    // 记录从文件头之后开始
+    let (mut gen, mut pos) = (gen, pos.max(HEADER_LEN));
+    loop {
+        // 日志可能在压缩中被删除
+        let mut file = match File::open(log_path(path, gen)) {
+            Ok(file) => file,
+            Err(_) => return Ok((reset, Vec::new())),
+        };
+        let len = file.metadata()?.len();
+        if pos > len {
+            return Ok((reset, Vec::new()));
+        }
+
+        // 当前日志已读完且存在更新的日志，切换到下一个日志
+        if pos == len {
+            if let Some(&next) = gen_list.iter().find(|&&g| g > gen) {
+                gen = next;
+                pos = HEADER_LEN;
+                continue;
+            }
+        }
+
+        // 至少读出一条完整命令，单条命令超过上限时扩大读取范围
+        let mut limit = max_bytes.max(1);
+        let payload = loop {
+            file.seek(SeekFrom::Start(pos))?;
+            let mut buf = Vec::new();
+            (&mut file).take(limit).read_to_end(&mut buf)?;
+            let complete = complete_prefix(&buf[..]) as usize;
+            if complete > 0 || pos + limit >= len {
+                buf.truncate(complete);
+                break buf;
+            }
+            limit *= 2;
+        };
+
+        // follower 需要日志的密钥与引用的 blob 才能还原其中的值
+        file.seek(SeekFrom::Start(0))?;
+        let key_id = LogHeader::read_from(&mut file, gen)?.key_id;
+        let mut blobs = Vec::new();
+        let mut blob_data = Vec::new();
+        for cmd in Deserializer::from_slice(&payload).into_iter::<Command>() {
+            if let Command::SetBlob { blob, .. } = cmd? {
+                // blob 在引用它的日志被压缩删除之后才回收，follower 同样需要重新追赶
+                let data = match fs::read(blob_path(&path.join(BLOB_DIR), blob)) {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((reset, Vec::new())),
+                    Err(e) => return Err(e.into()),
+                };
+                blobs.push((blob, data.len() as u64));
+                blob_data.extend(data);
+            }
+        }
+
+        let end = pos + payload.len() as u64;
+        let lag = (len - end) + remaining_after(path, &gen_list, gen)?;
+        let response = Response::Chunk {
+            gen,
+            pos,
+            len: payload.len() as u64,
+            lag,
+            key_id,
+            blobs,
+        };
+        let mut payload = payload;
+        payload.extend(blob_data);
+        return Ok((response, payload));
+    }
+}
+
+/// 序号大于 gen 的日志中记录的总字节数
+fn remaining_after(path: &Path, gen_list: &[u64], gen: u64) -> Result<u64> {
+    let mut total = 0;
+    for &g in gen_list.iter().filter(|&&g| g > gen) {
+        if let Ok(metadata) = fs::metadata(log_path(path, g)) {
+            total += metadata.len().saturating_sub(HEADER_LEN);
+        }
+    }
+    Ok(total)
+}
+
+/// follower 保存的状态
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct FollowerState {
+    #[serde(flatten)]
+    position: ReplicationPosition,
+    /// 提供读取的数据目录序号
+    #[serde(default)]
+    data: u64,
+}
+
+/// follower 从一次拉取中收到的日志与它引用的 blob
+struct Chunk {
+    payload: Vec<u8>,
+    key_id: u32,
+    blobs: HashMap<u64, Vec<u8>>,
+}
+
+/// 全量追赶时在单独目录中重建的存储
+struct Resync {
+    data: u64,
+    store: KvStore,
+    position: ReplicationPosition,
+}
+
+/// 复制的 follower 端
+/// 从 leader 拉取日志应用到本地的 KvStore，只对外提供读操作
+///
+/// 加密的 leader 需要以 `open_with_keyring` 提供它使用过的密钥，
+/// 复制的值解密之后以同一个 keyring 加密写入本地的存储。
+/// 分离存放的大值随引用它的日志一起拉取。
+///
+/// 数据保存在本地目录的 `data-N` 子目录中。需要全量追赶时在新的子目录中重建，
+/// 期间仍然以原有的数据提供读取，追上 leader 之后再切换到新的目录。
+pub struct ReplicaFollower {
+    path: PathBuf,
+    data: u64,
+    store: KvStore,
+    resync: Option<Resync>,
+    keyring: Option<Keyring>,
+    leader: SocketAddr,
+    conn: Option<(BufReader<TcpStream>, BufWriter<TcpStream>)>,
+    position: ReplicationPosition,
+    lag: Option<u64>,
+    caught_up_at: Option<Instant>,
+}
+
+impl ReplicaFollower {
+    /// 以本地目录与 leader 地址开启一个 follower
+    /// 本地目录中保存的复制进度会被继续使用，中断的全量追赶重新开始
+    pub fn open(path: impl Into<PathBuf>, leader: impl ToSocketAddrs) -> Result<ReplicaFollower> {
+        ReplicaFollower::open_with_keyring(path, leader, None)
+    }
+
+    /// 以 keyring 解密 leader 上加密的值，并加密本地的存储
+    pub fn open_with_keyring(
+        path: impl Into<PathBuf>,
+        leader: impl ToSocketAddrs,
+        keyring: Option<Keyring>,
+    ) -> Result<ReplicaFollower> {
+        let path = path.into();
+        let leader = leader
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| KvsError::Replication("no leader address".to_owned()))?;
+        let state: FollowerState = match fs::read(path.join(REPLICATION_STATE_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            // 无法读取的进度不能当作新的 follower，否则当前的数据会被当作过期的数据删除
+            Err(e) if e.kind() == io::ErrorKind::NotFound => FollowerState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        fs::create_dir_all(&path)?;
+        remove_stale_data(&path, state.data)?;
+        let store = open_store(&data_path(&path, state.data), &keyring)?;
+
+        Ok(ReplicaFollower {
+            path,
+            data: state.data,
+            store,
+            resync: None,
+            keyring,
+            leader,
+            conn: None,
+            position: state.position,
+            lag: None,
+            caught_up_at: None,
+        })
+    }
+
+    /// 读取数据
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    /// 当前提供读取的数据已复制到的 leader 日志位置
+    pub fn position(&self) -> ReplicationPosition {
+        self.position
+    }
+
+    /// 当前的复制延迟
+    pub fn lag(&self) -> ReplicationLag {
+        ReplicationLag {
+            bytes: self.lag,
+            since_caught_up: self.caught_up_at.map(|at| at.elapsed()),
+        }
+    }
+
+    /// 持续拉取直到追上 leader
+    pub fn sync(&mut self) -> Result<()> {
+        while self.sync_once()? > 0 {}
+        Ok(())
+    }
+
+    /// 拉取并应用一段日志，返回拉取后仍落后的字节数
+    pub fn sync_once(&mut self) -> Result<u64> {
+        let result = self.fetch_and_apply();
+        if result.is_err() {
+            // 连接出错后下次重新连接
+            self.conn = None;
+        }
+        result
+    }
+
+    fn fetch_and_apply(&mut self) -> Result<u64> {
+        if self.conn.is_none() {
+            let stream = TcpStream::connect(self.leader)?;
+            self.conn = Some((BufReader::new(stream.try_clone()?), BufWriter::new(stream)));
+        }
+        let (reader, writer) = self.conn.as_mut().expect("connection not established");
+
+        let position = self.resync.as_ref().map_or(self.position, |resync| resync.position);
+        let request = Request::Fetch {
+            gen: position.gen,
+            pos: position.pos,
+            max_bytes: FETCH_CHUNK_SIZE,
+        };
+        serde_json::to_writer(&mut *writer, &request)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(KvsError::Replication("leader closed connection".to_owned()));
+        }
+
+        match serde_json::from_str(&line)? {
+            Response::Reset { gen, lag } => {
+                // 本地数据可能包含 leader 已压缩掉的内容，在新的目录中从头追赶
+                self.start_resync(ReplicationPosition { gen, pos: 0 })?;
+                self.lag = Some(lag);
+                Ok(lag)
+            }
+            Response::Chunk { gen, pos, len, lag, key_id, blobs } => {
+                let mut payload = vec![0; len as usize];
+                reader.read_exact(&mut payload)?;
+                let mut chunk = Chunk { payload, key_id, blobs: HashMap::new() };
+                for (blob, bytes) in blobs {
+                    let mut data = vec![0; bytes as usize];
+                    reader.read_exact(&mut data)?;
+                    chunk.blobs.insert(blob, data);
+                }
+                let keyring = self.keyring.as_ref();
+
+                let position = ReplicationPosition { gen, pos: pos + len };
+                match &mut self.resync {
+                    Some(resync) => {
+                        apply(&mut resync.store, &chunk, keyring)?;
+                        resync.position = position;
+                        if lag == 0 {
+                            self.finish_resync()?;
+                        }
+                    }
+                    None => {
+                        apply(&mut self.store, &chunk, keyring)?;
+                        // 进度之前的写入落盘之后才保存进度
+                        self.store.sync()?;
+                        self.position = position;
+                        self.save_state(self.position, self.data)?;
+                    }
+                }
+                self.lag = Some(lag);
+                if lag == 0 {
+                    self.caught_up_at = Some(Instant::now());
+                }
+                Ok(lag)
+            }
+        }
+    }
+
+    /// 在新的数据目录中从 position 开始重建，进行中的重建重新开始
+    fn start_resync(&mut self, position: ReplicationPosition) -> Result<()> {
+        self.resync = None;
+        let data = self.data + 1;
+        let dir = data_path(&self.path, data);
+        remove_dir(&dir)?;
+        let store = open_store(&dir, &self.keyring)?;
+        self.resync = Some(Resync { data, store, position });
+        Ok(())
+    }
+
+    /// 重建的存储追上 leader 之后替换当前的存储
+    /// 保存的状态切换到新的目录之后才删除旧的目录
+    fn finish_resync(&mut self) -> Result<()> {
+        let mut resync = self.resync.take().expect("no resync in progress");
+        resync.store.sync()?;
+        self.save_state(resync.position, resync.data)?;
+
+        let old = self.data;
+        self.store = resync.store;
+        self.data = resync.data;
+        self.position = resync.position;
+        remove_dir(&data_path(&self.path, old))
+    }
+
+    fn save_state(&self, position: ReplicationPosition, data: u64) -> Result<()> {
+        let state = serde_json::to_vec(&FollowerState { position, data })?;
+        write_atomic(&self.path, REPLICATION_STATE_FILE, &state)
+    }
+}
+
+/// 以 keyring 打开 follower 本地的存储
+fn open_store(dir: &Path, keyring: &Option<Keyring>) -> Result<KvStore> {
+    let options = StoreOptions {
+        encryption: keyring.clone(),
+        ..StoreOptions::default()
+    };
+    KvStore::open_with_options(dir, options)
+}
+
+/// 将一段日志中的命令还原之后应用到存储
+fn apply(store: &mut KvStore, chunk: &Chunk, keyring: Option<&Keyring>) -> Result<()> {
+    let max_len = store.max_value_bytes();
+    let stream = Deserializer::from_slice(&chunk.payload).into_iter::<Command>();
+    for cmd in stream {
+        let cmd = cmd?;
+        kv::check_encrypted(&cmd, chunk.key_id)?;
+        match cmd {
+            Command::Set { key, value } => store.set(key, value)?,
+            Command::SetCompressed { key, value, codec } => {
+                let value = codec::decode_value(&value, codec, max_len)?;
+                store.set_with_codec(key, value, Some(codec))?
+            }
+            Command::SetEncrypted { key, value, nonce, codec } => {
+                let value = crypto::decrypt_value(keyring, chunk.key_id, &key, &nonce, &value, codec, max_len)?;
+                store.set(key, value)?
+            }
+            Command::SetBlob { key, blob, .. } => {
+                let data = chunk.blobs.get(&blob)
+                    .ok_or_else(|| KvsError::Replication(format!("leader did not send blob {}", blob)))?;
+                let (cmd, key_id) = blob::parse(blob, data)?;
+                let value = kv::decode_blob(&key, blob, cmd, keyring, key_id, max_len)?;
+                store.set(key, value)?
+            }
+            Command::Remove { key } => match store.remove(key) {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// 序号为 data 的数据目录
+fn data_path(path: &Path, data: u64) -> PathBuf {
+    path.join(format!("data-{}", data))
+}
+
+/// 删除当前数据目录以外的数据目录，例如中断的重建或替换之后没有删除的旧目录
+fn remove_stale_data(path: &Path, current: u64) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let stale = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("data-"))
+            .and_then(|data| data.parse::<u64>().ok())
+            .is_some_and(|data| data != current);
+        if stale {
+            remove_dir(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_dir(dir: &Path) -> Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
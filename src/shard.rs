@@ -0,0 +1,425 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    io,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{KvsError, Result},
+    kv::write_atomic,
+    KvStore,
+};
+
+/// 分片编号
+pub type ShardId = u64;
+
+/// 每个分片在哈希环上默认的虚拟节点数
+pub const DEFAULT_VIRTUAL_NODES: u64 = 64;
+
+/// 分片布局文件名
+const LAYOUT_FILE: &str = "shards.json";
+
+/// 一致性哈希环：虚拟节点的哈希值到分片的映射
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct Ring {
+    points: BTreeMap<u64, ShardId>,
+}
+
+impl Ring {
+    /// 键所属的分片：顺时针方向的第一个虚拟节点
+    fn owner(&self, key: &str) -> ShardId {
+        let hash = hash(key.as_bytes());
+        let (_, &shard) = self
+            .points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .expect("hash ring is empty");
+        shard
+    }
+
+    fn shards(&self) -> BTreeSet<ShardId> {
+        self.points.values().cloned().collect()
+    }
+
+    fn add(&mut self, shard: ShardId, virtual_nodes: u64) {
+        for vnode in 0..virtual_nodes {
+            let point = hash(format!("shard-{}#{}", shard, vnode).as_bytes());
+            self.points.insert(point, shard);
+        }
+    }
+}
+
+/// 持久化的分片布局
+#[derive(Serialize, Deserialize, Debug)]
+struct Layout {
+    next_id: ShardId,
+    virtual_nodes: u64,
+    ring: Ring,
+    /// 再平衡进行中时为迁移前的哈希环
+    previous: Option<Ring>,
+}
+
+/// 按键的哈希将数据分布到多个 KvStore 上
+///
+/// 每个分片是根目录下独立的 `shard-<id>` 目录。增加、拆分或移除分片后，
+/// 归属变化的键通过 `rebalance_step` 逐批迁移，迁移期间读写照常进行。
+pub struct ShardedStore {
+    path: PathBuf,
+    layout: Layout,
+    shards: HashMap<ShardId, KvStore>,
+    // 再平衡中尚未迁移的键及其所在的分片，按迁移顺序倒序排列
+    // 第一次迁移时收集，迁移开始后旧分片上不会再出现需要迁移的键
+    pending: Option<Vec<(ShardId, String)>>,
+}
+
+impl ShardedStore {
+    /// 通过根目录开启分片存储
+    /// 全新的目录以 shard_count 个分片初始化，已有目录沿用保存的布局
+    pub fn open(path: impl Into<PathBuf>, shard_count: u64) -> Result<ShardedStore> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        let layout = match fs::read(path.join(LAYOUT_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // 布局文件丢失时无法判断已有分片的归属，不能当作新建的存储清理这些分片
+                if !shard_dirs(&path)?.is_empty() {
+                    return Err(KvsError::Shard(format!(
+                        "{} contains shards but no {}",
+                        path.display(),
+                        LAYOUT_FILE
+                    )));
+                }
+                if shard_count == 0 {
+                    return Err(KvsError::Shard("at least one shard is required".to_owned()));
+                }
+                let mut ring = Ring::default();
+                for shard in 0..shard_count {
+                    ring.add(shard, DEFAULT_VIRTUAL_NODES);
+                }
+                let layout = Layout {
+                    next_id: shard_count,
+                    virtual_nodes: DEFAULT_VIRTUAL_NODES,
+                    ring,
+                    previous: None,
+                };
+                save_layout(&path, &layout)?;
+                layout
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut store = ShardedStore {
+            path,
+            layout,
+            shards: HashMap::new(),
+            pending: None,
+        };
+        store.remove_unused_shards()?;
+        store.open_shards()?;
+        Ok(store)
+    }
+
+    /// 当前哈希环上的分片
+    pub fn shard_ids(&self) -> Vec<ShardId> {
+        self.layout.ring.shards().into_iter().collect()
+    }
+
+    /// 键所属的分片
+    pub fn shard_for(&self, key: &str) -> ShardId {
+        self.layout.ring.owner(key)
+    }
+
+    /// 是否有未完成的再平衡
+    pub fn is_rebalancing(&self) -> bool {
+        self.layout.previous.is_some()
+    }
+
+    /// 存入数据
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let owner = self.layout.ring.owner(&key);
+        // 迁移中的键写入新分片后删除旧分片上的副本
+        if let Some(old) = self.previous_owner(&key, owner) {
+            ignore_not_found(self.shard(old)?.remove(key.clone()))?;
+        }
+        self.shard(owner)?.set(key, value)
+    }
+
+    /// 获取数据
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let owner = self.layout.ring.owner(&key);
+        if let Some(value) = self.shard(owner)?.get(key.clone())? {
+            return Ok(Some(value));
+        }
+        match self.previous_owner(&key, owner) {
+            Some(old) => self.shard(old)?.get(key),
+            None => Ok(None),
+        }
+    }
+
+    /// 删除数据
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let owner = self.layout.ring.owner(&key);
+        let mut found = ignore_not_found(self.shard(owner)?.remove(key.clone()))?;
+        if let Some(old) = self.previous_owner(&key, owner) {
+            found |= ignore_not_found(self.shard(old)?.remove(key))?;
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// 跨所有分片按键的顺序返回范围内的数据
+    pub fn scan<R: RangeBounds<String>>(&mut self, range: R) -> Result<Vec<(String, String)>> {
+        let range = (cloned_bound(range.start_bound()), cloned_bound(range.end_bound()));
+        // 迁移中同一个键可能短暂存在于两个分片，以新分片为准
+        let mut merged = BTreeMap::new();
+        let mut ids: Vec<ShardId> = self.shards.keys().cloned().collect();
+        ids.sort_unstable();
+        for id in ids {
+            for (key, value) in self.shard(id)?.scan(range.clone())? {
+                if self.layout.ring.owner(&key) == id || !merged.contains_key(&key) {
+                    merged.insert(key, value);
+                }
+            }
+        }
+        Ok(merged.into_iter().collect())
+    }
+
+    /// 增加一个分片，返回其编号
+    /// 新分片从每个已有分片接管一部分键
+    pub fn add_shard(&mut self) -> Result<ShardId> {
+        self.check_not_rebalancing()?;
+        let id = self.allocate_id();
+        let mut ring = self.layout.ring.clone();
+        ring.add(id, self.layout.virtual_nodes);
+        self.begin_rebalance(ring)?;
+        Ok(id)
+    }
+
+    /// 拆分分片，返回新分片的编号
+    /// 新分片接管原分片一半的虚拟节点
+    pub fn split_shard(&mut self, shard: ShardId) -> Result<ShardId> {
+        self.check_not_rebalancing()?;
+        self.check_shard(shard)?;
+        let id = self.allocate_id();
+        let mut ring = self.layout.ring.clone();
+        for (i, owner) in ring.points.values_mut().filter(|owner| **owner == shard).enumerate() {
+            if i % 2 == 1 {
+                *owner = id;
+            }
+        }
+        if !ring.shards().contains(&id) {
+            return Err(KvsError::Shard(format!("shard {} has too few virtual nodes to split", shard)));
+        }
+        self.begin_rebalance(ring)?;
+        Ok(id)
+    }
+
+    /// 移除分片，其数据迁移到哈希环上的后继分片
+    /// 迁移完成后分片目录被删除
+    pub fn remove_shard(&mut self, shard: ShardId) -> Result<()> {
+        self.check_not_rebalancing()?;
+        self.check_shard(shard)?;
+        if self.layout.ring.shards().len() == 1 {
+            return Err(KvsError::Shard("cannot remove the last shard".to_owned()));
+        }
+        let mut ring = self.layout.ring.clone();
+        ring.points.retain(|_, owner| *owner != shard);
+        self.begin_rebalance(ring)
+    }
+
+    /// 迁移至多 max_keys 个归属发生变化的键
+    /// 返回本次迁移的键数，迁移全部完成时返回 0 并结束再平衡
+    pub fn rebalance_step(&mut self, max_keys: usize) -> Result<usize> {
+        let previous = match &self.layout.previous {
+            Some(previous) => previous.clone(),
+            None => return Ok(0),
+        };
+
+        if self.pending.is_none() {
+            let mut pending = Vec::new();
+            let ring = self.layout.ring.clone();
+            for id in previous.shards() {
+                self.shard(id)?.for_each_key(|key| {
+                    if ring.owner(key) != id {
+                        pending.push((id, key.to_owned()));
+                    }
+                })?;
+            }
+            pending.sort_unstable_by(|a, b| b.cmp(a));
+            self.pending = Some(pending);
+        }
+
+        let mut moved = 0;
+        while moved < max_keys {
+            let (id, key) = match self.pending.as_mut().and_then(Vec::pop) {
+                Some(next) => next,
+                None => break,
+            };
+            let owner = self.layout.ring.owner(&key);
+            if let Some(value) = self.shard(id)?.get(key.clone())? {
+                // 新分片上已有值说明迁移开始后写入过新值，以新值为准
+                if self.shard(owner)?.get(key.clone())?.is_none() {
+                    self.shard(owner)?.set(key.clone(), value)?;
+                }
+                self.shard(id)?.remove(key)?;
+            }
+            moved += 1;
+        }
+
+        if moved == 0 {
+            self.finish_rebalance()?;
+        }
+        Ok(moved)
+    }
+
+    /// 迁移所有剩余的键
+    pub fn rebalance(&mut self) -> Result<()> {
+        while self.rebalance_step(1024)? > 0 {}
+        Ok(())
+    }
+
+    fn begin_rebalance(&mut self, ring: Ring) -> Result<()> {
+        let previous = std::mem::replace(&mut self.layout.ring, ring);
+        self.layout.previous = Some(previous);
+        save_layout(&self.path, &self.layout)?;
+        self.open_shards()
+    }
+
+    fn finish_rebalance(&mut self) -> Result<()> {
+        self.layout.previous = None;
+        self.pending = None;
+        save_layout(&self.path, &self.layout)?;
+        // 布局保存之后删除不再位于哈希环上的分片，中断时在下次打开时删除
+        self.remove_unused_shards()
+    }
+
+    /// 删除布局中没有引用的分片目录
+    fn remove_unused_shards(&mut self) -> Result<()> {
+        let mut used = self.layout.ring.shards();
+        if let Some(previous) = &self.layout.previous {
+            used.extend(previous.shards());
+        }
+        self.shards.retain(|id, _| used.contains(id));
+        for (id, path) in shard_dirs(&self.path)? {
+            if !used.contains(&id) {
+                fs::remove_dir_all(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 键在再平衡之前所属的分片，与当前分片不同时返回
+    fn previous_owner(&self, key: &str, owner: ShardId) -> Option<ShardId> {
+        self.layout
+            .previous
+            .as_ref()
+            .map(|previous| previous.owner(key))
+            .filter(|&old| old != owner)
+    }
+
+    fn open_shards(&mut self) -> Result<()> {
+        let mut ids = self.layout.ring.shards();
+        if let Some(previous) = &self.layout.previous {
+            ids.extend(previous.shards());
+        }
+        for id in ids {
+            if !self.shards.contains_key(&id) {
+                self.shards.insert(id, KvStore::open(shard_path(&self.path, id))?);
+            }
+        }
+        Ok(())
+    }
+
+    fn shard(&mut self, id: ShardId) -> Result<&mut KvStore> {
+        self.shards
+            .get_mut(&id)
+            .ok_or_else(|| KvsError::Shard(format!("shard {} is not open", id)))
+    }
+
+    fn allocate_id(&mut self) -> ShardId {
+        let id = self.layout.next_id;
+        self.layout.next_id += 1;
+        id
+    }
+
+    fn check_shard(&self, shard: ShardId) -> Result<()> {
+        if self.layout.ring.shards().contains(&shard) {
+            Ok(())
+        } else {
+            Err(KvsError::Shard(format!("shard {} does not exist", shard)))
+        }
+    }
+
+    fn check_not_rebalancing(&self) -> Result<()> {
+        if self.is_rebalancing() {
+            Err(KvsError::Shard("rebalance in progress".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn shard_path(root: &Path, id: ShardId) -> PathBuf {
+    root.join(format!("shard-{}", id))
+}
+
+/// 根目录中的分片目录
+fn shard_dirs(root: &Path) -> Result<Vec<(ShardId, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let id = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("shard-"))
+            .and_then(|id| id.parse::<ShardId>().ok());
+        if let Some(id) = id.filter(|_| entry.path().is_dir()) {
+            dirs.push((id, entry.path()));
+        }
+    }
+    Ok(dirs)
+}
+
+fn save_layout(root: &Path, layout: &Layout) -> Result<()> {
+    write_atomic(root, LAYOUT_FILE, &serde_json::to_vec(layout)?)
+}
+
+/// 删除不存在的键不视为错误，返回键是否存在
+fn ignore_not_found(result: Result<()>) -> Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(KvsError::KeyNotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn cloned_bound(bound: Bound<&String>) -> Bound<String> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// FNV-1a 加上 splitmix64 的混合，保证跨进程、跨版本稳定
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::OsStr,
+    fmt,
+    fs::{self, File},
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use serde_json::Deserializer;
+
+use crate::{
+    blob::{self, BLOB_DIR},
+    codec,
+    diskindex::INDEX_DIR,
+    error::Result,
+    format::{LogHeader, HEADER_LEN},
+    kv::{check_encrypted, log_path, sorted_gen_list, Command},
+    manifest::{Manifest, MANIFEST_FILE},
+    repair::REPAIR_REPORT_FILE,
+};
+
+/// 问题的严重程度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// 不影响数据，例如遗留的临时文件
+    Warning,
+    /// 数据损坏或不一致
+    Error,
+}
+
+/// 检查中发现的问题
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    /// 问题所在的日志
+    pub gen: Option<u64>,
+    /// 问题在日志中的偏移
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: ", severity)?;
+        match (self.gen, self.offset) {
+            (Some(gen), Some(offset)) => write!(f, "{}.log@{}: ", gen, offset)?,
+            (Some(gen), None) => write!(f, "{}.log: ", gen)?,
+            _ => {}
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// 单个日志的统计
+#[derive(Debug, Clone, Default)]
+pub struct GenReport {
+    pub gen: u64,
+    /// 文件大小
+    pub bytes: u64,
+    /// 仍被索引引用的字节数
+    pub live_bytes: u64,
+    pub sets: u64,
+    pub removes: u64,
+}
+
+impl GenReport {
+    /// 可被压缩回收的字节比例
+    pub fn dead_ratio(&self) -> f64 {
+        if self.bytes == 0 {
+            0.0
+        } else {
+            (self.bytes - self.live_bytes) as f64 / self.bytes as f64
+        }
+    }
+}
+
+/// 存储目录的检查结果
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub gens: Vec<GenReport>,
+    /// 重建的索引中的键数
+    pub keys: u64,
+    pub issues: Vec<Issue>,
+}
+
+impl VerifyReport {
+    /// 没有错误级别的问题
+    pub fn is_ok(&self) -> bool {
+        self.issues.iter().all(|issue| issue.severity != Severity::Error)
+    }
+}
+
+/// 位置以及记录所在的日志
+#[derive(Clone, Copy)]
+struct RecordPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+}
+
+/// 离线检查存储目录，不修改任何文件
+///
+/// 依次校验每个日志中的记录，按 `KvStore::open` 的方式重建索引，
+/// 再逐条回读索引指向的记录确认一致，并统计每个日志的无效字节比例。
+/// 压缩的值按默认的最大值大小 `DEFAULT_MAX_VALUE_BYTES` 检查。
+pub fn verify(path: impl AsRef<Path>) -> Result<VerifyReport> {
+    let path = path.as_ref();
+    let mut report = VerifyReport::default();
+    let manifest = Manifest::load(path)?;
+    let gen_list = match &manifest {
+        Some(manifest) => manifest.gens.clone(),
+        None => sorted_gen_list(path)?,
+    };
+    if manifest.as_ref().is_some_and(|manifest| manifest.compaction.is_some() || !manifest.obsolete.is_empty()) {
+        report.issues.push(Issue {
+            severity: Severity::Warning,
+            gen: None,
+            offset: None,
+            message: "interrupted compaction, leftovers are removed on next open".to_owned(),
+        });
+    }
+    let mut index = HashMap::<String, RecordPos>::new();
+
+    for &gen in &gen_list {
+        let bytes = match fs::metadata(log_path(path, gen)) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => {
+                report.issues.push(Issue {
+                    severity: Severity::Error,
+                    gen: Some(gen),
+                    offset: None,
+                    message: "listed in manifest but missing".to_owned(),
+                });
+                continue;
+            }
+        };
+        let mut gen_report = GenReport {
+            gen,
+            bytes,
+            ..GenReport::default()
+        };
+        let mut reader = BufReader::new(File::open(log_path(path, gen))?);
+        let key_id = match LogHeader::read_from(&mut reader, gen) {
+            Ok(header) => header.key_id,
+            Err(e) => {
+                report.issues.push(Issue {
+                    severity: Severity::Error,
+                    gen: Some(gen),
+                    offset: Some(0),
+                    message: e.to_string(),
+                });
+                report.gens.push(gen_report);
+                continue;
+            }
+        };
+        let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+        let mut pos = HEADER_LEN;
+        while let Some(cmd) = stream.next() {
+            let new_pos = HEADER_LEN + stream.byte_offset() as u64;
+            // 加密的日志中出现明文的值
+            if let Some(Err(e)) = cmd.as_ref().ok().map(|cmd| check_encrypted(cmd, key_id)) {
+                report.issues.push(Issue {
+                    severity: Severity::Error,
+                    gen: Some(gen),
+                    offset: Some(pos),
+                    message: e.to_string(),
+                });
+            }
+            match cmd {
+                // 没有密钥时无法校验加密的值，只检查记录的结构
+                // blob 只检查索引中仍然引用的部分
+                Ok(Command::Set { key, .. } | Command::SetEncrypted { key, .. } | Command::SetBlob { key, .. }) => {
+                    gen_report.sets += 1;
+                    index.insert(key, RecordPos { gen, pos, len: new_pos - pos });
+                }
+                Ok(Command::SetCompressed { key, value, codec }) => {
+                    gen_report.sets += 1;
+                    if let Err(e) = codec::decode_value(&value, codec, codec::DEFAULT_MAX_VALUE_BYTES) {
+                        report.issues.push(Issue {
+                            severity: Severity::Error,
+                            gen: Some(gen),
+                            offset: Some(pos),
+                            message: format!("value of {:?} cannot be decompressed: {}", key, e),
+                        });
+                    }
+                    index.insert(key, RecordPos { gen, pos, len: new_pos - pos });
+                }
+                Ok(Command::Remove { key }) => {
+                    // 压缩会保留更早日志中可能仍有旧值的删除标记，键不存在并不是错误
+                    gen_report.removes += 1;
+                    index.remove(&key);
+                }
+                Err(e) => {
+                    // 日志无法继续解析，之后的内容都视为损坏
+                    let kind = if e.is_eof() { "truncated record" } else { "corrupt record" };
+                    report.issues.push(Issue {
+                        severity: Severity::Error,
+                        gen: Some(gen),
+                        offset: Some(pos),
+                        message: format!("{}: {} ({} bytes unreadable)", kind, e, gen_report.bytes - pos),
+                    });
+                    break;
+                }
+            }
+            pos = new_pos;
+        }
+        report.gens.push(gen_report);
+    }
+
+    check_index(path, &index, &mut report)?;
+    check_files(path, &gen_list, &mut report)?;
+    report.keys = index.len() as u64;
+    Ok(report)
+}
+
+/// 回读索引指向的每条记录，确认是对应键的 Set 命令，引用的 blob 存在并且属于该键
+fn check_index(path: &Path, index: &HashMap<String, RecordPos>, report: &mut VerifyReport) -> Result<()> {
+    let mut files = HashMap::new();
+    for (key, record) in index {
+        let file = match files.entry(record.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(File::open(log_path(path, record.gen))?),
+        };
+        file.seek(SeekFrom::Start(record.pos))?;
+        let mut buf = vec![0; record.len as usize];
+        file.read_exact(&mut buf)?;
+
+        let problem = match serde_json::from_slice::<Command>(&buf) {
+            Ok(
+                Command::Set { key: found, .. }
+                | Command::SetCompressed { key: found, .. }
+                | Command::SetEncrypted { key: found, .. },
+            ) if &found == key => None,
+            Ok(Command::SetBlob { key: found, blob, .. }) if &found == key => {
+                match blob::read(&path.join(BLOB_DIR), blob) {
+                    Ok((
+                        Command::Set { key: found, .. }
+                        | Command::SetCompressed { key: found, .. }
+                        | Command::SetEncrypted { key: found, .. },
+                        _,
+                    )) if &found == key => None,
+                    Ok(_) => Some(format!("blob {} does not hold the value of {:?}", blob, key)),
+                    Err(e) => Some(format!("value of {:?} is unreadable: {}", key, e)),
+                }
+            }
+            _ => Some(format!("index entry for {:?} does not point at its set record", key)),
+        };
+        match problem {
+            None => {
+                let gen_report = report
+                    .gens
+                    .iter_mut()
+                    .find(|gen_report| gen_report.gen == record.gen)
+                    .expect("indexed generation has a report");
+                gen_report.live_bytes += record.len;
+            }
+            Some(message) => report.issues.push(Issue {
+                severity: Severity::Error,
+                gen: Some(record.gen),
+                offset: Some(record.pos),
+                message,
+            }),
+        }
+    }
+    Ok(())
+}
+
+/// 目录中不属于存储的文件
+fn check_files(path: &Path, gen_list: &[u64], report: &mut VerifyReport) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        let name = entry_path.file_name().and_then(OsStr::to_str).unwrap_or_default().to_owned();
+        let is_gen = name
+            .strip_suffix(".log")
+            .and_then(|stem| stem.parse::<u64>().ok())
+            .is_some_and(|gen| gen_list.contains(&gen) && log_path(path, gen) == entry_path);
+        let known = [MANIFEST_FILE, REPAIR_REPORT_FILE, INDEX_DIR, BLOB_DIR];
+        if is_gen || known.contains(&name.as_str()) {
+            continue;
+        }
+        let message = if name.ends_with(".tmp") {
+            format!("orphan temporary file {}", name)
+        } else {
+            format!("unexpected file {}", name)
+        };
+        report.issues.push(Issue {
+            severity: Severity::Warning,
+            gen: None,
+            offset: None,
+            message,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use assert_cmd::prelude::*;
+use key_value_db::backup::{self, RestorePoint};
+use key_value_db::{KvStore, Result};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+// 检查点应该可以直接打开，并且不受之后写入和压缩的影响
+#[test]
+fn checkpoint_is_consistent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("checkpoint");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.checkpoint(&dest)?;
+
+    // 检查点之后的写入与压缩
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+
+    let mut copy = KvStore::open(&dest)?;
+    assert_eq!(copy.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(copy.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // 副本的写入不影响原存储
+    copy.set("key1".to_owned(), "copy".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// 目标目录已有日志文件时应该拒绝
+#[test]
+fn checkpoint_into_existing_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(KvStore::open(other_dir.path())?);
+    assert!(store.checkpoint(other_dir.path()).is_err());
+    Ok(())
+}
+
+// 在同一目录重新创建的存储会重新使用日志序号，增量备份应该把它们视为新的日志
+#[test]
+fn backup_recreated_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repo_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("old".to_owned(), "a much longer value than the new one".to_owned())?;
+    backup::create_backup(&mut store, repo_dir.path())?;
+    drop(store);
+
+    // 文件头中的创建时间以秒为单位
+    std::fs::remove_dir_all(temp_dir.path())?;
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+    let manifest = backup::create_backup(&mut store, repo_dir.path())?;
+    assert!(manifest.parts.iter().any(|part| part.gen == 1 && part.start == 0));
+    drop(store);
+
+    backup::restore(repo_dir.path(), restore_dir.path(), RestorePoint::Latest)?;
+    let mut restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("old".to_owned())?, None);
+    assert_eq!(restored.get("new".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// 增量备份只保存新增的日志内容，可以恢复到任意一次备份
+#[test]
+fn incremental_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repo_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = backup::create_backup(&mut store, repo_dir.path())?;
+    assert_eq!(first.parent, None);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let second = backup::create_backup(&mut store, repo_dir.path())?;
+    assert_eq!(second.parent, Some(first.id));
+    // 只保存了当前日志新增的尾部
+    assert_eq!(second.parts.len(), 1);
+    assert_eq!(second.parts[0].start, first.parts[0].end);
+
+    // 压缩之后只保存新的日志
+    store.compact()?;
+    store.remove("key2".to_owned())?;
+    let third = backup::create_backup(&mut store, repo_dir.path())?;
+    assert!(third.parts.iter().all(|part| part.start == 0));
+    assert_eq!(backup::list_backups(repo_dir.path())?.len(), 3);
+
+    let restore = |point, expected: &[(&str, Option<&str>)]| -> Result<()> {
+        let dest = TempDir::new().expect("unable to create temporary working directory");
+        backup::restore(repo_dir.path(), dest.path(), point)?;
+        let mut restored = KvStore::open(dest.path())?;
+        for (key, value) in expected {
+            assert_eq!(restored.get(key.to_string())?, value.map(str::to_owned));
+        }
+        Ok(())
+    };
+    restore(RestorePoint::Backup(1), &[("key1", Some("value1")), ("key2", None)])?;
+    restore(RestorePoint::Backup(2), &[("key1", Some("value2")), ("key2", Some("value2"))])?;
+    restore(RestorePoint::Latest, &[("key1", Some("value2")), ("key2", None)])?;
+
+    // 恢复到第二次备份中间的位置，不在命令边界上的位置退回到完整的命令
+    let middle = second.parts[0].start + 1;
+    restore(
+        RestorePoint::Position { gen: second.parts[0].gen, offset: middle },
+        &[("key1", Some("value1")), ("key2", None)],
+    )?;
+    Ok(())
+}
+
+// 应该在部分压缩之后按位置恢复出该位置之前写入的全部数据
+// 压缩结果的序号大于之后写入的日志，其中的记录却写入得更早
+#[test]
+fn restore_position_across_partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repo_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("g".to_owned(), "1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    // 只压缩第一个日志，压缩结果的序号为 3，之后写入第 4 个日志
+    store.compact_gens(&[1])?;
+    store.set("c".to_owned(), "4".to_owned())?;
+    backup::create_backup(&mut store, repo_dir.path())?;
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(temp_dir.path().join("3.log").exists());
+
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    let offset = fs::metadata(temp_dir.path().join("2.log"))?.len();
+    backup::restore(repo_dir.path(), dest.path(), RestorePoint::Position { gen: 2, offset })?;
+    let mut restored = KvStore::open(dest.path())?;
+    assert_eq!(restored.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(restored.get("g".to_owned())?, Some("1".to_owned()));
+    assert_eq!(restored.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(restored.get("c".to_owned())?, None);
+
+    // 恢复到压缩结果中间的位置，之后写入的日志都不恢复
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    let offset = fs::metadata(temp_dir.path().join("3.log"))?.len() - 1;
+    backup::restore(repo_dir.path(), dest.path(), RestorePoint::Position { gen: 3, offset })?;
+    let mut restored = KvStore::open(dest.path())?;
+    assert_eq!(restored.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(restored.get("g".to_owned())?, None);
+    assert_eq!(restored.get("b".to_owned())?, None);
+    assert_eq!(restored.get("c".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn cli_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repo_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", repo_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Backup 1"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", repo_dir.path().to_str().unwrap(), dest_dir.path().to_str().unwrap(), "--backup", "1"])
+        .assert()
+        .success();
+
+    let mut restored = KvStore::open(dest_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
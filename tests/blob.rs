@@ -0,0 +1,203 @@
+use key_value_db::backup::{self, RestorePoint};
+use key_value_db::blob::BlobStats;
+use key_value_db::crypto::{EncryptionKey, Keyring};
+use key_value_db::verify::{self, Severity};
+use key_value_db::{repair, KvStore, Result, StoreOptions};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+mod common;
+
+const LARGE: usize = 256 * 1024;
+
+fn options() -> StoreOptions {
+    StoreOptions {
+        blob_threshold: 1024,
+        ..common::options()
+    }
+}
+
+fn large(c: char) -> String {
+    c.to_string().repeat(LARGE)
+}
+
+/// 目录中所有日志的字节数
+fn log_bytes(path: &Path) -> Result<u64> {
+    let mut bytes = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.path().extension() == Some("log".as_ref()) {
+            bytes += entry.metadata()?.len();
+        }
+    }
+    Ok(bytes)
+}
+
+fn blob_files(path: &Path) -> Result<usize> {
+    match fs::read_dir(path.join("blobs")) {
+        Ok(entries) => Ok(entries.count()),
+        Err(_) => Ok(0),
+    }
+}
+
+// 大值应该存入 blob，压缩只移动日志中的引用
+#[test]
+fn large_values_live_in_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("big".to_owned(), large('a'))?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(log_bytes(temp_dir.path())? < 4096);
+    assert_eq!(blob_files(temp_dir.path())?, 1);
+
+    store.compact()?;
+    // 压缩之后大值仍然不在日志中
+    assert!(log_bytes(temp_dir.path())? < 4096);
+    assert_eq!(store.get("big".to_owned())?, Some(large('a')));
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("big".to_owned())?, Some(large('a')));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.blob_stats().live_blobs, 1);
+
+    // 不分离 blob 的存储同样可以读取已有的 blob
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(large('a')));
+    Ok(())
+}
+
+// 被覆盖或删除的 blob 应该在回收之后删除，重新打开之后仍然正确
+#[test]
+fn blob_garbage_collection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("big1".to_owned(), large('a'))?;
+    store.set("big1".to_owned(), large('b'))?;
+    store.set("big2".to_owned(), large('c'))?;
+    store.remove("big2".to_owned())?;
+    store.set("big3".to_owned(), large('d'))?;
+    // 大值改为小值同样释放 blob
+    store.set("big3".to_owned(), "small".to_owned())?;
+
+    let stats = store.blob_stats();
+    assert_eq!(stats.live_blobs, 1);
+    assert_eq!(stats.dead_blobs, 3);
+    assert_eq!(blob_files(temp_dir.path())?, 4);
+    drop(store);
+
+    // 回收之前重新打开，旧的记录仍然可以重放
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.blob_stats().dead_blobs, 3);
+    let freed = store.collect_blobs()?;
+    assert_eq!(freed, stats.dead_bytes);
+    assert_eq!(store.blob_stats(), BlobStats { dead_blobs: 0, dead_bytes: 0, ..stats });
+    assert_eq!(blob_files(temp_dir.path())?, 1);
+    drop(store);
+
+    // 没有被引用的 blob 在打开时删除
+    fs::write(temp_dir.path().join("blobs").join("12345.blob"), "orphan")?;
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(blob_files(temp_dir.path())?, 1);
+    assert_eq!(store.get("big1".to_owned())?, Some(large('b')));
+    assert_eq!(store.get("big2".to_owned())?, None);
+    assert_eq!(store.get("big3".to_owned())?, Some("small".to_owned()));
+    Ok(())
+}
+
+// 开启自动压缩时等待回收的 blob 超过阈值后应该自动回收
+#[test]
+fn automatic_blob_collection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions {
+        auto_compaction: true,
+        blob_gc_bytes: 3 * LARGE as u64,
+        ..options()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for c in ['a', 'b', 'c', 'd', 'e', 'f', 'g'] {
+        store.set("big".to_owned(), large(c))?;
+        assert!(store.blob_stats().dead_bytes < 3 * LARGE as u64);
+        assert!(blob_files(temp_dir.path())? <= 4);
+    }
+    assert_eq!(store.get("big".to_owned())?, Some(large('g')));
+    Ok(())
+}
+
+// 加密存储中的 blob 同样应该加密，轮换密钥之后压缩应该重写 blob
+#[test]
+fn encrypted_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key1 = EncryptionKey::new(1, [1; 32])?;
+    let key2 = EncryptionKey::new(2, [2; 32])?;
+    let mut store = KvStore::open_with_options(
+        temp_dir.path(),
+        StoreOptions { encryption: Some(Keyring::new(key1.clone())), ..options() },
+    )?;
+    store.set("big".to_owned(), large('a'))?;
+    drop(store);
+    let blob = fs::read_dir(temp_dir.path().join("blobs"))?.next().unwrap()?.path();
+    // 密文的 base64 中偶尔会出现短的重复字符，以更长的明文片段判断
+    assert!(!String::from_utf8_lossy(&fs::read(&blob)?).contains(&large('a')[..64]));
+
+    let keyring = Keyring::new(key2.clone()).with_previous(key1);
+    let mut store = KvStore::open_with_options(temp_dir.path(), StoreOptions { encryption: Some(keyring), ..options() })?;
+    store.compact()?;
+    assert!(!blob.exists());
+    drop(store);
+
+    let mut store =
+        KvStore::open_with_options(temp_dir.path(), StoreOptions { encryption: Some(Keyring::new(key2)), ..options() })?;
+    assert_eq!(store.get("big".to_owned())?, Some(large('a')));
+    Ok(())
+}
+
+// 检查点、备份与修复应该带上 blob，缺失的 blob 应该被校验发现
+#[test]
+fn blobs_in_copies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repo_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("big1".to_owned(), large('a'))?;
+    backup::create_backup(&mut store, repo_dir.path())?;
+    store.set("big2".to_owned(), large('b'))?;
+    let second = backup::create_backup(&mut store, repo_dir.path())?;
+    assert_eq!(second.blobs.len(), 2);
+    store.checkpoint(copy_dir.path().join("checkpoint"))?;
+    drop(store);
+
+    let restored = copy_dir.path().join("restored");
+    backup::restore(repo_dir.path(), &restored, RestorePoint::Latest)?;
+    for dir in [copy_dir.path().join("checkpoint"), restored] {
+        let mut copy = KvStore::open(&dir)?;
+        assert_eq!(copy.get("big1".to_owned())?, Some(large('a')));
+        assert_eq!(copy.get("big2".to_owned())?, Some(large('b')));
+    }
+
+    // 删除一个 blob 之后校验报告错误，修复时该记录丢失
+    assert!(verify::verify(temp_dir.path())?.issues.is_empty());
+    let first_blob = fs::read_dir(temp_dir.path().join("blobs"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?
+        .into_iter()
+        .min()
+        .unwrap();
+    fs::remove_file(first_blob)?;
+    let report = verify::verify(temp_dir.path())?;
+    assert!(report.issues.iter().any(|issue| issue.severity == Severity::Error && issue.message.contains("big1")));
+
+    let repaired = copy_dir.path().join("repaired");
+    let report = repair::repair(temp_dir.path(), &repaired)?;
+    assert_eq!(report.recovered_records, 1);
+    assert_eq!(report.lost.len(), 1);
+    let mut repaired = KvStore::open(&repaired)?;
+    assert_eq!(repaired.get("big1".to_owned())?, None);
+    assert_eq!(repaired.get("big2".to_owned())?, Some(large('b')));
+    Ok(())
+}
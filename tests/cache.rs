@@ -0,0 +1,93 @@
+use key_value_db::cache::CacheStats;
+use key_value_db::{DataFormat, KvStore, Result, StoreOptions};
+use tempfile::TempDir;
+
+mod common;
+
+fn options(value_cache_bytes: usize) -> StoreOptions {
+    StoreOptions {
+        value_cache_bytes,
+        ..common::options()
+    }
+}
+
+// 重复读取应该命中缓存，写入、删除与导入之后应该读到新的值
+#[test]
+fn cache_hits_and_invalidation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(1024))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    for _ in 0..3 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert_eq!(store.cache_stats().hits, 2);
+    assert_eq!(store.cache_stats().misses, 1);
+
+    store.set("key1".to_owned(), "updated".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // 压缩之后缓存的值仍然正确
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("updated".to_owned()));
+
+    let data = "{\"key\":\"key1\",\"value\":\"imported\"}\n";
+    store.import(data.as_bytes(), DataFormat::JsonLines, true)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("imported".to_owned()));
+
+    assert_eq!(
+        store.cache_stats(),
+        CacheStats {
+            hits: 3,
+            misses: 5,
+            entries: 1,
+            bytes: "key1imported".len(),
+            capacity: 1024,
+        }
+    );
+    Ok(())
+}
+
+// 缓存不应该超过容量，经常读取的键应该留在缓存中
+#[test]
+fn cache_is_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(1000))?;
+    store.set("hot".to_owned(), "h".repeat(50))?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), "v".repeat(50))?;
+    }
+    // 超过容量的值不缓存
+    store.set("big".to_owned(), "b".repeat(2000))?;
+
+    for i in 0..100 {
+        assert!(store.get("hot".to_owned())?.is_some());
+        assert!(store.get(format!("key{:03}", i))?.is_some());
+        assert!(store.cache_stats().bytes <= 1000);
+    }
+    assert_eq!(store.get("big".to_owned())?.map(|value| value.len()), Some(2000));
+    assert_eq!(store.get("big".to_owned())?.map(|value| value.len()), Some(2000));
+
+    let stats = store.cache_stats();
+    // 除了第一次读取之外 hot 都命中缓存
+    assert_eq!(stats.hits, 99);
+    assert_eq!(stats.misses, 103);
+    assert!(stats.entries < 20);
+    Ok(())
+}
+
+// 关闭缓存时不应该记录命中统计
+#[test]
+fn cache_disabled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.cache_stats(), CacheStats::default());
+    Ok(())
+}
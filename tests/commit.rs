@@ -0,0 +1,86 @@
+use key_value_db::commit::{GroupCommitOptions, GroupCommitStore};
+use key_value_db::{KvStore, KvsError, Result, StoreOptions};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// 并发的写入应该合并落盘，返回之后都可以读到，重新打开之后仍然存在
+#[test]
+fn concurrent_writers_share_syncs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let commit = GroupCommitOptions {
+        max_batch_delay: Duration::from_millis(2),
+        ..GroupCommitOptions::default()
+    };
+    let store = GroupCommitStore::open(temp_dir.path(), StoreOptions::default(), commit)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|writer| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    store.set(format!("key{}-{}", writer, i), format!("value{}", i))?;
+                }
+                store.remove(format!("key{}-0", writer))
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("writer panicked")?;
+    }
+
+    let metrics = store.metrics();
+    assert_eq!(metrics.writes, 8 * 51);
+    // 多个写入共用一次落盘
+    assert!(metrics.syncs < metrics.writes, "{:?}", metrics);
+    assert!(metrics.max_batch > 1);
+    assert!(metrics.mean_batch() > 1.0);
+    assert!(metrics.max_latency >= metrics.mean_latency());
+    assert!(metrics.throughput() > 0.0);
+    assert_eq!(store.get("key3-7".to_owned())?, Some("value7".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for writer in 0..8 {
+        assert_eq!(store.get(format!("key{}-0", writer))?, None);
+        assert_eq!(store.get(format!("key{}-49", writer))?, Some("value49".to_owned()));
+    }
+    Ok(())
+}
+
+// leader 应该最多等待 max_batch_delay，批次满了之后不再等待
+#[test]
+fn batch_delay_and_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let commit = GroupCommitOptions {
+        max_batch_delay: Duration::from_millis(50),
+        ..GroupCommitOptions::default()
+    };
+    let store = GroupCommitStore::open(temp_dir.path().join("delayed"), StoreOptions::default(), commit)?;
+    let start = Instant::now();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(store.metrics().max_latency >= Duration::from_millis(50));
+
+    let commit = GroupCommitOptions {
+        max_batch_delay: Duration::from_secs(10),
+        max_batch_size: 1,
+    };
+    let store = GroupCommitStore::open(temp_dir.path().join("full"), StoreOptions::default(), commit)?;
+    let start = Instant::now();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(store.metrics().syncs, 1);
+    Ok(())
+}
+
+// 失败的写入不应该等待落盘，也不计入统计
+#[test]
+fn failed_write_is_not_committed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = GroupCommitStore::open(temp_dir.path(), StoreOptions::default(), GroupCommitOptions::default())?;
+    assert!(matches!(store.remove("missing".to_owned()), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.metrics().writes, 0);
+    assert_eq!(store.metrics().syncs, 0);
+    Ok(())
+}
@@ -0,0 +1,26 @@
+//! 集成测试共用的辅助函数，每个测试只用到其中的一部分
+#![allow(dead_code)]
+
+use key_value_db::{Result, StoreOptions};
+use std::fs;
+use std::path::Path;
+
+/// 关闭自动压缩的参数，由测试自己决定何时压缩
+pub fn options() -> StoreOptions {
+    StoreOptions {
+        auto_compaction: false,
+        ..StoreOptions::default()
+    }
+}
+
+/// 目录中所有日志的内容
+pub fn read_logs(path: &Path) -> Result<String> {
+    let mut data = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            data.extend(fs::read(path)?);
+        }
+    }
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
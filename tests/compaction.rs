@@ -0,0 +1,324 @@
+use assert_cmd::prelude::*;
+use key_value_db::compaction::{CompactionPolicy, CompactionScheduler, SizeRatioPolicy, TimeWindowPolicy};
+use key_value_db::inspect::{self, Operation};
+use key_value_db::kv::GenStats;
+use key_value_db::{verify, KvStore, Result, StoreOptions};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+/// 压缩过程中的每个崩溃点
+#[cfg(feature = "crash-test")]
+const CRASH_POINTS: [&str; 5] = [
+    "compaction-started",
+    "compaction-written",
+    "compaction-renamed",
+    "compaction-committed",
+    "compaction-stale-removed",
+];
+
+/// 写入分布在多个日志中的覆盖与删除
+fn fill_store(dir: &TempDir) -> Result<()> {
+    for round in 0..3 {
+        let mut store = KvStore::open(dir.path())?;
+        for i in round..20 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        store.remove(format!("key{}", round))?;
+    }
+    Ok(())
+}
+
+fn check_store(dir: &TempDir) -> Result<()> {
+    let mut store = KvStore::open(dir.path())?;
+    for i in 0..20 {
+        let expected = if i < 3 { None } else { Some(format!("value{}-2", i)) };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+    drop(store);
+
+    let report = verify::verify(dir.path())?;
+    assert!(report.issues.is_empty(), "{:?}", report.issues);
+    let leftovers: Vec<_> = fs::read_dir(dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".tmp"))
+        .collect();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+    Ok(())
+}
+
+// 在压缩的任何一步崩溃后，重新打开都应该得到压缩前的数据，不应该留下多余的文件
+// 崩溃点需要以 `cargo test --features crash-test` 编译
+#[cfg(feature = "crash-test")]
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    for point in CRASH_POINTS {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fill_store(&temp_dir)?;
+
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["compact"])
+            .env("KVS_CRASH_AT", point)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+
+        check_store(&temp_dir)?;
+    }
+    Ok(())
+}
+
+// 压缩在写入切换到新日志之后失败时，之后确认的写入应该在重新打开后保留
+#[cfg(feature = "crash-test")]
+#[test]
+fn writes_survive_failed_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill_store(&temp_dir)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    key_value_db::kv::fail_at("compaction-writer-switched");
+    assert!(store.compact().is_err());
+    store.set("key0".to_owned(), "after".to_owned())?;
+    store.remove("key3".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    for i in 4..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}-2", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill_store(&temp_dir)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    check_store(&temp_dir)?;
+    Ok(())
+}
+
+// 只压缩部分日志时应该保留删除标记，直到更早的日志也被压缩
+#[test]
+fn partial_compaction_keeps_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let removes = |dir: &TempDir| -> Result<usize> {
+        let mut count = 0;
+        inspect::dump(dir.path(), None, 0, |record| {
+            if record.op == Operation::Remove {
+                count += 1;
+            }
+            Ok(())
+        })?;
+        Ok(count)
+    };
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+
+    // 删除标记所在的日志之前还有保存旧值的日志 1
+    store.compact_gens(&[2])?;
+    drop(store);
+    assert_eq!(removes(&temp_dir)?, 1);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.compact()?;
+    drop(store);
+    assert_eq!(removes(&temp_dir)?, 0);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// 增量压缩应该只合并无效数据比例最高的日志
+#[test]
+fn incremental_compaction_picks_most_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "old".to_owned())?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..9 {
+        store.set(format!("key{}", i), "new".to_owned())?;
+    }
+    for i in 10..20 {
+        store.set(format!("key{}", i), "new".to_owned())?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let stats = store.gen_stats();
+    assert_eq!(stats[0].gen, 1);
+    assert!(stats[0].garbage_ratio() > 0.8);
+    assert_eq!(stats[1].dead_bytes, 0);
+    let untouched = stats[1].clone();
+
+    assert_eq!(store.compact_incremental(1)?, vec![1]);
+    assert!(!temp_dir.path().join("1.log").exists());
+    let stats = store.gen_stats();
+    assert!(stats.contains(&untouched));
+    assert!(stats.iter().all(|stats| stats.dead_bytes == 0));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key9".to_owned())?, Some("old".to_owned()));
+    for i in (0..9).chain(10..20) {
+        assert_eq!(store.get(format!("key{}", i))?, Some("new".to_owned()));
+    }
+    // 没有无效数据时不做任何压缩
+    assert!(store.compact_incremental(u64::MAX)?.is_empty());
+    Ok(())
+}
+
+// 关闭自动压缩后写入不应该触发压缩，由调用者按策略手动压缩
+#[test]
+fn manual_compaction_with_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions {
+        auto_compaction: false,
+        compaction_policy: Box::new(SizeRatioPolicy {
+            ratio: 2.0,
+            min_dead_bytes: 0,
+        }),
+        ..StoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for round in 0..3 {
+        for i in 0..1000 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    let stats = store.gen_stats();
+    assert_eq!(stats.len(), 1);
+    assert!(stats[0].dead_bytes > 1024 * 64);
+
+    assert_eq!(store.run_compaction()?, vec![1]);
+    assert!(store.gen_stats().iter().all(|stats| stats.dead_bytes == 0));
+    // 有效数据占多数时不再压缩
+    store.set("key0".to_owned(), "value".to_owned())?;
+    assert!(store.run_compaction()?.is_empty());
+    assert_eq!(store.get("key999".to_owned())?, Some("value999-2".to_owned()));
+    Ok(())
+}
+
+// 无效数据超过记录字节数的统计不应该导致下溢
+#[test]
+fn size_ratio_with_excess_dead_bytes() {
+    let mut policy = SizeRatioPolicy {
+        ratio: 2.0,
+        min_dead_bytes: 0,
+    };
+    let stats = [GenStats { gen: 1, bytes: 10, dead_bytes: 20 }];
+    assert_eq!(policy.select(&stats), vec![1]);
+}
+
+// 调度器应该在后台按间隔压缩，停止时不返回错误
+#[test]
+fn scheduled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions {
+        auto_compaction: false,
+        compaction_policy: Box::new(SizeRatioPolicy {
+            ratio: 1.0,
+            min_dead_bytes: 0,
+        }),
+        ..StoreOptions::default()
+    };
+    let store = Arc::new(Mutex::new(KvStore::open_with_options(temp_dir.path(), options)?));
+    let dead_bytes = |store: &Arc<Mutex<KvStore>>| -> u64 {
+        store.lock().unwrap().gen_stats().iter().map(|stats| stats.dead_bytes).sum()
+    };
+    store.lock().unwrap().set("key".to_owned(), "value1".to_owned())?;
+    store.lock().unwrap().set("key".to_owned(), "value2".to_owned())?;
+    assert!(dead_bytes(&store) > 0);
+
+    let scheduler = CompactionScheduler::start(Arc::clone(&store), Duration::from_millis(10));
+    let start = SystemTime::now();
+    while dead_bytes(&store) > 0 {
+        assert!(start.elapsed().unwrap() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    scheduler.stop()?;
+    assert_eq!(store.lock().unwrap().get("key".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// 日志数量超过上限时合并所有日志
+struct MaxGensPolicy(usize);
+
+impl CompactionPolicy for MaxGensPolicy {
+    fn select(&mut self, stats: &[GenStats]) -> Vec<u64> {
+        if stats.len() > self.0 {
+            stats.iter().map(|stats| stats.gen).collect()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+// 自定义策略与时间窗口应该决定自动压缩的时机
+#[test]
+fn custom_and_time_window_policies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for _ in 0..3 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key".to_owned(), "value".to_owned())?;
+    }
+
+    // 窗口之外不压缩
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let hour_from_now = ((now + 3600) % 86400) as u32;
+    let closed = TimeWindowPolicy {
+        start: hour_from_now,
+        end: (hour_from_now + 60) % 86400,
+        inner: Box::new(MaxGensPolicy(2)),
+    };
+    assert!(!closed.in_window(SystemTime::now()));
+    let options = StoreOptions {
+        auto_compaction: true,
+        compaction_policy: Box::new(closed),
+        ..StoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.gen_stats().len(), 4);
+    drop(store);
+
+    // 跨过午夜、覆盖全天的窗口
+    let open = TimeWindowPolicy {
+        start: 1,
+        end: 0,
+        inner: Box::new(MaxGensPolicy(2)),
+    };
+    let options = StoreOptions {
+        auto_compaction: true,
+        compaction_policy: Box::new(open),
+        ..StoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.gen_stats().len(), 2);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,142 @@
+#![cfg(all(feature = "lz4", feature = "zstd"))]
+
+use key_value_db::codec::Codec;
+use key_value_db::verify::{self, Severity};
+use key_value_db::{KvStore, KvsError, Result, StoreOptions};
+use std::fs;
+use tempfile::TempDir;
+
+mod common;
+
+fn options(compression: Option<Codec>) -> StoreOptions {
+    StoreOptions {
+        compression,
+        ..common::options()
+    }
+}
+
+/// 冗长的 JSON 文档
+fn document(i: usize) -> String {
+    let fields: Vec<String> = (0..20)
+        .map(|field| format!("\"field{}\": \"some repeated text for record {}\"", field, i))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+// 压缩与未压缩的记录应该可以混合读取、压缩与重新打开
+#[test]
+fn mixed_compressed_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(None))?;
+    for i in 0..10 {
+        store.set(format!("plain{}", i), document(i))?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(Codec::Lz4)))?;
+    for i in 0..10 {
+        store.set(format!("lz4-{}", i), document(i))?;
+        store.set_with_codec(format!("zstd-{}", i), document(i), Some(Codec::Zstd))?;
+    }
+    // 压缩之后不会变小的值按原样写入
+    store.set("small".to_owned(), "x".to_owned())?;
+
+    let logs = common::read_logs(temp_dir.path())?;
+    assert_eq!(logs.matches("\"codec\":\"lz4\"").count(), 10);
+    assert_eq!(logs.matches("\"codec\":\"zstd\"").count(), 10);
+    assert!(logs.contains("\"small\",\"value\":\"x\""));
+
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(None))?;
+    for i in 0..10 {
+        for prefix in ["plain", "lz4-", "zstd-"] {
+            assert_eq!(store.get(format!("{}{}", prefix, i))?, Some(document(i)));
+        }
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("x".to_owned()));
+    Ok(())
+}
+
+// 压缩的值应该明显减少日志的大小
+#[test]
+fn compression_saves_space() -> Result<()> {
+    let mut sizes = Vec::new();
+    for compression in [None, Some(Codec::Lz4), Some(Codec::Zstd)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_options(temp_dir.path(), options(compression))?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), document(i))?;
+        }
+        drop(store);
+        sizes.push(common::read_logs(temp_dir.path())?.len());
+    }
+    assert!(sizes[1] * 2 < sizes[0], "{:?}", sizes);
+    assert!(sizes[2] * 2 < sizes[0], "{:?}", sizes);
+    Ok(())
+}
+
+// 损坏的压缩数据应该报告为损坏
+#[test]
+fn corrupted_compressed_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(Codec::Zstd)))?;
+    store.set("key1".to_owned(), document(1))?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log)?;
+    let start = data.windows(9).position(|window| window == b"\"value\":\"").unwrap() + 9;
+    data[start + 4] = b'!';
+    fs::write(&log, data)?;
+
+    let report = verify::verify(temp_dir.path())?;
+    assert!(report
+        .issues
+        .iter()
+        .any(|issue| issue.severity == Severity::Error && issue.message.contains("cannot be decompressed")));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.get("key1".to_owned()), Err(KvsError::Corruption(_))));
+    Ok(())
+}
+
+// 解压的结果超过最大值大小时应该返回错误，而不是按记录声明的大小分配内存
+#[test]
+fn decompressed_value_is_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(Codec::Zstd)))?;
+    store.set("zstd".to_owned(), "a".repeat(4096))?;
+    store.set_with_codec("lz4".to_owned(), "a".repeat(4096), Some(Codec::Lz4))?;
+    drop(store);
+
+    let small = StoreOptions {
+        max_value_bytes: 1024,
+        ..options(None)
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), small)?;
+    for key in ["zstd", "lz4"] {
+        assert!(matches!(store.get(key.to_owned()), Err(KvsError::ValueTooLarge(_, 1024))));
+    }
+    // 写入同样受最大值大小限制
+    assert!(matches!(
+        store.set("key".to_owned(), "a".repeat(1025)),
+        Err(KvsError::ValueTooLarge(1025, 1024))
+    ));
+    store.set("key".to_owned(), "a".repeat(1024))?;
+    drop(store);
+
+    // lz4 的长度前缀声明了接近 2 GiB 的值
+    let mut log = b"KVSLOG\0\0".to_vec();
+    log.extend_from_slice(&1u32.to_le_bytes());
+    log.extend_from_slice(&0u32.to_le_bytes());
+    log.extend_from_slice(&0u64.to_le_bytes());
+    log.extend_from_slice(&1u64.to_le_bytes());
+    log.extend_from_slice(br#"{"SetCompressed":{"key":"key1","value":"////fxBh","codec":"lz4"}}"#);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("1.log"), log)?;
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(None))?;
+    assert!(matches!(store.get("key1".to_owned()), Err(KvsError::ValueTooLarge(_, _))));
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use assert_cmd::prelude::*;
+use key_value_db::backup::{self, RestorePoint};
+use key_value_db::crypto::{EncryptionKey, Keyring};
+use key_value_db::{repair, verify, KvStore, KvsError, Result, StoreOptions};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+
+fn key(id: u32) -> EncryptionKey {
+    EncryptionKey::new(id, [id as u8; 32]).expect("nonzero key id")
+}
+
+fn options(encryption: Option<Keyring>) -> StoreOptions {
+    StoreOptions {
+        encryption,
+        ..common::options()
+    }
+}
+
+/// 在 data 中查找 pattern 的位置
+fn find(data: &[u8], pattern: &str) -> usize {
+    data.windows(pattern.len()).position(|window| window == pattern.as_bytes()).unwrap()
+}
+
+/// 日志 gen 文件头中的密钥 id
+fn key_id(path: &Path, gen: u64) -> Result<u32> {
+    let data = fs::read(path.join(format!("{}.log", gen)))?;
+    Ok(u32::from_le_bytes(data[12..16].try_into().unwrap()))
+}
+
+// 加密的值应该可以读取、压缩与重新打开，日志中不应该出现明文
+#[test]
+fn encrypted_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keyring = Keyring::new(key(1));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring.clone())))?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("secret value {}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret value 1".to_owned()));
+    assert!(!common::read_logs(temp_dir.path())?.contains("secret value"));
+    assert_eq!(key_id(temp_dir.path(), 1)?, 1);
+
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring)))?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("secret value {}", i)));
+    }
+    drop(store);
+
+    // 没有密钥时应该拒绝打开
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Encryption(_))));
+    let wrong = Keyring::new(key(2));
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options(Some(wrong))),
+        Err(KvsError::Encryption(_))
+    ));
+    Ok(())
+}
+
+// 轮换密钥之后压缩应该以新的密钥重写旧的日志，包括之前没有加密的日志
+#[test]
+fn key_rotation_through_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(None))?;
+    store.set("plain".to_owned(), "plain value".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(Keyring::new(key(1)))))?;
+    store.set("old".to_owned(), "old value".to_owned())?;
+    drop(store);
+
+    let keyring = Keyring::new(key(2)).with_previous(key(1));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring)))?;
+    store.set("new".to_owned(), "new value".to_owned())?;
+    assert_eq!(store.get("plain".to_owned())?, Some("plain value".to_owned()));
+    assert_eq!(store.get("old".to_owned())?, Some("old value".to_owned()));
+    store.compact()?;
+    drop(store);
+
+    // 压缩之后只需要新的密钥
+    let logs = common::read_logs(temp_dir.path())?;
+    assert!(!logs.contains(" value"));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(Keyring::new(key(2)))))?;
+    for key in ["plain", "old", "new"] {
+        assert_eq!(store.get(key.to_owned())?, Some(format!("{} value", key)));
+    }
+    Ok(())
+}
+
+// 篡改密文或把值挪到其他键下应该报告为损坏
+#[test]
+fn tampering_is_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keyring = Keyring::new(key(1));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring.clone())))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let original = fs::read(&log)?;
+
+    // 修改密文中的一个字符
+    let start = find(&original, "\"value\":\"") + 9;
+    let mut data = original.clone();
+    data[start + 2] = if data[start + 2] == b'A' { b'B' } else { b'A' };
+    fs::write(&log, data)?;
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring.clone())))?;
+    assert!(matches!(store.get("key1".to_owned()), Err(KvsError::Corruption(_))));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // 交换两条记录的键
+    let mut swapped = original;
+    let (first, second) = (find(&swapped, "\"key1\""), find(&swapped, "\"key2\""));
+    swapped[first + 4] = b'2';
+    swapped[second + 4] = b'1';
+    fs::write(&log, swapped)?;
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring)))?;
+    assert!(matches!(store.get("key1".to_owned()), Err(KvsError::Corruption(_))));
+    assert!(matches!(store.get("key2".to_owned()), Err(KvsError::Corruption(_))));
+    Ok(())
+}
+
+// 加密的日志中不应该接受明文的值
+#[test]
+fn plaintext_in_encrypted_log_is_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keyring = Keyring::new(key(1));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring.clone())))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log)?;
+    data.extend_from_slice(br#"{"Set":{"key":"key1","value":"forged"}}"#);
+    fs::write(&log, data)?;
+
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options(Some(keyring))),
+        Err(KvsError::Corruption(_))
+    ));
+    let report = verify::verify(temp_dir.path())?;
+    assert!(report.issues.iter().any(|issue| issue.message.contains("unencrypted value")));
+    Ok(())
+}
+
+// 加密只保护值：键与删除记录以明文写入，伪造的删除记录无法被发现
+#[test]
+fn keys_and_removes_are_cleartext() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keyring = Keyring::new(key(1));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring.clone())))?;
+    store.set("visible-key".to_owned(), "secret value".to_owned())?;
+    store.set("other-key".to_owned(), "secret value".to_owned())?;
+    store.remove("other-key".to_owned())?;
+    drop(store);
+
+    let logs = common::read_logs(temp_dir.path())?;
+    assert!(logs.contains("\"visible-key\""));
+    assert!(logs.contains(r#"{"Remove":{"key":"other-key"}}"#));
+    assert!(!logs.contains("secret value"));
+
+    let log = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log)?;
+    data.extend_from_slice(br#"{"Remove":{"key":"visible-key"}}"#);
+    fs::write(&log, data)?;
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring)))?;
+    assert_eq!(store.get("visible-key".to_owned())?, None);
+    Ok(())
+}
+
+// 加密的存储应该可以备份、恢复与修复
+#[test]
+fn backup_and_repair_encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repo_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repair_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keyring = Keyring::new(key(1));
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(Some(keyring.clone())))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    backup::create_backup(&mut store, repo_dir.path())?;
+    drop(store);
+
+    backup::restore(repo_dir.path(), restore_dir.path(), RestorePoint::Latest)?;
+    let mut restored = KvStore::open_with_options(restore_dir.path(), options(Some(keyring.clone())))?;
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(restored);
+
+    // 篡改 key2 的密文，修复时应该作为丢失的记录跳过
+    let log = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log)?;
+    let start = find(&data, "{\"SetEncrypted\":{\"key\":\"key2\"");
+    let value = start + find(&data[start..], "\"value\":\"") + 9;
+    data[value..value + 4].copy_from_slice(b"AAAA");
+    fs::write(&log, data)?;
+
+    // 修复需要原存储的密钥
+    let dest = repair_dir.path().join("repaired");
+    assert!(matches!(repair::repair(temp_dir.path(), &dest), Err(KvsError::Encryption(_))));
+    fs::remove_dir_all(&dest)?;
+
+    let report = repair::repair_with_options(temp_dir.path(), &dest, options(Some(keyring.clone())))?;
+    assert_eq!(report.recovered_records, 2);
+    assert_eq!(report.lost.len(), 1);
+    assert_eq!(report.lost[0].keys, vec!["key2".to_owned()]);
+
+    let mut repaired = KvStore::open_with_options(&dest, options(Some(keyring)))?;
+    assert_eq!(repaired.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(repaired.get("key2".to_owned())?, None);
+    assert_eq!(repaired.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// 命令行应该可以通过密钥文件读写加密的存储
+#[test]
+fn cli_key_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key_file = temp_dir.path().join("store.key");
+    fs::write(&key_file, format!("1 {}\n", "01".repeat(32)))?;
+    let store_dir = temp_dir.path().join("store");
+    fs::create_dir(&store_dir)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--key-file", key_file.to_str().unwrap(), "set", "key1", "secret value"])
+        .current_dir(&store_dir)
+        .assert()
+        .success();
+    assert!(!common::read_logs(&store_dir)?.contains("secret value"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--key-file", key_file.to_str().unwrap()])
+        .current_dir(&store_dir)
+        .assert()
+        .success()
+        .stdout("secret value\n");
+
+    // 与命令行写入的是同一个密钥
+    let mut store = KvStore::open_with_options(&store_dir, options(Some(Keyring::new(key(1)))))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret value".to_owned()));
+    drop(store);
+
+    // 没有密钥时无法打开
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&store_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
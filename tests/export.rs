@@ -0,0 +1,136 @@
+use assert_cmd::prelude::*;
+use key_value_db::{DataFormat, ImportStats, KvStore, Result, StoreOptions};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn sample() -> Vec<(String, String)> {
+    vec![
+        ("comma".to_owned(), "a,b".to_owned()),
+        ("empty".to_owned(), String::new()),
+        ("newline".to_owned(), "line1\nline2\r\n".to_owned()),
+        ("quote".to_owned(), "say \"hi\"".to_owned()),
+        ("unicode".to_owned(), "键值".to_owned()),
+    ]
+}
+
+// 三种格式导出后应该可以原样导入
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    for format in [DataFormat::JsonLines, DataFormat::Csv, DataFormat::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path().join("src"))?;
+        for (key, value) in sample() {
+            store.set(key, value)?;
+        }
+        store.set("removed".to_owned(), "value".to_owned())?;
+        store.remove("removed".to_owned())?;
+
+        let mut dump = Vec::new();
+        assert_eq!(store.export(&mut dump, format)?, 5);
+
+        let mut copy = KvStore::open(temp_dir.path().join("dst"))?;
+        copy.set("comma".to_owned(), "old".to_owned())?;
+        let stats = copy.import(&dump[..], format, false)?;
+        assert_eq!(stats, ImportStats { inserted: 4, overwritten: 1 });
+        assert_eq!(copy.scan(..)?, sample());
+    }
+    Ok(())
+}
+
+// 原子导入中途出错时不应该写入任何数据
+#[test]
+fn atomic_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+
+    let broken = "{\"key\":\"key1\",\"value\":\"new\"}\n{\"key\":\"key2\"}\n";
+    assert!(store.import(broken.as_bytes(), DataFormat::JsonLines, true).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("old".to_owned()));
+
+    let data = "{\"key\":\"key1\",\"value\":\"new\"}\n{\"key\":\"key2\",\"value\":\"a\"}\n{\"key\":\"key2\",\"value\":\"b\"}\n";
+    let stats = store.import(data.as_bytes(), DataFormat::JsonLines, true)?;
+    assert_eq!(stats, ImportStats { inserted: 1, overwritten: 2 });
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("b".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// 原子导入的数据应该按分段大小写入多个日志
+#[test]
+fn atomic_import_respects_segment_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || StoreOptions {
+        auto_compaction: false,
+        max_segment_bytes: 1024,
+        ..StoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let data: String = (0..100)
+        .map(|i| format!("{{\"key\":\"key{}\",\"value\":\"value{}\"}}\n", i, i))
+        .collect();
+    let stats = store.import(data.as_bytes(), DataFormat::JsonLines, true)?;
+    assert_eq!(stats, ImportStats { inserted: 100, overwritten: 0 });
+    assert!(store.gen_stats().len() > 3);
+    assert!(store.gen_stats().iter().all(|stats| stats.bytes <= 1024));
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// 二进制数据中超过实际内容的长度应该报错，而不是按该长度分配内存
+#[test]
+fn truncated_binary_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut data = b"KVSDUMP\x01".to_vec();
+    data.extend_from_slice(&u32::MAX.to_le_bytes());
+    data.extend_from_slice(b"key");
+    assert!(store.import(&data[..], DataFormat::Binary, true).is_err());
+    assert_eq!(store.export(std::io::sink(), DataFormat::JsonLines)?, 0);
+    Ok(())
+}
+
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dump = other_dir.path().join("dump.csv");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "csv", "--output", dump.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "csv", dump.to_str().unwrap(), "--atomic"])
+        .current_dir(&other_dir)
+        .assert()
+        .success()
+        .stdout(contains("Inserted 1, overwritten 0"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "xml"])
+        .current_dir(&other_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
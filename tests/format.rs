@@ -0,0 +1,111 @@
+use assert_cmd::prelude::*;
+use key_value_db::format::{self, FORMAT_VERSION, HEADER_LEN};
+use key_value_db::{KvStore, KvsError, Result};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// 没有文件头的旧格式存储
+fn legacy_store(dir: &TempDir) -> Result<()> {
+    fs::write(
+        dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+    fs::write(dir.path().join("2.log"), r#"{"Remove":{"key":"key1"}}"#)?;
+    fs::write(dir.path().join("3.log"), "")?;
+    Ok(())
+}
+
+// 新日志应该以文件头开始，未知版本的日志应该拒绝打开
+#[test]
+fn header_version_checked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log)?;
+    assert!(data.starts_with(b"KVSLOG"));
+    assert_eq!(data[8..12], FORMAT_VERSION.to_le_bytes());
+    assert_eq!(data[HEADER_LEN as usize], b'{');
+
+    data[8..12].copy_from_slice(&99u32.to_le_bytes());
+    fs::write(&log, data)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat(message)) => assert!(message.contains("version 99")),
+        other => panic!("expected UnsupportedFormat, got {:?}", other.err()),
+    }
+    Ok(())
+}
+
+// 旧格式的存储应该拒绝打开，迁移后数据不变
+#[test]
+fn migrate_legacy_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    legacy_store(&temp_dir)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat(message)) => assert!(message.contains("kvs migrate")),
+        other => panic!("expected UnsupportedFormat, got {:?}", other.err()),
+    }
+
+    let report = format::migrate(temp_dir.path())?;
+    assert_eq!(report.migrated, vec![1, 2, 3]);
+    // 再次迁移不做任何修改
+    let report = format::migrate(temp_dir.path())?;
+    assert_eq!(report.current, vec![1, 2, 3]);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// 打开存储不应该修改旧格式的空日志，只有迁移才会补上文件头
+#[test]
+fn open_does_not_migrate_empty_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("1.log"), "")?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat(message)) => assert!(message.contains("kvs migrate")),
+        other => panic!("expected UnsupportedFormat, got {:?}", other.err()),
+    }
+    assert_eq!(fs::metadata(temp_dir.path().join("1.log"))?.len(), 0);
+
+    let report = format::migrate(temp_dir.path())?;
+    assert_eq!(report.migrated, vec![1]);
+    assert_eq!(fs::metadata(temp_dir.path().join("1.log"))?.len(), HEADER_LEN);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+#[test]
+fn cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    legacy_store(&temp_dir)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Migrated 3 logs"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+    Ok(())
+}
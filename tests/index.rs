@@ -0,0 +1,240 @@
+use key_value_db::index::IndexMode;
+use key_value_db::{KvStore, KvsError, Result, StoreOptions};
+use tempfile::TempDir;
+
+mod common;
+
+const KEYS: usize = 20_000;
+
+fn disk_options(cache_entries: usize) -> StoreOptions {
+    StoreOptions {
+        index_mode: IndexMode::Disk { cache_entries },
+        ..common::options()
+    }
+}
+
+// 索引的内存应该随键数增长，每个键只占用几十字节
+#[test]
+fn index_memory_per_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), common::options())?;
+    assert_eq!(store.index_memory().entries, 0);
+
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    // 覆盖写入不增加索引的大小
+    let memory = store.index_memory();
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "updated".to_owned())?;
+    }
+    assert_eq!(store.index_memory().entries, memory.entries);
+    assert_eq!(store.index_memory().disk_bytes, memory.disk_bytes);
+    assert_eq!(memory.entries, KEYS);
+    assert!(memory.key_bytes >= KEYS * "key0000".len());
+    assert!(memory.total() < KEYS * 64, "{:?}", memory);
+    Ok(())
+}
+
+// 删除大量的键之后索引应该回收空间，存活的键仍然可以读取
+#[test]
+fn index_reclaims_removed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), common::options())?;
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..KEYS).filter(|i| i % 4 != 0) {
+        store.remove(format!("key{}", i))?;
+    }
+    // 删除的键在删除标记中保留到压缩为止
+    assert_eq!(store.index_memory().entries, KEYS);
+
+    store.compact()?;
+    let memory = store.index_memory();
+    assert_eq!(memory.entries, KEYS / 4);
+    for i in 0..KEYS {
+        let expected = if i % 4 == 0 { Some(format!("value{}", i)) } else { None };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+
+    // 删除后重新写入的键使用新的位置
+    store.set("key1".to_owned(), "again".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), common::options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.index_memory().entries, KEYS / 4 + 1);
+    Ok(())
+}
+
+// 磁盘索引应该支持写入、删除、范围查询、压缩与重新打开
+#[test]
+fn disk_index_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(100))?;
+    for i in 0..5000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    for i in (0..5000).step_by(2) {
+        store.remove(format!("key{:04}", i))?;
+    }
+    store.set("key0001".to_owned(), "updated".to_owned())?;
+    assert_eq!(store.get("key0000".to_owned())?, None);
+    assert_eq!(store.get("key0001".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(
+        store.scan("key0010".to_owned().."key0016".to_owned())?,
+        vec![
+            ("key0011".to_owned(), "value11".to_owned()),
+            ("key0013".to_owned(), "value13".to_owned()),
+            ("key0015".to_owned(), "value15".to_owned()),
+        ]
+    );
+
+    store.compact()?;
+    assert_eq!(store.index_memory().entries, 2500);
+    assert!(store.index_memory().disk_bytes > 0);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(100))?;
+    for i in 0..5000 {
+        let expected = match i {
+            1 => Some("updated".to_owned()),
+            i if i % 2 == 1 => Some(format!("value{}", i)),
+            _ => None,
+        };
+        assert_eq!(store.get(format!("key{:04}", i))?, expected);
+    }
+    Ok(())
+}
+
+// 磁盘索引占用的内存应该只取决于缓存的大小，与键数无关
+#[test]
+fn disk_index_memory_is_bounded() -> Result<()> {
+    for keys in [1000, 10_000] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(64))?;
+        for i in 0..keys {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+        for i in 0..keys {
+            assert_eq!(store.get(format!("key{}", i))?, Some("value".to_owned()));
+        }
+        let memory = store.index_memory();
+        assert_eq!(memory.entries, keys);
+        assert_eq!(memory.key_bytes, 0);
+        assert!(memory.table_bytes < 64 * 512, "{:?}", memory);
+    }
+    Ok(())
+}
+
+// 日志序号超出索引可以记录的范围时应该返回错误而不是崩溃
+#[test]
+fn oversized_generation_is_rejected() -> Result<()> {
+    for options in [common::options(), disk_options(16)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let gen = u64::from(u32::MAX) + 1;
+        let mut log = b"KVSLOG\0\0".to_vec();
+        log.extend_from_slice(&1u32.to_le_bytes());
+        log.extend_from_slice(&0u32.to_le_bytes());
+        log.extend_from_slice(&0u64.to_le_bytes());
+        log.extend_from_slice(&gen.to_le_bytes());
+        log.extend_from_slice(br#"{"Set":{"key":"key1","value":"value1"}}"#);
+        std::fs::write(temp_dir.path().join(format!("{}.log", gen)), log)?;
+
+        assert!(matches!(
+            KvStore::open_with_options(temp_dir.path(), options),
+            Err(KvsError::UnsupportedFormat(message)) if message.contains("index limit")
+        ));
+    }
+    Ok(())
+}
+
+// 正常关闭后重新打开应该直接使用保存的磁盘索引，快照在打开之后失效
+#[test]
+fn disk_index_is_reused_after_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join("index").join("snapshot.json");
+    let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(16))?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..10 {
+        store.remove(format!("key{}", i))?;
+    }
+    let stats = store.gen_stats();
+    let memory = store.index_memory();
+    drop(store);
+    assert!(snapshot.exists());
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(16))?;
+    assert!(!snapshot.exists());
+    assert_eq!(store.index_memory().entries, memory.entries);
+    assert_eq!(store.index_memory().disk_bytes, memory.disk_bytes);
+    assert_eq!(store.gen_stats()[..stats.len()], stats[..]);
+    assert_eq!(store.get("key5".to_owned())?, None);
+    assert_eq!(store.get("key500".to_owned())?, Some("value500".to_owned()));
+
+    // 压缩之后删除标记与统计同样应该保存
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(16))?;
+    assert_eq!(store.index_memory().entries, 990);
+    assert!(store.gen_stats().iter().all(|stats| stats.dead_bytes == 0));
+    assert_eq!(store.get("key5".to_owned())?, None);
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// 关闭之后日志被修改时应该放弃保存的磁盘索引，由日志重建
+#[test]
+fn stale_disk_index_is_rebuilt() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(16))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let last_log = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .max_by_key(|path| path.file_stem().unwrap().to_str().unwrap().parse::<u64>().unwrap())
+        .unwrap();
+    let mut log = std::fs::OpenOptions::new().append(true).open(last_log)?;
+    std::io::Write::write_all(&mut log, br#"{"Set":{"key":"key2","value":"value2"}}"#)?;
+    drop(log);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(16))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.index_memory().entries, 2);
+    Ok(())
+}
+
+// 保存的磁盘索引文件损坏时应该由日志重建，而不是读到错误的键或者崩溃
+#[test]
+fn corrupt_disk_index_is_rebuilt() -> Result<()> {
+    // 超出键文件的长度前缀，以及不是 UTF-8 的键
+    let damages: [&[u8]; 2] = [&[0xff, 0xff, 0xff, 0x7f], &[4, 0, 0, 0, 0xff, 0xfe, 0xfd, 0xfc]];
+    for damage in damages {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(16))?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        drop(store);
+
+        let keys = temp_dir.path().join("index").join("live.keys");
+        let mut data = std::fs::read(&keys)?;
+        data[..damage.len()].copy_from_slice(damage);
+        std::fs::write(&keys, data)?;
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), disk_options(16))?;
+        assert_eq!(store.index_memory().entries, 100);
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use assert_cmd::prelude::*;
+use key_value_db::codec::Codec;
+use key_value_db::format::HEADER_LEN;
+use key_value_db::inspect::{self, Operation};
+use key_value_db::{KvStore, Result};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+// 应该按写入顺序列出每条记录，并能从记录边界开始打印
+#[test]
+fn dump_lists_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "v2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let mut records = Vec::new();
+    inspect::dump(temp_dir.path(), None, 0, |record| {
+        records.push(record.clone());
+        Ok(())
+    })?;
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].offset, HEADER_LEN);
+    assert_eq!(records[1].offset, HEADER_LEN + records[0].len);
+    assert_eq!(records[1].value_size, Some(2));
+    assert_eq!(records[2].op, Operation::Remove);
+    assert_eq!(records[2].value_size, None);
+
+    let mut tail = Vec::new();
+    inspect::dump(temp_dir.path(), Some(records[1].gen), records[1].offset, |record| {
+        tail.push(record.clone());
+        Ok(())
+    })?;
+    assert_eq!(tail, records[1..]);
+    Ok(())
+}
+
+// 应该给出键的当前位置以及所有仍在磁盘上的历史记录
+#[test]
+fn inspect_key_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let inspection = inspect::inspect_key(temp_dir.path(), "key1")?;
+    assert_eq!(inspection.history.len(), 2);
+    assert_eq!(inspection.current.as_ref(), inspection.history.last());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let inspection = inspect::inspect_key(temp_dir.path(), "key1")?;
+    assert_eq!(inspection.history.len(), 3);
+    assert!(inspection.current.is_none());
+    Ok(())
+}
+
+// 压缩的值应该给出还原后的大小，并单独给出记录中编码后的大小
+#[test]
+fn dump_reports_decoded_value_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_with_codec("key1".to_owned(), "a".repeat(1000), Some(Codec::Lz4))?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let mut records = Vec::new();
+    inspect::dump(temp_dir.path(), None, 0, |record| {
+        records.push(record.clone());
+        Ok(())
+    })?;
+    assert_eq!(records[0].value_size, Some(1000));
+    assert!(records[0].encoded_size.is_some_and(|size| size < 1000));
+    assert!(records[0].to_string().contains("value_size=1000 encoded_size="));
+    assert_eq!(records[1].value_size, Some(5));
+    assert_eq!(records[1].encoded_size, None);
+    Ok(())
+}
+
+#[test]
+fn cli_dump_and_inspect_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("gen=1 offset=32"))
+        .stdout(contains("op=set key=\"key1\" value_size=6"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["inspect-key", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("current: gen=1 offset=32"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", "--from-offset", "10"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
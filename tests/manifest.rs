@@ -0,0 +1,62 @@
+use key_value_db::manifest::{Compaction, Manifest};
+use key_value_db::{verify, KvStore, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// 清单应该记录存活的日志，不在清单中的日志文件不应该被加载或覆盖
+#[test]
+fn stray_logs_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(Manifest::load(temp_dir.path())?.unwrap().gens, vec![1]);
+
+    let stray = r#"{"Set":{"key":"key1","value":"stray"}}"#;
+    fs::write(temp_dir.path().join("7.log"), stray)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert_eq!(Manifest::load(temp_dir.path())?.unwrap().gens, vec![1, 8]);
+    assert_eq!(fs::read_to_string(temp_dir.path().join("7.log"))?, stray);
+    Ok(())
+}
+
+// 打开时应该删除中断的压缩留下的文件
+#[test]
+fn interrupted_compaction_cleaned_up() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // 压缩结果已写出一部分，但还没有提交
+    let mut manifest = Manifest::load(temp_dir.path())?.unwrap();
+    manifest.compaction = Some(Compaction { outputs: vec![5] });
+    fs::write(temp_dir.path().join("5.log"), "partial")?;
+    fs::write(temp_dir.path().join("manifest.json"), serde_json::to_vec(&manifest)?)?;
+    assert!(!verify::verify(temp_dir.path())?.issues.is_empty());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(!temp_dir.path().join("5.log").exists());
+    store.compact()?;
+    drop(store);
+
+    // 压缩已提交，但旧日志还没有删除
+    let mut manifest = Manifest::load(temp_dir.path())?.unwrap();
+    assert_eq!(manifest.compaction, None);
+    fs::write(temp_dir.path().join("1.log"), "stale")?;
+    manifest.obsolete = vec![1];
+    fs::write(temp_dir.path().join("manifest.json"), serde_json::to_vec(&manifest)?)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(!temp_dir.path().join("1.log").exists());
+    drop(store);
+    assert!(verify::verify(temp_dir.path())?.issues.is_empty());
+    Ok(())
+}
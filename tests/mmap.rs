@@ -0,0 +1,85 @@
+use key_value_db::{DataFormat, KvStore, Result, StoreOptions};
+use tempfile::TempDir;
+
+mod common;
+
+fn options(mmap_reads: bool) -> StoreOptions {
+    StoreOptions {
+        max_segment_bytes: 1024,
+        mmap_reads,
+        ..common::options()
+    }
+}
+
+/// 依次经过重新打开、日志切换、导入与压缩，返回每一步之后读到的值
+fn read_through_lifecycle(mmap_reads: bool) -> Result<Vec<Option<String>>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(mmap_reads))?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let mut reads = Vec::new();
+    let keys = ["key0", "key25", "key49", "imported", "missing"];
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(mmap_reads))?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}-again", i))?;
+        // 已切换出去的日志与当前日志交替读取
+        reads.push(store.get(format!("key{}", i / 2))?);
+    }
+    store.remove("key25".to_owned())?;
+
+    let data = "{\"key\":\"imported\",\"value\":\"from import\"}\n";
+    store.import(data.as_bytes(), DataFormat::JsonLines, true)?;
+    for key in &keys {
+        reads.push(store.get(key.to_string())?);
+    }
+
+    store.compact()?;
+    store.set("key0".to_owned(), "after compaction".to_owned())?;
+    for key in &keys {
+        reads.push(store.get(key.to_string())?);
+    }
+    Ok(reads)
+}
+
+// 内存映射读取与缓冲读取应该读到相同的值
+#[test]
+fn mmap_reads_match_buffered_reads() -> Result<()> {
+    let mapped = read_through_lifecycle(true)?;
+    let buffered = read_through_lifecycle(false)?;
+    assert_eq!(mapped, buffered);
+    assert_eq!(
+        &mapped[mapped.len() - 5..],
+        &[
+            Some("after compaction".to_owned()),
+            None,
+            Some("value49-again".to_owned()),
+            Some("from import".to_owned()),
+            None,
+        ]
+    );
+    Ok(())
+}
+
+// 被映射的日志在压缩中删除后，应该从压缩结果中读取
+#[test]
+fn compaction_replaces_mapped_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(true))?;
+    for round in 0..3 {
+        for i in 0..40 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    for i in 0..40 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}-2", i)));
+    }
+
+    store.compact()?;
+    for i in 0..40 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}-2", i)));
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use key_value_db::raft::{MembershipChange, NodeId, Role, SimNetwork, WriteOp};
+use key_value_db::{RaftNode, RaftOptions, Result};
+use tempfile::TempDir;
+
+fn cluster(dir: &TempDir, ids: &[NodeId], options: RaftOptions) -> Result<SimNetwork> {
+    let mut net = SimNetwork::new();
+    for &id in ids {
+        let node = RaftNode::open(id, dir.path().join(id.to_string()), ids, options.clone())?;
+        net.add_node(node);
+    }
+    Ok(net)
+}
+
+fn wait_for_leader(net: &mut SimNetwork) -> Result<NodeId> {
+    for _ in 0..200 {
+        net.tick()?;
+        if let Some(leader) = net.leader() {
+            return Ok(leader);
+        }
+    }
+    panic!("No leader elected");
+}
+
+fn set(net: &mut SimNetwork, key: &str, value: &str) -> Result<u64> {
+    let leader = wait_for_leader(net)?;
+    let index = net.node_mut(leader).propose(WriteOp::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    })?;
+    net.deliver()?;
+    Ok(index)
+}
+
+// 集群应该选出唯一的 leader 并把写入复制到所有节点
+#[test]
+fn replicate_to_all_nodes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut net = cluster(&temp_dir, &[1, 2, 3], RaftOptions::default())?;
+
+    let leader = wait_for_leader(&mut net)?;
+    let leaders = (1..=3).filter(|&id| net.node(id).role() == Role::Leader).count();
+    assert_eq!(leaders, 1);
+
+    let index = set(&mut net, "key1", "value1")?;
+    net.run(5)?;
+    for id in 1..=3 {
+        assert!(net.node(id).commit_index() >= index);
+        assert_eq!(net.node_mut(id).get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    // follower 不接受写入
+    let follower = (1..=3).find(|&id| id != leader).unwrap();
+    let result = net.node_mut(follower).propose(WriteOp::Remove { key: "key1".to_owned() });
+    assert!(result.is_err());
+    Ok(())
+}
+
+// leader 被隔离后应该选出新的 leader，旧 leader 恢复后追上新的写入
+#[test]
+fn reelect_after_leader_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut net = cluster(&temp_dir, &[1, 2, 3], RaftOptions::default())?;
+
+    set(&mut net, "key1", "value1")?;
+    let old_leader = wait_for_leader(&mut net)?;
+    net.isolate(old_leader);
+
+    // 少数派的旧 leader 无法提交写入
+    let stale = net.node_mut(old_leader).propose(WriteOp::Set {
+        key: "key1".to_owned(),
+        value: "stale".to_owned(),
+    })?;
+    net.run(5)?;
+    assert!(net.node(old_leader).commit_index() < stale);
+
+    let new_leader = wait_for_leader(&mut net)?;
+    assert_ne!(new_leader, old_leader);
+    set(&mut net, "key1", "value2")?;
+
+    net.heal();
+    net.run(20)?;
+    for id in 1..=3 {
+        assert_eq!(net.node_mut(id).get("key1".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// 落后过多的节点应该通过快照追赶
+#[test]
+fn install_snapshot_on_lagging_follower() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = RaftOptions {
+        snapshot_threshold: 10,
+        ..RaftOptions::default()
+    };
+    let mut net = cluster(&temp_dir, &[1, 2, 3], options)?;
+
+    let leader = wait_for_leader(&mut net)?;
+    let lagging = (1..=3).find(|&id| id != leader).unwrap();
+    net.isolate(lagging);
+    for i in 0..50 {
+        set(&mut net, &format!("key{}", i), &format!("value{}", i))?;
+    }
+    assert!(net.node(leader).snapshot_meta().last_index > 0);
+
+    net.heal();
+    net.run(20)?;
+    assert!(net.node(lagging).snapshot_meta().last_index > 0);
+    for i in 0..50 {
+        assert_eq!(net.node_mut(lagging).get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// 节点重启后应该从磁盘恢复状态
+#[test]
+fn restart_node() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = RaftOptions {
+        snapshot_threshold: 5,
+        ..RaftOptions::default()
+    };
+    let mut net = cluster(&temp_dir, &[1, 2, 3], options.clone())?;
+    for i in 0..12 {
+        set(&mut net, &format!("key{}", i), "value")?;
+    }
+    net.run(5)?;
+
+    let leader = wait_for_leader(&mut net)?;
+    let term = net.node(leader).term();
+    net.remove_node(leader).unwrap();
+    let node = RaftNode::open(leader, temp_dir.path().join(leader.to_string()), &[], options)?;
+    assert!(node.term() >= term);
+    assert_eq!(node.members(), vec![1, 2, 3]);
+    net.add_node(node);
+
+    set(&mut net, "key0", "latest")?;
+    net.run(20)?;
+    assert_eq!(net.node_mut(leader).get("key0".to_owned())?, Some("latest".to_owned()));
+    assert_eq!(net.node_mut(leader).get("key11".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// 成员变更：加入新节点，移除 leader
+#[test]
+fn membership_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut net = cluster(&temp_dir, &[1, 2, 3], RaftOptions::default())?;
+    set(&mut net, "key1", "value1")?;
+
+    // 新节点以空成员列表启动，等待 leader 同步
+    net.add_node(RaftNode::open(4, temp_dir.path().join("4"), &[], RaftOptions::default())?);
+    let leader = wait_for_leader(&mut net)?;
+    net.node_mut(leader).propose_membership(MembershipChange::AddNode(4))?;
+    // 上一次变更提交之前不能发起新的变更
+    assert!(net.node_mut(leader).propose_membership(MembershipChange::AddNode(5)).is_err());
+    net.run(10)?;
+    assert_eq!(net.node(4).members(), vec![1, 2, 3, 4]);
+    assert_eq!(net.node_mut(4).get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let leader = wait_for_leader(&mut net)?;
+    net.node_mut(leader).propose_membership(MembershipChange::RemoveNode(leader))?;
+    net.run(10)?;
+    assert_ne!(net.node(leader).role(), Role::Leader);
+
+    let new_leader = wait_for_leader(&mut net)?;
+    assert_ne!(new_leader, leader);
+    assert!(!net.node(new_leader).members().contains(&leader));
+    set(&mut net, "key2", "value2")?;
+    net.run(5)?;
+    assert_eq!(net.node_mut(4).get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
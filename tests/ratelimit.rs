@@ -0,0 +1,91 @@
+use key_value_db::backup;
+use key_value_db::compaction::GarbageRatioPolicy;
+use key_value_db::ratelimit::RateLimiter;
+use key_value_db::{DataFormat, KvStore, Result, StoreOptions};
+use std::io;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// 令牌用完之后应该按设定的速度等待
+#[test]
+fn limiter_throttles() {
+    let limiter = RateLimiter::with_burst(100_000, 10_000);
+    let start = Instant::now();
+    for _ in 0..5 {
+        limiter.acquire(10_000);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(350));
+
+    let metrics = limiter.metrics();
+    assert_eq!(metrics.bytes, 50_000);
+    assert_eq!(metrics.throttled, 4);
+    assert!(metrics.throttled_time >= Duration::from_millis(350));
+}
+
+// 速度为 0 时不应该限速，只统计字节数
+#[test]
+fn zero_rate_is_unlimited() {
+    let limiter = RateLimiter::new(0);
+    let start = Instant::now();
+    limiter.acquire(1_000_000);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(limiter.metrics().bytes, 1_000_000);
+    assert_eq!(limiter.metrics().throttled, 0);
+}
+
+// 写入触发的自动压缩同样应该经过限速器
+#[test]
+fn auto_compaction_is_throttled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limiter = RateLimiter::new(0);
+    let options = StoreOptions {
+        auto_compaction: true,
+        compaction_policy: Box::new(GarbageRatioPolicy { min_dead_bytes: 10_000, ..GarbageRatioPolicy::default() }),
+        io_limiter: Some(limiter.clone()),
+        max_segment_bytes: 4096,
+        ..StoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for round in 0..10 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    assert!(limiter.metrics().bytes > 0);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}-9", i)));
+    }
+    Ok(())
+}
+
+// 压缩、备份与导出应该共用存储的限速器
+#[test]
+fn background_jobs_share_limiter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repo_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limiter = RateLimiter::with_burst(1_000_000, 1000);
+    let options = StoreOptions {
+        auto_compaction: false,
+        io_limiter: Some(limiter.clone()),
+        ..StoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), "value".repeat(20))?;
+    }
+    assert_eq!(limiter.metrics().bytes, 0);
+
+    store.compact()?;
+    let compacted = limiter.metrics().bytes;
+    assert!(compacted > 100_000);
+
+    store.export(io::sink(), DataFormat::JsonLines)?;
+    let exported = limiter.metrics().bytes;
+    assert!(exported > compacted);
+
+    backup::create_backup(&mut store, repo_dir.path())?;
+    let metrics = limiter.metrics();
+    assert!(metrics.bytes > exported);
+    assert!(metrics.throttled > 0);
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use assert_cmd::prelude::*;
+use key_value_db::{repair, verify, KvStore, Result};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// 写入两个日志，并破坏第一个日志中 key2 的记录
+fn damaged_store(dir: &TempDir) -> Result<()> {
+    let mut store = KvStore::open(dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(dir.path())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let log = dir.path().join("1.log");
+    let mut data = fs::read(&log)?;
+    let record: &[u8] = b"{\"Set\":{\"key\":\"key2\"";
+    let pos = data.windows(record.len()).position(|window| window == record).unwrap();
+    data[pos..pos + 7].copy_from_slice(b"garbage");
+    fs::write(&log, data)?;
+    Ok(())
+}
+
+// 中间日志损坏时应该跳过损坏的部分，恢复其余的数据
+#[test]
+fn repair_skips_damaged_region() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    damaged_store(&temp_dir)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = repair::repair(temp_dir.path(), dest_dir.path())?;
+    assert_eq!(report.gens, vec![1, 2]);
+    assert_eq!(report.recovered_records, 4);
+    assert_eq!(report.lost.len(), 1);
+    assert_eq!(report.lost[0].gen, 1);
+    assert_eq!(report.lost[0].keys, vec!["key2".to_owned()]);
+    assert_eq!(report.keys, 2);
+
+    let mut store = KvStore::open(dest_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+    assert!(verify::verify(dest_dir.path())?.issues.is_empty());
+    Ok(())
+}
+
+#[test]
+fn cli_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    damaged_store(&temp_dir)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair", dest_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Recovered 4 records, 2 keys"))
+        .stdout(contains("\"key2\""));
+    assert!(dest_dir.path().join("repair-report.json").exists());
+    Ok(())
+}
+
+// 清单损坏时应该按目录中的日志文件修复
+#[test]
+fn repair_with_corrupt_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    damaged_store(&temp_dir)?;
+    fs::write(temp_dir.path().join("manifest.json"), "garbage")?;
+
+    let report = repair::repair(temp_dir.path(), dest_dir.path())?;
+    assert_eq!(report.gens, vec![1, 2]);
+    assert_eq!(report.recovered_records, 4);
+    assert_eq!(report.keys, 2);
+    let mut store = KvStore::open(dest_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// 清单中的日志缺失时应该记录为丢失，并恢复其余日志中的数据
+#[test]
+fn repair_with_missing_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    damaged_store(&temp_dir)?;
+    fs::remove_file(temp_dir.path().join("1.log"))?;
+
+    let report = repair::repair(temp_dir.path(), dest_dir.path())?;
+    assert_eq!(report.gens, vec![1, 2]);
+    assert_eq!(report.recovered_records, 2);
+    assert_eq!(report.lost.len(), 1);
+    assert_eq!(report.lost[0].gen, 1);
+    let mut store = KvStore::open(dest_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
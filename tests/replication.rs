@@ -0,0 +1,199 @@
+use key_value_db::crypto::{EncryptionKey, Keyring};
+use key_value_db::{KvStore, KvsError, ReplicaFollower, ReplicationLeader, Result, StoreOptions};
+use tempfile::TempDir;
+
+// follower 应该复制 leader 上的写入与删除
+#[test]
+fn follower_replicates_writes() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(leader_dir.path())?;
+    let leader = ReplicationLeader::start(leader_dir.path(), "127.0.0.1:0")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut follower = ReplicaFollower::open(follower_dir.path(), leader.local_addr())?;
+    follower.sync()?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(follower.lag().bytes, Some(0));
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    follower.sync()?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(follower.get("key2".to_owned())?, None);
+
+    // 重新打开后从保存的位置继续复制
+    let position = follower.position();
+    drop(follower);
+    let mut follower = ReplicaFollower::open(follower_dir.path(), leader.local_addr())?;
+    assert_eq!(follower.position(), position);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    follower.sync()?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(follower.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// leader 压缩删除了 follower 所在的日志后，follower 应该全量追赶
+#[test]
+fn follower_catches_up_after_compaction() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(leader_dir.path())?;
+    let leader = ReplicationLeader::start(leader_dir.path(), "127.0.0.1:0")?;
+    store.set("stale".to_owned(), "value".to_owned())?;
+
+    let mut follower = ReplicaFollower::open(follower_dir.path(), leader.local_addr())?;
+    follower.sync()?;
+    assert_eq!(follower.get("stale".to_owned())?, Some("value".to_owned()));
+
+    store.remove("stale".to_owned())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "latest".to_owned())?;
+
+    follower.sync()?;
+    assert_eq!(follower.lag().bytes, Some(0));
+    assert_eq!(follower.get("stale".to_owned())?, None);
+    assert_eq!(follower.get("key0".to_owned())?, Some("latest".to_owned()));
+    for key_id in 1..100 {
+        assert_eq!(follower.get(format!("key{}", key_id))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// 全量追赶期间应该继续以原有的数据提供读取，重新打开之后重新开始追赶
+#[test]
+fn follower_serves_reads_during_resync() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(leader_dir.path())?;
+    let leader = ReplicationLeader::start(leader_dir.path(), "127.0.0.1:0")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut follower = ReplicaFollower::open(follower_dir.path(), leader.local_addr())?;
+    follower.sync()?;
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    // 收到重置之后旧的数据仍然可读
+    let position = follower.position();
+    assert!(follower.sync_once()? > 0);
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(follower.position(), position);
+    drop(follower);
+
+    let mut follower = ReplicaFollower::open(follower_dir.path(), leader.local_addr())?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    follower.sync()?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(follower);
+
+    // 切换之后只保留一个数据目录
+    let dirs = std::fs::read_dir(follower_dir.path())?
+        .filter(|entry| entry.as_ref().is_ok_and(|entry| entry.path().is_dir()))
+        .count();
+    assert_eq!(dirs, 1);
+    let mut follower = ReplicaFollower::open(follower_dir.path(), leader.local_addr())?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// 加密并分离大值的 leader 应该可以复制到持有密钥的 follower
+#[test]
+fn encrypted_blob_leader() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keyring = Keyring::new(EncryptionKey::new(1, [7; 32])?);
+    let options = StoreOptions {
+        auto_compaction: false,
+        encryption: Some(keyring.clone()),
+        blob_threshold: 1024,
+        ..StoreOptions::default()
+    };
+
+    let mut store = KvStore::open_with_options(leader_dir.path(), options)?;
+    let leader = ReplicationLeader::start(leader_dir.path(), "127.0.0.1:0")?;
+    let large = "large value ".repeat(1000);
+    store.set("small".to_owned(), "small value".to_owned())?;
+    store.set("large".to_owned(), large.clone())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    // 没有密钥时报告缺少的密钥
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut follower = ReplicaFollower::open(other_dir.path(), leader.local_addr())?;
+    assert!(matches!(follower.sync(), Err(KvsError::Encryption(_))));
+    drop(follower);
+
+    let mut follower = ReplicaFollower::open_with_keyring(follower_dir.path(), leader.local_addr(), Some(keyring.clone()))?;
+    follower.sync()?;
+    assert_eq!(follower.get("small".to_owned())?, Some("small value".to_owned()));
+    assert_eq!(follower.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(follower.get("removed".to_owned())?, None);
+
+    // 压缩之后全量追赶同样可以取得 blob
+    store.set("small".to_owned(), "updated".to_owned())?;
+    store.compact()?;
+    follower.sync()?;
+    assert_eq!(follower.get("small".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(follower.get("large".to_owned())?, Some(large));
+    Ok(())
+}
+
+// leader 丢弃之后应该关闭已有的连接，follower 不能再从它拉取日志
+#[test]
+fn dropped_leader_closes_connections() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(leader_dir.path())?;
+    let leader = ReplicationLeader::start(leader_dir.path(), "127.0.0.1:0")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut follower = ReplicaFollower::open(follower_dir.path(), leader.local_addr())?;
+    follower.sync()?;
+    drop(leader);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(follower.sync().is_err());
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// 无法读取复制进度时应该拒绝打开，不能删除已复制的数据
+#[test]
+fn unreadable_state_keeps_data() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(leader_dir.path())?;
+    let leader = ReplicationLeader::start(leader_dir.path(), "127.0.0.1:0")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut follower = ReplicaFollower::open(follower_dir.path(), leader.local_addr())?;
+    // 全量追赶之后数据位于新的目录
+    store.compact()?;
+    follower.sync()?;
+    drop(follower);
+
+    let state = follower_dir.path().join("replication.json");
+    let saved = std::fs::read(&state)?;
+    std::fs::remove_file(&state)?;
+    std::fs::create_dir(&state)?;
+    assert!(ReplicaFollower::open(follower_dir.path(), leader.local_addr()).is_err());
+
+    std::fs::remove_dir(&state)?;
+    std::fs::write(&state, saved)?;
+    let mut follower = ReplicaFollower::open(follower_dir.path(), leader.local_addr())?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
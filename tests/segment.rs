@@ -0,0 +1,81 @@
+use key_value_db::{KvStore, Result, StoreOptions};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+mod common;
+
+const MAX_SEGMENT_BYTES: u64 = 1024;
+
+fn options() -> StoreOptions {
+    StoreOptions {
+        max_segment_bytes: MAX_SEGMENT_BYTES,
+        ..common::options()
+    }
+}
+
+/// 目录中每个日志文件的大小
+fn log_sizes(path: &Path) -> Result<Vec<u64>> {
+    let mut sizes = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.path().extension() == Some("log".as_ref()) {
+            sizes.push(entry.metadata()?.len());
+        }
+    }
+    Ok(sizes)
+}
+
+// 当前日志写满时应该切换到新的日志，每个日志都不超过分段大小
+#[test]
+fn writes_rotate_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    // 超过分段大小的记录独占一个日志
+    store.set("big".to_owned(), "x".repeat(4096))?;
+    store.set("key1".to_owned(), "after".to_owned())?;
+
+    let sizes = log_sizes(temp_dir.path())?;
+    assert!(sizes.len() > 4);
+    assert_eq!(sizes.iter().filter(|&&size| size > MAX_SEGMENT_BYTES).count(), 1);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("big".to_owned())?.map(|value| value.len()), Some(4096));
+    Ok(())
+}
+
+// 压缩结果应该按分段大小拆分为多个日志
+#[test]
+fn compaction_output_split() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for round in 0..5 {
+        for i in 0..50 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    store.compact()?;
+
+    let stats = store.gen_stats();
+    // 压缩结果之后是空的写入日志
+    let (writer, outputs) = stats.split_last().unwrap();
+    assert_eq!(writer.bytes, 0);
+    assert!(outputs.len() > 1);
+    assert!(outputs.iter().all(|stats| stats.dead_bytes == 0));
+    assert!(log_sizes(temp_dir.path())?.iter().all(|&size| size <= MAX_SEGMENT_BYTES));
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}-4", i)));
+    }
+    Ok(())
+}
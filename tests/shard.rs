@@ -0,0 +1,140 @@
+use key_value_db::{Result, ShardedStore};
+use tempfile::TempDir;
+
+fn fill(store: &mut ShardedStore, count: usize) -> Result<()> {
+    for i in 0..count {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    Ok(())
+}
+
+fn check(store: &mut ShardedStore, count: usize) -> Result<()> {
+    for i in 0..count {
+        assert_eq!(store.get(format!("key{:04}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// 数据应该分布到所有分片，并在重新打开后保留
+#[test]
+fn distribute_keys_across_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = ShardedStore::open(temp_dir.path(), 4)?;
+    fill(&mut store, 200)?;
+
+    let mut used: Vec<u64> = (0..200).map(|i| store.shard_for(&format!("key{:04}", i))).collect();
+    used.sort_unstable();
+    used.dedup();
+    assert_eq!(used, vec![0, 1, 2, 3]);
+
+    store.remove("key0000".to_owned())?;
+    assert!(store.remove("key0000".to_owned()).is_err());
+    drop(store);
+
+    // 已有目录沿用保存的布局
+    let mut store = ShardedStore::open(temp_dir.path(), 1)?;
+    assert_eq!(store.shard_ids(), vec![0, 1, 2, 3]);
+    assert_eq!(store.get("key0000".to_owned())?, None);
+    assert_eq!(store.get("key0001".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// 跨分片的范围查询应该按键的顺序返回
+#[test]
+fn scan_across_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = ShardedStore::open(temp_dir.path(), 3)?;
+    fill(&mut store, 100)?;
+
+    let pairs = store.scan("key0010".to_owned().."key0020".to_owned())?;
+    let keys: Vec<String> = pairs.into_iter().map(|(key, _)| key).collect();
+    let expected: Vec<String> = (10..20).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(store.scan(..)?.len(), 100);
+    Ok(())
+}
+
+// 再平衡期间读写照常进行，完成后数据都在新的归属分片上
+#[test]
+fn rebalance_online() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = ShardedStore::open(temp_dir.path(), 2)?;
+    fill(&mut store, 300)?;
+
+    let new_shard = store.add_shard()?;
+    assert!(store.is_rebalancing());
+    assert!(store.add_shard().is_err());
+    assert!(store.rebalance_step(10)? > 0);
+    check(&mut store, 300)?;
+
+    // 迁移中写入和删除
+    store.set("key0001".to_owned(), "updated".to_owned())?;
+    store.remove("key0002".to_owned())?;
+    store.rebalance()?;
+    assert!(!store.is_rebalancing());
+    assert_eq!(store.get("key0001".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("key0002".to_owned())?, None);
+    assert!((0..300).any(|i| store.shard_for(&format!("key{:04}", i)) == new_shard));
+    assert_eq!(store.scan(..)?.len(), 299);
+    Ok(())
+}
+
+// 拆分与移除分片
+#[test]
+fn split_and_remove_shard() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = ShardedStore::open(temp_dir.path(), 2)?;
+    fill(&mut store, 200)?;
+
+    let split = store.split_shard(0)?;
+    store.rebalance_step(5)?;
+    drop(store);
+
+    // 中断的再平衡在重新打开后继续
+    let mut store = ShardedStore::open(temp_dir.path(), 2)?;
+    assert!(store.is_rebalancing());
+    store.rebalance()?;
+    assert_eq!(store.shard_ids(), vec![0, 1, split]);
+    check(&mut store, 200)?;
+
+    store.remove_shard(0)?;
+    store.rebalance()?;
+    assert_eq!(store.shard_ids(), vec![1, split]);
+    assert!(!temp_dir.path().join("shard-0").exists());
+    check(&mut store, 200)?;
+    drop(store);
+
+    // 保存布局之后、删除分片之前中断留下的目录在打开时删除
+    std::fs::create_dir(temp_dir.path().join("shard-0"))?;
+    std::fs::write(temp_dir.path().join("shard-0").join("1.log"), "orphan")?;
+    let mut store = ShardedStore::open(temp_dir.path(), 2)?;
+    assert!(!temp_dir.path().join("shard-0").exists());
+    check(&mut store, 200)?;
+    Ok(())
+}
+
+// 布局文件丢失或无法读取时应该拒绝打开，不能删除已有的分片
+#[test]
+fn missing_layout_keeps_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = ShardedStore::open(temp_dir.path(), 2)?;
+    fill(&mut store, 50)?;
+    drop(store);
+
+    let layout = temp_dir.path().join("shards.json");
+    let saved = std::fs::read(&layout)?;
+    // 布局文件无法读取
+    std::fs::remove_file(&layout)?;
+    std::fs::create_dir(&layout)?;
+    assert!(ShardedStore::open(temp_dir.path(), 2).is_err());
+    // 布局文件丢失
+    std::fs::remove_dir(&layout)?;
+    assert!(ShardedStore::open(temp_dir.path(), 2).is_err());
+    assert!(temp_dir.path().join("shard-0").exists());
+    assert!(temp_dir.path().join("shard-1").exists());
+
+    std::fs::write(&layout, saved)?;
+    let mut store = ShardedStore::open(temp_dir.path(), 2)?;
+    check(&mut store, 50)?;
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use assert_cmd::prelude::*;
+use key_value_db::verify::{self, Severity};
+use key_value_db::{KvStore, Result};
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::process::Command;
+use tempfile::TempDir;
+
+// 正常的存储应该通过检查，并统计每个日志的无效字节比例
+#[test]
+fn verify_healthy_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let report = verify::verify(temp_dir.path())?;
+    assert!(report.is_ok());
+    assert!(report.issues.is_empty());
+    assert_eq!(report.keys, 1);
+    let gen = &report.gens[0];
+    assert_eq!((gen.sets, gen.removes), (3, 1));
+    assert!(gen.dead_ratio() > 0.5 && gen.dead_ratio() < 1.0);
+
+    // 遗留的临时文件只是警告
+    fs::write(temp_dir.path().join("9.log.tmp"), b"")?;
+    let report = verify::verify(temp_dir.path())?;
+    assert!(report.is_ok());
+    assert_eq!(report.issues.len(), 1);
+    assert_eq!(report.issues[0].severity, Severity::Warning);
+    Ok(())
+}
+
+// 损坏的日志应该报告错误的位置，命令行以非零状态退出
+#[test]
+fn verify_corrupt_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new().append(true).open(&log)?.write_all(b"{\"Set\":{\"key\":")?;
+
+    let report = verify::verify(temp_dir.path())?;
+    assert!(!report.is_ok());
+    let issue = report.issues.iter().find(|issue| issue.severity == Severity::Error).unwrap();
+    assert_eq!((issue.gen, issue.offset), (Some(1), Some(len)));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(contains("truncated record"));
+    Ok(())
+}